@@ -0,0 +1,7 @@
+CREATE TABLE video_trim (
+    video_id BIGINT PRIMARY KEY REFERENCES gb_videos (id) ON DELETE CASCADE,
+    duration DOUBLE PRECISION NOT NULL,
+    inpoint DOUBLE PRECISION,
+    outpoint DOUBLE PRECISION,
+    analyzed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
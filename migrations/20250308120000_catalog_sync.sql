@@ -0,0 +1,9 @@
+ALTER TABLE gb_videos
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN removed_at TIMESTAMPTZ;
+
+ALTER TABLE playlist_entry ADD COLUMN position BIGINT;
+UPDATE playlist_entry SET position = id;
+ALTER TABLE playlist_entry ALTER COLUMN position SET NOT NULL;
+
+CREATE INDEX playlist_entry_position_idx ON playlist_entry (position);
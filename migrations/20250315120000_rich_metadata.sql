@@ -0,0 +1,21 @@
+ALTER TABLE gb_videos
+    ADD COLUMN subjects VARCHAR[],
+    ADD COLUMN published_at TIMESTAMPTZ,
+    ADD COLUMN runtime DOUBLE PRECISION,
+    ADD COLUMN thumbnail_url VARCHAR,
+    ADD COLUMN details_fetched_at TIMESTAMPTZ;
+
+CREATE INDEX gb_videos_published_at_idx ON gb_videos (published_at);
+
+CREATE TABLE gb_video_files (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    video_id BIGINT NOT NULL REFERENCES gb_videos (id) ON DELETE CASCADE,
+    "name" VARCHAR NOT NULL,
+    format VARCHAR NOT NULL,
+    "size" BIGINT,
+    "length" DOUBLE PRECISION,
+    width INT,
+    height INT,
+    md5 VARCHAR NOT NULL,
+    UNIQUE (video_id, "name")
+);
@@ -0,0 +1,23 @@
+CREATE TYPE playlist_entry_status AS ENUM (
+    'unplayed',
+    'pending',
+    'downloaded',
+    'active',
+    'finished'
+);
+
+ALTER TABLE playlist_entry
+    ALTER COLUMN "status" TYPE playlist_entry_status USING "status"::playlist_entry_status,
+    ADD COLUMN status_changed_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE TABLE playlist_entry_transition (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    entry_id BIGINT NOT NULL REFERENCES playlist_entry (id) ON DELETE CASCADE,
+    from_status playlist_entry_status NOT NULL,
+    to_status playlist_entry_status NOT NULL,
+    transitioned_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX playlist_entry_transition_entry_idx ON playlist_entry_transition (entry_id);
+
+ALTER TABLE active_playlist_entry ADD PRIMARY KEY (id);
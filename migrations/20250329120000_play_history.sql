@@ -0,0 +1,14 @@
+CREATE TYPE play_outcome AS ENUM ('completed', 'skipped');
+
+CREATE TABLE play_history (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    video_id BIGINT NOT NULL REFERENCES gb_videos (id) ON DELETE CASCADE,
+    entry_id BIGINT REFERENCES playlist_entry (id) ON DELETE SET NULL,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ended_at TIMESTAMPTZ,
+    outcome play_outcome,
+    reason VARCHAR
+);
+
+CREATE INDEX play_history_video_idx ON play_history (video_id);
+CREATE INDEX play_history_started_at_idx ON play_history (started_at);
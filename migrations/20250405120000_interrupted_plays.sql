@@ -0,0 +1 @@
+ALTER TYPE play_outcome ADD VALUE 'interrupted';
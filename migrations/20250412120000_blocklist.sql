@@ -0,0 +1,5 @@
+CREATE TABLE blocklist (
+    video_id BIGINT PRIMARY KEY REFERENCES gb_videos (id) ON DELETE CASCADE,
+    reason VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
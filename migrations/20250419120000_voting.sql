@@ -0,0 +1,29 @@
+CREATE TABLE vote_round (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    -- the entry that was playing while the vote ran
+    entry_id BIGINT REFERENCES playlist_entry (id) ON DELETE SET NULL,
+    opened_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    closed_at TIMESTAMPTZ,
+    winner_video_id BIGINT REFERENCES gb_videos (id) ON DELETE SET NULL
+);
+
+-- at most one round can be open at a time
+CREATE UNIQUE INDEX vote_round_open_idx ON vote_round ((closed_at IS NULL)) WHERE closed_at IS NULL;
+
+CREATE TABLE vote_candidate (
+    round_id BIGINT NOT NULL REFERENCES vote_round (id) ON DELETE CASCADE,
+    choice INT NOT NULL,
+    video_id BIGINT NOT NULL REFERENCES gb_videos (id) ON DELETE CASCADE,
+    PRIMARY KEY (round_id, choice)
+);
+
+CREATE INDEX vote_candidate_video_idx ON vote_candidate (video_id);
+
+CREATE TABLE vote (
+    round_id BIGINT NOT NULL,
+    voter VARCHAR NOT NULL,
+    choice INT NOT NULL,
+    cast_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (round_id, voter),
+    FOREIGN KEY (round_id, choice) REFERENCES vote_candidate (round_id, choice) ON DELETE CASCADE
+);
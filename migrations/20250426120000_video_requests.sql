@@ -0,0 +1,19 @@
+CREATE TYPE request_status AS ENUM ('pending', 'approved', 'rejected');
+
+CREATE TABLE video_request (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    video_id BIGINT NOT NULL REFERENCES gb_videos (id) ON DELETE CASCADE,
+    requested_by VARCHAR NOT NULL,
+    -- what the viewer typed, an identifier or a search term
+    query VARCHAR NOT NULL,
+    status request_status NOT NULL DEFAULT 'pending',
+    requested_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    decided_by VARCHAR,
+    decided_at TIMESTAMPTZ,
+    reason VARCHAR,
+    -- the playlist entry an approved request was spliced in as
+    entry_id BIGINT REFERENCES playlist_entry (id) ON DELETE SET NULL
+);
+
+CREATE INDEX video_request_requested_by_idx ON video_request (requested_by, requested_at);
+CREATE INDEX video_request_status_idx ON video_request (status);
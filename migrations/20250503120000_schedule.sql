@@ -0,0 +1,9 @@
+-- occurrences of programming blocks whose videos were put into the playlist
+CREATE TABLE schedule_slot (
+    block VARCHAR NOT NULL,
+    starts_at TIMESTAMPTZ NOT NULL,
+    ends_at TIMESTAMPTZ NOT NULL,
+    filled_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    video_ids BIGINT[] NOT NULL DEFAULT '{}',
+    PRIMARY KEY (block, starts_at)
+);
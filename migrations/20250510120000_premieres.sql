@@ -0,0 +1,13 @@
+CREATE TYPE premiere_status AS ENUM ('scheduled', 'queued', 'started', 'cancelled');
+
+CREATE TABLE premiere (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    video_id BIGINT NOT NULL REFERENCES gb_videos (id) ON DELETE CASCADE,
+    starts_at TIMESTAMPTZ NOT NULL,
+    status premiere_status NOT NULL DEFAULT 'scheduled',
+    -- the playlist entry that is held back until the premiere starts
+    entry_id BIGINT REFERENCES playlist_entry (id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX premiere_starts_at_idx ON premiere (starts_at);
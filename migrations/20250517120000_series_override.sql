@@ -0,0 +1,6 @@
+-- a NULL series keeps a video out of any series its title suggests
+CREATE TABLE series_override (
+    video_id BIGINT PRIMARY KEY REFERENCES gb_videos (id) ON DELETE CASCADE,
+    series VARCHAR,
+    part INT
+);
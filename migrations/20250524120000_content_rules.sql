@@ -0,0 +1,3 @@
+-- name of the content rule that put the video on the blocklist, NULL if it
+-- was added by hand
+ALTER TABLE blocklist ADD COLUMN "rule" VARCHAR;
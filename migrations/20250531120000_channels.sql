@@ -0,0 +1,23 @@
+CREATE TABLE channel (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "name" VARCHAR NOT NULL UNIQUE
+);
+
+-- everything that existed so far belongs to the main channel
+INSERT INTO channel (id, "name") VALUES (1, 'main');
+SELECT setval(pg_get_serial_sequence('channel', 'id'), 1);
+
+ALTER TABLE playlist_entry
+    ADD COLUMN channel_id BIGINT NOT NULL DEFAULT 1 REFERENCES channel (id) ON DELETE CASCADE,
+    DROP CONSTRAINT playlist_entry_video_id_key,
+    ADD UNIQUE (channel_id, video_id);
+ALTER TABLE playlist_entry ALTER COLUMN channel_id DROP DEFAULT;
+
+-- the row of a channel points at its active entry
+ALTER TABLE active_playlist_entry RENAME COLUMN id TO channel_id;
+ALTER TABLE active_playlist_entry
+    ADD FOREIGN KEY (channel_id) REFERENCES channel (id) ON DELETE CASCADE;
+
+ALTER TABLE premiere
+    ADD COLUMN channel_id BIGINT NOT NULL DEFAULT 1 REFERENCES channel (id) ON DELETE CASCADE;
+ALTER TABLE premiere ALTER COLUMN channel_id DROP DEFAULT;
@@ -0,0 +1,5 @@
+-- the row keeps pointing at the last active entry after it finished, which
+-- must not keep the entry from being removed from the playlist
+ALTER TABLE active_playlist_entry
+    DROP CONSTRAINT active_playlist_entry_entry_index_fkey,
+    ADD FOREIGN KEY (entry_index) REFERENCES playlist_entry (id) ON DELETE CASCADE;
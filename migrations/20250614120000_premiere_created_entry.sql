@@ -0,0 +1,3 @@
+-- the entry of a video that was in the playlist before its premiere stays
+-- when the premiere is cancelled
+ALTER TABLE premiere ADD COLUMN created_entry BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,294 @@
+use std::process::Stdio;
+
+use camino::Utf8Path;
+use color_eyre::eyre::{bail, Context, OptionExt};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::{config::DeadAirConfig, Result};
+
+/// A time range in seconds, relative to the start of the video.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// The result of the dead air analysis for a single video.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimRange {
+    /// Total duration of the video in seconds
+    pub duration: f64,
+    /// Where playback should start, if the video has a dead intro
+    pub inpoint: Option<f64>,
+    /// Where playback should stop, if the video has a dead outro
+    pub outpoint: Option<f64>,
+}
+
+/// Output of a single blackdetect/silencedetect run over a part of the video.
+#[derive(Debug, Default)]
+struct DetectOutput {
+    duration: Option<f64>,
+    black: Vec<Interval>,
+    silence: Vec<Interval>,
+}
+
+/// Runs ffmpeg's blackdetect and silencedetect filters over the beginning and the
+/// end of the video and figures out how much of either end can be skipped.
+pub async fn detect_dead_air(path: &Utf8Path, config: &DeadAirConfig) -> Result<TrimRange> {
+    let head = run_detect(path, config, 0.0).await?;
+    let duration = head
+        .duration
+        .ok_or_eyre("could not determine video duration")?;
+
+    let tail_offset = (duration - config.scan_window).max(0.0);
+    let tail = if tail_offset > 0.0 {
+        run_detect(path, config, tail_offset).await?
+    } else {
+        DetectOutput::default()
+    };
+
+    let intervals: Vec<_> = head
+        .black
+        .into_iter()
+        .chain(head.silence)
+        .chain(tail.black)
+        .chain(tail.silence)
+        .collect();
+
+    let range = trim_range(&intervals, duration, config.tolerance);
+    info!("dead air analysis for {path}: {range:?}");
+    Ok(range)
+}
+
+async fn run_detect(path: &Utf8Path, config: &DeadAirConfig, offset: f64) -> Result<DetectOutput> {
+    let black_filter = format!(
+        "blackdetect=d={}:pix_th={}",
+        config.min_duration, config.pixel_threshold
+    );
+    let silence_filter = format!(
+        "silencedetect=noise={}dB:d={}",
+        config.noise_threshold, config.min_duration
+    );
+    let offset_arg = offset.to_string();
+    let window_arg = config.scan_window.to_string();
+
+    let output = Command::new("ffmpeg")
+        .args([
+            "-hide_banner",
+            "-nostats",
+            "-ss",
+            &offset_arg,
+            "-t",
+            &window_arg,
+            "-i",
+            path.as_str(),
+            "-vf",
+            &black_filter,
+            "-af",
+            &silence_filter,
+            "-f",
+            "null",
+            "-",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .wrap_err("failed to run ffmpeg")?;
+
+    if !output.status.success() {
+        bail!(
+            "ffmpeg exited with {} while analyzing {path}",
+            output.status
+        );
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut result = parse_detect_output(&stderr, config.scan_window);
+    for interval in result.black.iter_mut().chain(result.silence.iter_mut()) {
+        interval.start += offset;
+        interval.end += offset;
+    }
+    Ok(result)
+}
+
+/// Parses the log output of ffmpeg. `window_end` is used to close a silence that
+/// is still ongoing when the input ends.
+fn parse_detect_output(stderr: &str, window_end: f64) -> DetectOutput {
+    let mut output = DetectOutput::default();
+    let mut silence_start = None;
+
+    for line in stderr.lines() {
+        if let Some(duration) = line.trim_start().strip_prefix("Duration:") {
+            output.duration = duration.split(',').next().and_then(parse_timestamp);
+        } else if line.contains("blackdetect") {
+            if let (Some(start), Some(end)) =
+                (field(line, "black_start:"), field(line, "black_end:"))
+            {
+                output.black.push(Interval { start, end });
+            }
+        } else if line.contains("silencedetect") {
+            if let Some(start) = field(line, "silence_start:") {
+                silence_start = Some(start);
+            } else if let Some(end) = field(line, "silence_end:") {
+                let start = silence_start.take().unwrap_or(0.0);
+                output.silence.push(Interval { start, end });
+            }
+        }
+    }
+
+    if let Some(start) = silence_start {
+        output.silence.push(Interval {
+            start,
+            end: window_end,
+        });
+    }
+
+    output
+}
+
+/// Extracts a numeric value following `key`, e.g. `black_end:12.5`.
+fn field(line: &str, key: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(key)?;
+    rest.trim_start()
+        .split(|c: char| c.is_whitespace() || c == '|')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Parses `HH:MM:SS.ms` into seconds.
+fn parse_timestamp(value: &str) -> Option<f64> {
+    let mut parts = value.trim().splitn(3, ':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Merges the detected intervals into a head and tail range. Intervals that touch
+/// each other (within `tolerance` seconds) are chained together, so a black intro
+/// followed by a silent "starting soon" screen is trimmed as a whole.
+fn trim_range(intervals: &[Interval], duration: f64, tolerance: f64) -> TrimRange {
+    let mut by_start = intervals.to_vec();
+    by_start.sort_by(|a, b| a.start.total_cmp(&b.start));
+    let mut head_end = None;
+    for interval in &by_start {
+        let current = head_end.unwrap_or(0.0);
+        if interval.start > current + tolerance {
+            break;
+        }
+        if interval.end > current {
+            head_end = Some(interval.end);
+        }
+    }
+
+    let mut by_end = intervals.to_vec();
+    by_end.sort_by(|a, b| b.end.total_cmp(&a.end));
+    let mut tail_start = None;
+    for interval in &by_end {
+        let current = tail_start.unwrap_or(duration);
+        if interval.end < current - tolerance {
+            break;
+        }
+        if interval.start < current {
+            tail_start = Some(interval.start);
+        }
+    }
+
+    match (head_end, tail_start) {
+        // the whole video is dead air, better to play it than to skip it silently
+        (Some(inpoint), Some(outpoint)) if inpoint >= outpoint => TrimRange {
+            duration,
+            inpoint: None,
+            outpoint: None,
+        },
+        (inpoint, outpoint) => TrimRange {
+            duration,
+            inpoint,
+            outpoint,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STDERR: &str = "\
+Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'quick-look-halo-3.mp4':
+  Metadata:
+    major_brand     : isom
+  Duration: 00:25:12.48, start: 0.000000, bitrate: 2458 kb/s
+  Stream #0:0[0x1](und): Video: h264 (High) (avc1 / 0x31637661), yuv420p, 1280x720, 2325 kb/s, 29.97 fps
+  Stream #0:1[0x2](und): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, stereo, fltp, 128 kb/s
+[silencedetect @ 0x55d5c1a3e2c0] silence_start: 0
+[blackdetect @ 0x55d5c1a41b00] black_start:0 black_end:4.004 black_duration:4.004
+[silencedetect @ 0x55d5c1a3e2c0] silence_end: 6.52 | silence_duration: 6.52
+[silencedetect @ 0x55d5c1a3e2c0] silence_start: 170.25
+[out#0/null @ 0x55d5c1a3f100] video:74kB audio:31kB subtitle:0kB
+";
+
+    #[test]
+    fn parses_detect_output() {
+        let output = parse_detect_output(STDERR, 180.0);
+        assert_eq!(output.duration, Some(1512.48));
+        assert_eq!(
+            output.black,
+            [Interval {
+                start: 0.0,
+                end: 4.004
+            }]
+        );
+        assert_eq!(
+            output.silence,
+            [
+                Interval {
+                    start: 0.0,
+                    end: 6.52
+                },
+                Interval {
+                    start: 170.25,
+                    end: 180.0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn trims_chained_intervals() {
+        let intervals = [
+            Interval {
+                start: 0.0,
+                end: 4.0,
+            },
+            Interval {
+                start: 4.5,
+                end: 12.0,
+            },
+            Interval {
+                start: 30.0,
+                end: 40.0,
+            },
+            Interval {
+                start: 1490.0,
+                end: 1500.0,
+            },
+        ];
+        let range = trim_range(&intervals, 1500.0, 1.0);
+        assert_eq!(range.inpoint, Some(12.0));
+        assert_eq!(range.outpoint, Some(1490.0));
+
+        let range = trim_range(&intervals[2..3], 1500.0, 1.0);
+        assert_eq!((range.inpoint, range.outpoint), (None, None));
+
+        let everything = [Interval {
+            start: 0.0,
+            end: 60.0,
+        }];
+        let range = trim_range(&everything, 60.0, 1.0);
+        assert_eq!((range.inpoint, range.outpoint), (None, None));
+    }
+}
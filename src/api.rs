@@ -0,0 +1,620 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use camino::Utf8PathBuf;
+use color_eyre::eyre::OptionExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::{
+    config::{ApiConfig, EpgConfig, HealthConfig, RequestConfig},
+    dashboard,
+    db::{
+        Database, InvalidPremiere, InvalidRequest, InvalidTransition, InvalidVote, PlaylistEntry,
+        Premiere, QueueItem, VideoId, VideoRequest, VoteRound, VoteTally,
+    },
+    epg::{self, Playing},
+    export::{self, ExportFormat},
+    health,
+    metrics::Metrics,
+    on_this_day,
+    player::{PlayerHandle, PlayerState},
+    recent_errors::RecentErrors,
+    Result,
+};
+
+/// Name of the cookie the dashboard stores the session id in.
+pub const SESSION_COOKIE: &str = "gb_forever_session";
+
+/// The playlist and player of a channel besides the main one.
+#[derive(Clone)]
+pub struct ChannelHandle {
+    pub database: Database,
+    pub player: PlayerHandle,
+}
+
+/// Returned when the `channel` parameter names no configured channel.
+#[derive(Debug)]
+pub struct UnknownChannel(String);
+
+impl fmt::Display for UnknownChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown channel {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownChannel {}
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub database: Database,
+    pub player: PlayerHandle,
+    /// Other channels by name, controlled with the `channel` parameter
+    channels: Arc<HashMap<String, ChannelHandle>>,
+    pub video_path: Utf8PathBuf,
+    pub errors: RecentErrors,
+    pub requests: RequestConfig,
+    pub epg: EpgConfig,
+    pub metrics: Metrics,
+    pub health: HealthConfig,
+    token: Arc<str>,
+    /// Sessions of the dashboard logins with the time they started, forgotten
+    /// on restart
+    sessions: Arc<Mutex<HashMap<String, Instant>>>,
+    session_max_age: Duration,
+}
+
+impl ApiState {
+    pub fn new(
+        config: &ApiConfig,
+        database: Database,
+        player: PlayerHandle,
+        video_path: Utf8PathBuf,
+        errors: RecentErrors,
+        requests: RequestConfig,
+        epg: EpgConfig,
+    ) -> Result<Self> {
+        let token = config
+            .token
+            .as_deref()
+            .ok_or_eyre("api.token has to be set when the API is enabled")?;
+        Ok(Self {
+            database,
+            player,
+            channels: Arc::default(),
+            video_path,
+            errors,
+            requests,
+            epg,
+            metrics: Metrics::default(),
+            health: config.health.clone(),
+            token: token.into(),
+            sessions: Arc::default(),
+            session_max_age: Duration::from_secs(config.session_max_age),
+        })
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Makes a channel besides the main one controllable.
+    pub fn with_channel(mut self, name: impl Into<String>, channel: ChannelHandle) -> Self {
+        Arc::make_mut(&mut self.channels).insert(name.into(), channel);
+        self
+    }
+
+    /// The playlist and player of the channel `name`, the main one by default.
+    fn channel(&self, name: Option<&str>) -> Result<(&Database, &PlayerHandle)> {
+        match name {
+            None | Some("main") => Ok((&self.database, &self.player)),
+            Some(name) => {
+                let channel = self
+                    .channels
+                    .get(name)
+                    .ok_or_else(|| UnknownChannel(name.into()))?;
+                Ok((&channel.database, &channel.player))
+            }
+        }
+    }
+
+    pub fn token_matches(&self, token: &str) -> bool {
+        token == &*self.token
+    }
+
+    /// How long a dashboard session lasts.
+    pub fn session_max_age(&self) -> Duration {
+        self.session_max_age
+    }
+
+    /// Starts a dashboard session, so the token itself is not stored in the
+    /// browser. Returns the value of the session cookie.
+    pub fn start_session(&self) -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill(&mut bytes);
+        let session: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, started| started.elapsed() < self.session_max_age);
+        sessions.insert(session.clone(), Instant::now());
+        session
+    }
+
+    /// Ends the dashboard session of the request, if it has one.
+    pub fn end_session(&self, headers: &HeaderMap) {
+        if let Some(session) = session_cookie(headers) {
+            self.sessions.lock().unwrap().remove(session);
+        }
+    }
+
+    /// Accepts the token as a bearer token or, for browsers, the cookie of a
+    /// session that has not expired yet.
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        bearer.is_some_and(|t| self.token_matches(t))
+            || session_cookie(headers).is_some_and(|session| {
+                self.sessions
+                    .lock()
+                    .unwrap()
+                    .get(session)
+                    .is_some_and(|started| started.elapsed() < self.session_max_age)
+            })
+    }
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// Wraps errors so handlers can use `?`. Known error types get a matching
+/// status code, everything else is a 500.
+pub struct ApiError(color_eyre::Report);
+
+impl<E: Into<color_eyre::Report>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = if self.0.downcast_ref::<InvalidTransition>().is_some() {
+            StatusCode::CONFLICT
+        } else if let Some(invalid) = self.0.downcast_ref::<InvalidVote>() {
+            match invalid {
+                InvalidVote::NoOpenRound => StatusCode::CONFLICT,
+                InvalidVote::UnknownChoice(_) => StatusCode::BAD_REQUEST,
+            }
+        } else if let Some(invalid) = self.0.downcast_ref::<InvalidRequest>() {
+            match invalid {
+                InvalidRequest::NotFound(_) => StatusCode::NOT_FOUND,
+                InvalidRequest::Cooldown(_) | InvalidRequest::TooManyPending(_) => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                _ => StatusCode::CONFLICT,
+            }
+        } else if let Some(invalid) = self.0.downcast_ref::<InvalidPremiere>() {
+            match invalid {
+                InvalidPremiere::InPast => StatusCode::BAD_REQUEST,
+                _ => StatusCode::CONFLICT,
+            }
+        } else if self.0.downcast_ref::<UnknownChannel>().is_some() {
+            StatusCode::NOT_FOUND
+        } else if let Some(sqlx::Error::RowNotFound) = self.0.downcast_ref::<sqlx::Error>() {
+            StatusCode::NOT_FOUND
+        } else {
+            error!("request failed: {:?}", self.0);
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        let body = serde_json::json!({ "error": format!("{:#}", self.0) });
+        (status, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// Serves the API until `shutdown` is cancelled, letting requests in flight
+/// finish.
+pub async fn serve(bind_address: &str, state: ApiState, shutdown: CancellationToken) -> Result<()> {
+    let listener = TcpListener::bind(bind_address).await?;
+    info!("control API listening on {bind_address}");
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+pub fn router(state: ApiState) -> Router {
+    // orchestrators, frontends and viewers call these without a token
+    let public = Router::new()
+        .route("/epg.xml", get(program_guide))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/export/upcoming", get(export_upcoming))
+        .route("/export/history", get(export_history))
+        .with_state(state.clone());
+
+    Router::new()
+        .merge(api_routes(state.clone()))
+        .merge(dashboard::router(state))
+        .merge(public)
+}
+
+fn api_routes(state: ApiState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/api/now-playing", get(now_playing))
+        .route("/api/queue", get(queue))
+        .route("/api/downloads", get(downloads))
+        .route("/api/skip", post(skip))
+        .route("/api/pause", post(pause))
+        .route("/api/resume", post(resume))
+        .route("/api/enqueue", post(enqueue))
+        .route("/api/vote", get(current_vote).post(vote))
+        .route("/api/requests", get(pending_requests).post(submit_request))
+        .route("/api/requests/{id}/approve", post(approve_request))
+        .route("/api/requests/{id}/reject", post(reject_request))
+        .route("/api/premieres", get(premieres).post(create_premiere))
+        .route("/api/premieres/{id}", delete(cancel_premiere))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    if state.is_authorized(request.headers()) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct NowPlaying {
+    state: PlayerState,
+    current: Option<QueueItem>,
+    /// Set if the current video was first published on today's date
+    aired_years_ago: Option<i32>,
+}
+
+/// Selects the channel of the control and health endpoints.
+#[derive(Deserialize)]
+struct ChannelQuery {
+    channel: Option<String>,
+}
+
+async fn now_playing(
+    State(state): State<ApiState>,
+    Query(query): Query<ChannelQuery>,
+) -> ApiResult<NowPlaying> {
+    let (database, player) = state.channel(query.channel.as_deref())?;
+    let entries = database.current_video().await?.into_iter().collect();
+    let current = database.with_videos(entries).await?.pop();
+    let aired_years_ago = current.as_ref().and_then(|item| {
+        on_this_day::years_ago(item.video.published_at, OffsetDateTime::now_utc().date())
+    });
+    Ok(Json(NowPlaying {
+        state: player.state(),
+        current,
+        aired_years_ago,
+    }))
+}
+
+async fn metrics(State(state): State<ApiState>) -> std::result::Result<Response, ApiError> {
+    let rendered = state
+        .metrics
+        .render(&state.database, &state.video_path)
+        .await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        rendered,
+    )
+        .into_response())
+}
+
+async fn health_response(state: &ApiState, channel: Option<&str>, ready: bool) -> Response {
+    let (database, player) = match state.channel(channel) {
+        Ok(channel) => channel,
+        Err(e) => return ApiError(e).into_response(),
+    };
+    let report = health::check(database, player, &state.health).await;
+    let ok = if ready { report.ready } else { report.healthy };
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+async fn healthz(State(state): State<ApiState>, Query(query): Query<ChannelQuery>) -> Response {
+    health_response(&state, query.channel.as_deref(), false).await
+}
+
+async fn readyz(State(state): State<ApiState>, Query(query): Query<ChannelQuery>) -> Response {
+    health_response(&state, query.channel.as_deref(), true).await
+}
+
+async fn program_guide(State(state): State<ApiState>) -> std::result::Result<Response, ApiError> {
+    if !state.epg.enabled {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let programmes = epg::guide(&state.database, &state.player, state.epg.hours).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        epg::to_xmltv(&state.epg.channel_name, &programmes),
+    )
+        .into_response())
+}
+
+/// Most entries a public export can list.
+const MAX_EXPORT_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default = "default_export_format")]
+    format: ExportFormat,
+    #[serde(default = "default_export_limit")]
+    limit: i64,
+}
+
+fn default_export_format() -> ExportFormat {
+    ExportFormat::M3u
+}
+
+fn default_export_limit() -> i64 {
+    100
+}
+
+fn export_response(
+    format: ExportFormat,
+    title: &str,
+    items: &[export::ExportItem],
+) -> std::result::Result<Response, ApiError> {
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        export::render(format, title, items)?,
+    )
+        .into_response())
+}
+
+async fn export_upcoming(
+    State(state): State<ApiState>,
+    Query(query): Query<ExportQuery>,
+) -> std::result::Result<Response, ApiError> {
+    let limit = query.limit.clamp(1, MAX_EXPORT_LIMIT);
+    let items =
+        export::upcoming(&state.database, Playing::from_player(&state.player), limit).await?;
+    export_response(
+        query.format,
+        &format!("{} upcoming", state.epg.channel_name),
+        &items,
+    )
+}
+
+async fn export_history(
+    State(state): State<ApiState>,
+    Query(query): Query<ExportQuery>,
+) -> std::result::Result<Response, ApiError> {
+    let limit = query.limit.clamp(1, MAX_EXPORT_LIMIT);
+    let items = export::history(&state.database, limit).await?;
+    export_response(
+        query.format,
+        &format!("{} history", state.epg.channel_name),
+        &items,
+    )
+}
+
+#[derive(Deserialize)]
+struct QueueQuery {
+    #[serde(default = "default_queue_limit")]
+    limit: i64,
+    channel: Option<String>,
+}
+
+fn default_queue_limit() -> i64 {
+    10
+}
+
+/// Most entries the queue endpoint lists.
+const MAX_QUEUE_LIMIT: i64 = 1000;
+
+async fn queue(
+    State(state): State<ApiState>,
+    Query(query): Query<QueueQuery>,
+) -> ApiResult<Vec<QueueItem>> {
+    let (database, _) = state.channel(query.channel.as_deref())?;
+    let limit = query.limit.clamp(1, MAX_QUEUE_LIMIT);
+    let entries = database.upcoming_entries(limit).await?;
+    Ok(Json(database.with_videos(entries).await?))
+}
+
+async fn downloads(State(state): State<ApiState>) -> ApiResult<Vec<QueueItem>> {
+    let entries = state.database.download_queue().await?;
+    Ok(Json(state.database.with_videos(entries).await?))
+}
+
+#[derive(Deserialize, Default)]
+struct ReasonBody {
+    reason: Option<String>,
+}
+
+async fn skip(
+    State(state): State<ApiState>,
+    Query(query): Query<ChannelQuery>,
+    body: Option<Json<ReasonBody>>,
+) -> std::result::Result<StatusCode, ApiError> {
+    let (_, player) = state.channel(query.channel.as_deref())?;
+    let Json(body) = body.unwrap_or_default();
+    let reason = body.reason.unwrap_or_else(|| "skipped via API".into());
+    player.skip(reason).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn pause(
+    State(state): State<ApiState>,
+    Query(query): Query<ChannelQuery>,
+) -> std::result::Result<StatusCode, ApiError> {
+    let (_, player) = state.channel(query.channel.as_deref())?;
+    player.pause().await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn resume(
+    State(state): State<ApiState>,
+    Query(query): Query<ChannelQuery>,
+) -> std::result::Result<StatusCode, ApiError> {
+    let (_, player) = state.channel(query.channel.as_deref())?;
+    player.resume().await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    identifier: String,
+}
+
+async fn enqueue(
+    State(state): State<ApiState>,
+    Json(body): Json<EnqueueRequest>,
+) -> ApiResult<PlaylistEntry> {
+    let video = state
+        .database
+        .fetch_video(&VideoId::IaIdentifier(body.identifier))
+        .await?;
+    Ok(Json(state.database.enqueue_next(video.id).await?))
+}
+
+#[derive(Serialize)]
+struct CurrentVote {
+    round: VoteRound,
+    tally: Vec<VoteTally>,
+}
+
+async fn current_vote(State(state): State<ApiState>) -> ApiResult<Option<CurrentVote>> {
+    let Some(round) = state.database.current_vote_round().await? else {
+        return Ok(Json(None));
+    };
+    let tally = state.database.vote_tally(round.id).await?;
+    Ok(Json(Some(CurrentVote { round, tally })))
+}
+
+#[derive(Deserialize)]
+struct VoteRequest {
+    /// Name of the viewer, each viewer has one vote per round
+    voter: String,
+    choice: i32,
+}
+
+/// Votes on behalf of a viewer, for overlays or extensions that collect votes
+/// outside of the chat.
+async fn vote(
+    State(state): State<ApiState>,
+    Json(body): Json<VoteRequest>,
+) -> std::result::Result<StatusCode, ApiError> {
+    state.database.cast_vote(&body.voter, body.choice).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pending_requests(State(state): State<ApiState>) -> ApiResult<Vec<VideoRequest>> {
+    Ok(Json(state.database.pending_requests().await?))
+}
+
+#[derive(Deserialize)]
+struct SubmitRequest {
+    requested_by: String,
+    /// An identifier or a search term
+    query: String,
+}
+
+async fn submit_request(
+    State(state): State<ApiState>,
+    Json(body): Json<SubmitRequest>,
+) -> std::result::Result<Response, ApiError> {
+    if !state.requests.enabled {
+        let body = serde_json::json!({ "error": "video requests are disabled" });
+        return Ok((StatusCode::FORBIDDEN, Json(body)).into_response());
+    }
+    let request = state
+        .database
+        .submit_request(&body.requested_by, &body.query, &state.requests)
+        .await?;
+    Ok((StatusCode::CREATED, Json(request)).into_response())
+}
+
+async fn approve_request(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+) -> ApiResult<PlaylistEntry> {
+    Ok(Json(state.database.approve_request(id, "api").await?))
+}
+
+async fn reject_request(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+    body: Option<Json<ReasonBody>>,
+) -> std::result::Result<StatusCode, ApiError> {
+    let Json(body) = body.unwrap_or_default();
+    state
+        .database
+        .reject_request(id, "api", body.reason.as_deref())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn premieres(State(state): State<ApiState>) -> ApiResult<Vec<Premiere>> {
+    Ok(Json(state.database.upcoming_premieres().await?))
+}
+
+#[derive(Deserialize)]
+struct CreatePremiere {
+    identifier: String,
+    #[serde(with = "time::serde::rfc3339")]
+    starts_at: OffsetDateTime,
+}
+
+async fn create_premiere(
+    State(state): State<ApiState>,
+    Json(body): Json<CreatePremiere>,
+) -> std::result::Result<(StatusCode, Json<Premiere>), ApiError> {
+    let video_id = state.database.get_video_id(&body.identifier).await?;
+    let premiere = state
+        .database
+        .create_premiere(video_id, body.starts_at)
+        .await?;
+    Ok((StatusCode::CREATED, Json(premiere)))
+}
+
+async fn cancel_premiere(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+) -> std::result::Result<StatusCode, ApiError> {
+    state.database.cancel_premiere(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,353 @@
+use camino::Utf8PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::bail;
+use gb_forever::{
+    config::load_config,
+    db::{Database, GbVideo, PlaylistEntryStatus, VideoId},
+    downloader::DownloadOrchestrator,
+    epg::Playing,
+    export::{self, ExportFormat},
+    ia::InternetArchive,
+    rules::ContentRules,
+    schedule::parse_blocks,
+    Result,
+};
+use time::{macros::format_description, OffsetDateTime};
+use tracing_subscriber::EnvFilter;
+
+/// Manage the gb-forever catalog and playlist.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Search the catalog by title, identifier or description
+    Search {
+        query: String,
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Show the current and upcoming playlist entries
+    Playlist {
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Insert a video into the playlist, by default right after the current one
+    Insert {
+        identifier: String,
+        /// Position among the upcoming entries, starting at 1
+        #[arg(long)]
+        position: Option<i64>,
+    },
+    /// Remove an entry from the playlist
+    Remove { entry_id: i64 },
+    /// Never play a video again
+    Blocklist {
+        identifier: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Allow a blocklisted video to play again
+    Unblocklist { identifier: String },
+    /// Blocklist the videos the content rules of the config exclude
+    Rules,
+    /// Put a video into a series by hand, overriding the detected one
+    Series {
+        identifier: String,
+        /// Name of the series, videos with the same name play together
+        #[arg(required_unless_present_any = ["standalone", "clear"])]
+        name: Option<String>,
+        /// Place within the series, by default taken from the title
+        #[arg(long)]
+        part: Option<i32>,
+        /// Keep the video out of any series
+        #[arg(long, conflicts_with_all = ["name", "clear"])]
+        standalone: bool,
+        /// Detect the series from the metadata again
+        #[arg(long, conflicts_with = "name")]
+        clear: bool,
+    },
+    /// Shuffle the entries that have not played yet
+    Reshuffle,
+    /// Move all entries with the given status back to unplayed
+    Reset {
+        #[arg(value_enum)]
+        status: PlaylistEntryStatus,
+    },
+    /// Download a video from the playlist right away
+    Download { identifier: String },
+    /// Show viewer requests waiting for moderation
+    Requests,
+    /// Splice a requested video into the playlist
+    Approve { request_id: i64 },
+    /// Turn down a viewer request
+    Reject {
+        request_id: i64,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Show when the programming blocks of the schedule run next
+    Schedule {
+        #[arg(long, default_value_t = 7)]
+        days: i64,
+    },
+    /// Write the upcoming videos or the play history as a playlist file
+    Export {
+        #[arg(value_enum)]
+        what: ExportKind,
+        #[arg(long, value_enum, default_value_t = ExportFormat::M3u)]
+        format: ExportFormat,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+        /// File to write to instead of stdout
+        #[arg(long)]
+        output: Option<Utf8PathBuf>,
+    },
+    /// Print play statistics
+    Stats {
+        #[arg(long, default_value_t = 14)]
+        days: i32,
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+}
+
+#[derive(Clone, ValueEnum)]
+enum ExportKind {
+    Upcoming,
+    History,
+}
+
+fn print_video(video: &GbVideo) {
+    println!(
+        "{:<40} {:<12} {}",
+        video.identifier,
+        video.date.as_deref().unwrap_or("-"),
+        video.title
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _ = dotenvy::dotenv();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let cli = Cli::parse();
+    let config = load_config()?;
+    let database = Database::connect(&config.database_url).await?;
+
+    match cli.command {
+        Command::Search { query, limit } => {
+            for video in database.search_videos(&query, limit).await? {
+                print_video(&video);
+            }
+        }
+        Command::Playlist { limit } => {
+            let current = database.current_video().await?.into_iter().collect();
+            for item in database.with_videos(current).await? {
+                println!("now playing: {}", item.video.title);
+            }
+            let upcoming = database.upcoming_entries(limit).await?;
+            for (index, item) in database.with_videos(upcoming).await?.iter().enumerate() {
+                println!(
+                    "{:>3}. [entry {:>6}] {:<10} {}",
+                    index + 1,
+                    item.entry.id,
+                    item.entry.status,
+                    item.video.title
+                );
+            }
+        }
+        Command::Insert {
+            identifier,
+            position,
+        } => {
+            let video_id = database.get_video_id(&identifier).await?;
+            let slot = position.map(|p| (p - 1).max(0));
+            let entry = database.enqueue_at(video_id, slot).await?;
+            println!("inserted {identifier} as entry {}", entry.id);
+        }
+        Command::Remove { entry_id } => {
+            database.remove_entry(entry_id).await?;
+            println!("removed entry {entry_id}");
+        }
+        Command::Blocklist { identifier, reason } => {
+            let video_id = database.get_video_id(&identifier).await?;
+            database
+                .blocklist_video(video_id, reason.as_deref())
+                .await?;
+            println!("blocklisted {identifier}");
+        }
+        Command::Unblocklist { identifier } => {
+            let video_id = database.get_video_id(&identifier).await?;
+            database.unblocklist_video(video_id).await?;
+            println!("removed {identifier} from the blocklist");
+        }
+        Command::Rules => {
+            let rules = ContentRules::from_config(&config.rules)?;
+            let count = rules.apply(&database).await?;
+            println!("content rules exclude {count} videos");
+        }
+        Command::Series {
+            identifier,
+            name,
+            part,
+            standalone: _,
+            clear,
+        } => {
+            let video_id = database.get_video_id(&identifier).await?;
+            if clear {
+                database.clear_series_override(video_id).await?;
+                println!("{identifier} is detected again");
+            } else {
+                database
+                    .set_series_override(video_id, name.as_deref(), part)
+                    .await?;
+                match name {
+                    Some(name) => println!("{identifier} is part of {name}"),
+                    None => println!("{identifier} is not part of any series"),
+                }
+            }
+        }
+        Command::Reshuffle => {
+            let count = database.reshuffle_upcoming().await?;
+            println!("reshuffled {count} entries");
+        }
+        Command::Reset { status } => {
+            match status {
+                PlaylistEntryStatus::Active => {
+                    bail!("the active entry can not be reset, skip it instead")
+                }
+                PlaylistEntryStatus::Unplayed => bail!("unplayed entries need no reset"),
+                _ => {}
+            }
+            let (count, files) = database.reset_entries(status).await?;
+            for file in &files {
+                if let Err(e) = tokio::fs::remove_file(file).await {
+                    eprintln!("failed to delete {file}: {e}");
+                }
+            }
+            println!(
+                "reset {count} {status} entries to unplayed, deleted {} files",
+                files.len()
+            );
+        }
+        Command::Download { identifier } => {
+            let downloader = DownloadOrchestrator::new(
+                database.clone(),
+                InternetArchive::default(),
+                config.video_path.clone(),
+                config.dead_air.clone(),
+            );
+            tokio::fs::create_dir_all(&config.video_path).await?;
+            downloader
+                .download_single_video(VideoId::IaIdentifier(identifier.clone()))
+                .await?;
+            println!("downloaded {identifier}");
+        }
+        Command::Requests => {
+            for request in database.pending_requests().await? {
+                println!(
+                    "{:>6}  {:<20} {} ({})",
+                    request.id, request.requested_by, request.title, request.query
+                );
+            }
+        }
+        Command::Approve { request_id } => {
+            let entry = database.approve_request(request_id, "gbctl").await?;
+            println!("approved request {request_id} as entry {}", entry.id);
+        }
+        Command::Reject { request_id, reason } => {
+            database
+                .reject_request(request_id, "gbctl", reason.as_deref())
+                .await?;
+            println!("rejected request {request_id}");
+        }
+        Command::Schedule { days } => {
+            let now = OffsetDateTime::now_utc();
+            let mut occurrences: Vec<_> = parse_blocks(&config.schedule)?
+                .into_iter()
+                .flat_map(|block| {
+                    block
+                        .occurrences(now, days)
+                        .map(|(start, end)| (start, end, block.name.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            occurrences.sort();
+            let format =
+                format_description!("[weekday repr:short] [year]-[month]-[day] [hour]:[minute]");
+            for (start, end, name) in occurrences {
+                println!(
+                    "{} - {}  {name}",
+                    start.format(&format)?,
+                    end.format(&format)?
+                );
+            }
+        }
+        Command::Export {
+            what,
+            format,
+            limit,
+            output,
+        } => {
+            let (title, items) = match what {
+                ExportKind::Upcoming => {
+                    let playing = Playing::from_database(&database).await?;
+                    (
+                        "upcoming",
+                        export::upcoming(&database, playing, limit).await?,
+                    )
+                }
+                ExportKind::History => ("history", export::history(&database, limit).await?),
+            };
+            let title = format!("{} {title}", config.epg.channel_name);
+            let rendered = export::render(format, &title, &items)?;
+            match output {
+                Some(path) => {
+                    tokio::fs::write(&path, rendered).await?;
+                    println!("wrote {} videos to {path}", items.len());
+                }
+                None => print!("{rendered}"),
+            }
+        }
+        Command::Stats { days, limit } => {
+            println!(
+                "total hours streamed: {:.1}",
+                database.total_hours_streamed().await?
+            );
+            println!("\nmost played:");
+            for video in database.most_played_videos(limit).await? {
+                println!("{:>5}  {}", video.plays, video.title);
+            }
+            println!("\nmost played shows:");
+            for show in database.most_played_shows(limit).await? {
+                println!("{:>5}  {}", show.plays, show.show);
+            }
+            println!("\nplays per creator:");
+            for creator in database.plays_per_creator(limit).await? {
+                println!("{:>5}  {}", creator.plays, creator.creator);
+            }
+            println!("\nplays per day:");
+            for day in database.plays_per_day(days).await? {
+                println!("{:>5}  {}", day.plays, day.day);
+            }
+            println!("\nvotes (won / offered / votes):");
+            for video in database.vote_stats(limit).await? {
+                println!(
+                    "{:>3} / {:>3} / {:>5}  {}",
+                    video.won, video.offered, video.votes, video.title
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,5 @@
-use futures::{pin_mut, TryStreamExt};
-use gb_forever::{db::Database, ia::InternetArchive, Result};
+use gb_forever::{db::Database, ia::InternetArchive, sync::CatalogSync, Result};
 use std::env;
-use tracing::info;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -17,17 +15,8 @@ async fn main() -> Result<()> {
     let database = Database::connect(&url).await?;
     let ia = InternetArchive::default();
 
-    let stream = ia
-        .search_all("collection:giant-bomb-archive")
-        .try_chunks(10_000);
-    pin_mut!(stream);
-
-    while let Some(chunk) = stream.try_next().await? {
-        info!("got chunk of {} items", chunk.len());
-        database.insert_items(&chunk).await?;
-    }
-
-    database.create_random_playlist().await?;
+    // the player builds the playlist once it finds it empty
+    CatalogSync::new(database, ia).sync().await?;
 
     Ok(())
 }
@@ -0,0 +1,26 @@
+use gb_forever::{db::Database, ia::InternetArchive, sync::CatalogSync, Result};
+use std::env;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _ = dotenvy::dotenv();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let url = env::var("DATABASE_URL")?;
+    let database = Database::connect(&url).await?;
+    let ia = InternetArchive::default();
+
+    let report = CatalogSync::new(database, ia).sync().await?;
+    info!(
+        "{} items in the archive, {} added, {} changed, {} removed, {} file listings fetched",
+        report.seen, report.inserted, report.updated, report.removed, report.details_fetched
+    );
+
+    Ok(())
+}
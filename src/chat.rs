@@ -0,0 +1,536 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use color_eyre::eyre::bail;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{ChatConfig, RequestConfig},
+    db::{Database, InvalidRequest, InvalidVote},
+    on_this_day,
+    player::{format_duration, PlayerHandle},
+    supervisor::Supervisor,
+    Result,
+};
+
+/// Twitch drops messages longer than this.
+const MAX_MESSAGE_LENGTH: usize = 450;
+/// Twitch allows this many messages per [`RATE_LIMIT_WINDOW`] and locks the
+/// bot out for a while when it sends more.
+const RATE_LIMIT_MESSAGES: usize = 20;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const NEXT_COUNT: i64 = 3;
+const SEARCH_COUNT: i64 = 3;
+const PENDING_REQUESTS_COUNT: usize = 5;
+
+/// A single line of the IRC protocol, including IRCv3 tags as used by Twitch.
+#[derive(Debug)]
+pub struct IrcMessage<'a> {
+    pub tags: HashMap<&'a str, &'a str>,
+    pub prefix: Option<&'a str>,
+    pub command: &'a str,
+    pub params: Vec<&'a str>,
+}
+
+impl<'a> IrcMessage<'a> {
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut tags = HashMap::new();
+        if let Some(stripped) = rest.strip_prefix('@') {
+            let (raw_tags, remainder) = stripped.split_once(' ')?;
+            for tag in raw_tags.split(';') {
+                let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                tags.insert(key, value);
+            }
+            rest = remainder;
+        }
+
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (raw_prefix, remainder) = stripped.split_once(' ')?;
+            prefix = Some(raw_prefix);
+            rest = remainder;
+        }
+
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut parts = middle.split_whitespace();
+        let command = parts.next()?;
+        let mut params: Vec<_> = parts.collect();
+        params.extend(trailing);
+
+        Some(Self {
+            tags,
+            prefix,
+            command,
+            params,
+        })
+    }
+
+    /// The nickname of the sender, taken from the `nick!user@host` prefix.
+    pub fn nick(&self) -> Option<&'a str> {
+        let prefix = self.prefix?;
+        Some(prefix.split_once('!').map_or(prefix, |(nick, _)| nick))
+    }
+}
+
+/// Cheap to clone handle to post messages through a running [`ChatBot`].
+#[derive(Clone)]
+pub struct ChatHandle {
+    outgoing: mpsc::Sender<String>,
+}
+
+impl ChatHandle {
+    /// Queues a message for the channel. Messages are dropped if the bot can
+    /// not keep up, e.g. while it is reconnecting.
+    pub fn say(&self, message: impl Into<String>) {
+        if let Err(e) = self.outgoing.try_send(message.into()) {
+            warn!("dropping chat message: {e}");
+        }
+    }
+}
+
+/// Answers viewer commands in the Twitch chat of the channel.
+pub struct ChatBot {
+    config: ChatConfig,
+    requests: RequestConfig,
+    database: Database,
+    player: PlayerHandle,
+    outgoing: Mutex<mpsc::Receiver<String>>,
+    started_at: Instant,
+}
+
+impl ChatBot {
+    pub fn new(
+        config: ChatConfig,
+        requests: RequestConfig,
+        database: Database,
+        player: PlayerHandle,
+    ) -> (Self, ChatHandle) {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(16);
+        let bot = Self {
+            config,
+            requests,
+            database,
+            player,
+            outgoing: Mutex::new(outgoing_rx),
+            started_at: Instant::now(),
+        };
+        (
+            bot,
+            ChatHandle {
+                outgoing: outgoing_tx,
+            },
+        )
+    }
+
+    /// Spawns the bot, reconnecting whenever the connection drops.
+    pub fn start(self, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            loop {
+                if let Err(e) = self.run_session().await {
+                    error!("chat connection failed: {e}");
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+
+    async fn run_session(&self) -> Result<()> {
+        let address = (self.config.server.as_str(), self.config.port);
+        let stream = TcpStream::connect(address).await?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        let channel = format!("#{}", self.config.channel.to_lowercase());
+        if let Some(password) = &self.config.password {
+            send_line(&mut write, &format!("PASS {password}")).await?;
+        }
+        send_line(&mut write, &format!("NICK {}", self.config.nickname)).await?;
+        send_line(&mut write, "CAP REQ :twitch.tv/tags twitch.tv/commands").await?;
+        send_line(&mut write, &format!("JOIN {channel}")).await?;
+        info!("connected to chat of {channel}");
+
+        let mut outgoing = self.outgoing.lock().await;
+        let mut limit = RateLimit::default();
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => match line? {
+                    Some(line) => line,
+                    None => break,
+                },
+                Some(text) = outgoing.recv() => {
+                    limit.wait().await;
+                    send_line(&mut write, &privmsg(&channel, &text)).await?;
+                    continue;
+                }
+            };
+            let Some(message) = IrcMessage::parse(&line) else {
+                continue;
+            };
+            match message.command {
+                "PING" => {
+                    let token = message.params.first().copied().unwrap_or_default();
+                    send_line(&mut write, &format!("PONG :{token}")).await?;
+                }
+                "RECONNECT" => bail!("server asked us to reconnect"),
+                "PRIVMSG" => {
+                    let Some(text) = message.params.get(1) else {
+                        continue;
+                    };
+                    if !text.starts_with('!') {
+                        continue;
+                    }
+                    let reply = match self.handle_command(&message, text).await {
+                        Ok(reply) => reply,
+                        Err(e) => match user_error(&e) {
+                            Some(error) => {
+                                Some(format!("@{} {error}", message.nick().unwrap_or_default()))
+                            }
+                            None => {
+                                warn!("chat command {text} failed: {e}");
+                                Some("Something went wrong, sorry!".into())
+                            }
+                        },
+                    };
+                    if let Some(reply) = reply {
+                        limit.wait().await;
+                        send_line(&mut write, &privmsg(&channel, &reply)).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_moderator(&self, message: &IrcMessage<'_>) -> bool {
+        is_moderator(message, &self.config)
+    }
+
+    async fn handle_command(&self, message: &IrcMessage<'_>, text: &str) -> Result<Option<String>> {
+        let (command, argument) = text.split_once(' ').unwrap_or((text, ""));
+        let argument = argument.trim();
+
+        let reply = match command.to_lowercase().as_str() {
+            "!nowplaying" | "!np" => self.now_playing().await?,
+            "!next" => self.next().await?,
+            "!uptime" => format!(
+                "Live for {}",
+                format_duration(self.started_at.elapsed().as_secs_f64())
+            ),
+            "!search" if argument.is_empty() => "Usage: !search <title>".into(),
+            "!search" => self.search(argument).await?,
+            "!vote" => return self.vote(message, argument).await,
+            "!request" if self.requests.enabled => return self.request(message, argument).await,
+            "!requests" if self.requests.enabled && self.is_moderator(message) => {
+                self.pending_requests().await?
+            }
+            "!approve" if self.requests.enabled && self.is_moderator(message) => {
+                self.decide_request(message, argument, true).await?
+            }
+            "!reject" if self.requests.enabled && self.is_moderator(message) => {
+                self.decide_request(message, argument, false).await?
+            }
+            "!skip" if self.is_moderator(message) => {
+                let nick = message.nick().unwrap_or("a moderator");
+                self.player
+                    .skip(format!("skipped by {nick} in chat"))
+                    .await?;
+                "Skipping the current video".into()
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(reply))
+    }
+
+    async fn now_playing(&self) -> Result<String> {
+        let current = self.database.current_video().await?.into_iter().collect();
+        let Some(item) = self.database.with_videos(current).await?.pop() else {
+            return Ok("Nothing is playing right now".into());
+        };
+        let position = format_duration(self.player.state().position);
+        let playing = match item.video.runtime {
+            Some(runtime) => format!(
+                "Now playing: {} ({position} / {})",
+                item.video.title,
+                format_duration(runtime)
+            ),
+            None => format!("Now playing: {} ({position})", item.video.title),
+        };
+        Ok(
+            match on_this_day::years_ago(item.video.published_at, OffsetDateTime::now_utc().date())
+            {
+                Some(1) => format!("{playing}, originally aired 1 year ago today"),
+                Some(years) => format!("{playing}, originally aired {years} years ago today"),
+                None => playing,
+            },
+        )
+    }
+
+    async fn next(&self) -> Result<String> {
+        let upcoming = self.database.upcoming_entries(NEXT_COUNT).await?;
+        let titles: Vec<_> = self
+            .database
+            .with_videos(upcoming)
+            .await?
+            .into_iter()
+            .map(|item| item.video.title)
+            .collect();
+        if titles.is_empty() {
+            Ok("Nothing is queued up".into())
+        } else {
+            Ok(format!("Up next: {}", titles.join(" | ")))
+        }
+    }
+
+    /// Casts a vote with `!vote <n>`, or shows the candidates with just `!vote`.
+    /// Successful votes are not answered to keep the chat readable.
+    async fn vote(&self, message: &IrcMessage<'_>, argument: &str) -> Result<Option<String>> {
+        let Some(nick) = message.nick() else {
+            return Ok(None);
+        };
+        if argument.is_empty() {
+            let Some(round) = self.database.current_vote_round().await? else {
+                return Ok(Some("There is no vote running right now".into()));
+            };
+            let tally = self.database.vote_tally(round.id).await?;
+            let candidates: Vec<_> = tally
+                .iter()
+                .map(|t| format!("{}: {} ({} votes)", t.choice, t.title, t.votes))
+                .collect();
+            return Ok(Some(format!(
+                "Vote with !vote <n> | {}",
+                candidates.join(" | ")
+            )));
+        }
+        let Ok(choice) = argument.parse() else {
+            return Ok(Some(format!("@{nick} usage: !vote <n>")));
+        };
+
+        self.database.cast_vote(nick, choice).await?;
+        Ok(None)
+    }
+
+    async fn request(&self, message: &IrcMessage<'_>, query: &str) -> Result<Option<String>> {
+        let Some(nick) = message.nick() else {
+            return Ok(None);
+        };
+        if query.is_empty() {
+            return Ok(Some(format!(
+                "@{nick} usage: !request <identifier or title>"
+            )));
+        }
+        let request = self
+            .database
+            .submit_request(nick, query, &self.requests)
+            .await?;
+        Ok(Some(format!(
+            "@{nick} requested {} (#{}), a moderator will take a look",
+            request.title, request.id
+        )))
+    }
+
+    async fn pending_requests(&self) -> Result<String> {
+        let requests = self.database.pending_requests().await?;
+        if requests.is_empty() {
+            return Ok("No requests are waiting".into());
+        }
+        let pending: Vec<_> = requests
+            .iter()
+            .take(PENDING_REQUESTS_COUNT)
+            .map(|r| format!("#{} {} (by {})", r.id, r.title, r.requested_by))
+            .collect();
+        Ok(format!(
+            "{} waiting: {}",
+            requests.len(),
+            pending.join(" | ")
+        ))
+    }
+
+    async fn decide_request(
+        &self,
+        message: &IrcMessage<'_>,
+        argument: &str,
+        approve: bool,
+    ) -> Result<String> {
+        let moderator = message.nick().unwrap_or("a moderator");
+        let (id, reason) = argument.split_once(' ').unwrap_or((argument, ""));
+        let Ok(id) = id.trim_start_matches('#').parse() else {
+            let command = if approve { "!approve" } else { "!reject" };
+            return Ok(format!("@{moderator} usage: {command} <request id>"));
+        };
+
+        let request = self.database.fetch_request(id).await?;
+        if approve {
+            self.database.approve_request(id, moderator).await?;
+            Ok(format!(
+                "@{} your request {} is coming up soon",
+                request.requested_by, request.title
+            ))
+        } else {
+            let reason = Some(reason.trim()).filter(|r| !r.is_empty());
+            self.database.reject_request(id, moderator, reason).await?;
+            Ok(match reason {
+                Some(reason) => format!(
+                    "@{} your request {} was rejected: {reason}",
+                    request.requested_by, request.title
+                ),
+                None => format!(
+                    "@{} your request {} was rejected",
+                    request.requested_by, request.title
+                ),
+            })
+        }
+    }
+
+    async fn search(&self, query: &str) -> Result<String> {
+        let videos = self.database.search_videos(query, SEARCH_COUNT).await?;
+        if videos.is_empty() {
+            return Ok(format!("No videos found for \"{query}\""));
+        }
+        let results: Vec<_> = videos
+            .iter()
+            .map(|v| format!("{} ({})", v.title, v.identifier))
+            .collect();
+        Ok(results.join(" | "))
+    }
+}
+
+/// The message for errors caused by what a viewer typed, which are answered in
+/// chat instead of being logged.
+fn user_error(e: &color_eyre::Report) -> Option<String> {
+    if let Some(invalid) = e.downcast_ref::<InvalidVote>() {
+        Some(invalid.to_string())
+    } else {
+        e.downcast_ref::<InvalidRequest>().map(|i| i.to_string())
+    }
+}
+
+/// Whether the sender has a moderator or broadcaster badge, or is listed as a
+/// moderator in the config.
+fn is_moderator(message: &IrcMessage<'_>, config: &ChatConfig) -> bool {
+    let badges = message.tags.get("badges").copied().unwrap_or_default();
+    let has_badge = badges
+        .split(',')
+        .any(|badge| badge.starts_with("moderator/") || badge.starts_with("broadcaster/"));
+    let is_listed = message.nick().is_some_and(|nick| {
+        nick.eq_ignore_ascii_case(&config.channel)
+            || config
+                .moderators
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(nick))
+    });
+
+    has_badge || message.tags.get("mod") == Some(&"1") || is_listed
+}
+
+/// A message to the channel. Line breaks and other control characters would
+/// end the message early or smuggle in further commands, so they become spaces.
+fn privmsg(channel: &str, text: &str) -> String {
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(MAX_MESSAGE_LENGTH)
+        .collect();
+    format!("PRIVMSG {channel} :{text}")
+}
+
+/// Keeps the bot below Twitch's message rate limit.
+#[derive(Default)]
+struct RateLimit {
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    /// Waits until another message may be sent and counts it as sent.
+    async fn wait(&mut self) {
+        if self.sent.len() >= RATE_LIMIT_MESSAGES {
+            if let Some(oldest) = self.sent.pop_front() {
+                tokio::time::sleep_until(oldest + RATE_LIMIT_WINDOW).await;
+            }
+        }
+        self.sent.push_back(Instant::now());
+    }
+}
+
+async fn send_line(write: &mut OwnedWriteHalf, line: &str) -> Result<()> {
+    write.write_all(line.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_twitch_messages() {
+        let line = "@badges=moderator/1,subscriber/12;display-name=Vinny;mod=1 \
+            :vinny!vinny@vinny.tmi.twitch.tv PRIVMSG #giantbomb :!skip this one\r\n";
+        let message = IrcMessage::parse(line).unwrap();
+        assert_eq!(message.tags["display-name"], "Vinny");
+        assert_eq!(message.tags["badges"], "moderator/1,subscriber/12");
+        assert_eq!(message.nick(), Some("vinny"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, ["#giantbomb", "!skip this one"]);
+
+        let ping = IrcMessage::parse("PING :tmi.twitch.tv").unwrap();
+        assert_eq!((ping.prefix, ping.command), (None, "PING"));
+        assert_eq!(ping.params, ["tmi.twitch.tv"]);
+
+        assert!(IrcMessage::parse("").is_none());
+        assert!(IrcMessage::parse("@only-tags").is_none());
+    }
+
+    #[test]
+    fn recognizes_moderators() {
+        let config = ChatConfig {
+            channel: "GiantBomb".into(),
+            moderators: vec!["Jeff".into()],
+            ..Default::default()
+        };
+        let moderator = |line: &str| is_moderator(&IrcMessage::parse(line).unwrap(), &config);
+
+        assert!(moderator(
+            "@badges=moderator/1 :drew!drew@host PRIVMSG #giantbomb :!skip"
+        ));
+        assert!(moderator(
+            "@mod=1 :drew!drew@host PRIVMSG #giantbomb :!skip"
+        ));
+        assert!(moderator(":jeff!jeff@host PRIVMSG #giantbomb :!skip"));
+        assert!(moderator(
+            ":giantbomb!giantbomb@host PRIVMSG #giantbomb :!skip"
+        ));
+        assert!(!moderator(
+            "@badges=subscriber/12;mod=0 :ryan!ryan@host PRIVMSG #giantbomb :!skip"
+        ));
+    }
+
+    #[test]
+    fn messages_stay_on_one_line() {
+        assert_eq!(
+            privmsg("#giantbomb", "Now playing: Line\r\nJOIN #other"),
+            "PRIVMSG #giantbomb :Now playing: Line  JOIN #other"
+        );
+        let long = privmsg("#giantbomb", &"a".repeat(1000));
+        assert_eq!(
+            long.len(),
+            "PRIVMSG #giantbomb :".len() + MAX_MESSAGE_LENGTH
+        );
+    }
+}
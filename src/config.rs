@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use camino::Utf8PathBuf;
 use config::{Config, Environment, File};
 use serde::Deserialize;
@@ -9,6 +11,569 @@ pub struct AppConfig {
     pub database_url: String,
     pub stream_key: String,
     pub video_path: Utf8PathBuf,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub dead_air: DeadAirConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub player: PlayerConfig,
+    #[serde(default)]
+    pub playlist: PlaylistConfig,
+    #[serde(default)]
+    pub rules: RulesConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub chat: ChatConfig,
+    #[serde(default)]
+    pub voting: VotingConfig,
+    #[serde(default)]
+    pub requests: RequestConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub on_this_day: OnThisDayConfig,
+    #[serde(default)]
+    pub epg: EpgConfig,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    /// Channels that stream next to the main one
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+}
+
+/// Where a channel streams to and how it is encoded.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// RTMP URL the stream key is appended to
+    pub destination: String,
+    /// x264 preset
+    pub preset: String,
+    pub video_bitrate: String,
+    pub audio_bitrate: String,
+    /// Size every video is scaled to, with black bars if the aspect ratio
+    /// differs
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            destination: "rtmp://live-ber.twitch.tv/app".into(),
+            preset: "veryfast".into(),
+            video_bitrate: "6000k".into(),
+            audio_bitrate: "160k".into(),
+            width: 1920,
+            height: 1080,
+            framerate: 30,
+        }
+    }
+}
+
+/// A channel with its own playlist. Downloaded videos are shared with the
+/// other channels, everything else is configured once for the main channel.
+/// The now playing, queue, skip, pause, resume and health endpoints of the API
+/// select a channel with `?channel=<name>`. Chat, voting, schedule,
+/// premieres, the EPG, notifications and the dashboard only know the main
+/// channel.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChannelConfig {
+    /// Lowercase letters, digits, `-` and `_`, as it is part of file names
+    pub name: String,
+    pub stream_key: String,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub playlist: PlaylistConfig,
+}
+
+/// Settings for playing back the playlist.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PlayerConfig {
+    /// Number of upcoming videos that are kept downloaded
+    pub download_buffer: usize,
+    /// Seconds after which a download that did not start is requested again
+    pub download_retry: u64,
+    /// Seconds between two saves of the playback position
+    pub progress_interval: u64,
+    /// Seconds to wait before trying again when nothing can be played
+    pub retry_delay: u64,
+    /// Times playing an entry may fail before it is skipped
+    pub max_attempts: u32,
+    /// Seconds before a premiere when its video is put into the playlist, so
+    /// it is downloaded in time
+    pub premiere_lead_time: u64,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            download_buffer: 5,
+            download_retry: 300,
+            progress_interval: 10,
+            retry_delay: 5,
+            max_attempts: 3,
+            premiere_lead_time: 7200,
+        }
+    }
+}
+
+/// How the playlist is put together whenever every video in it has played.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PlaylistConfig {
+    pub strategy: StrategyKind,
+    /// Only videos matching this play
+    pub filter: CatalogFilter,
+    pub series: SeriesConfig,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    /// Random order
+    #[default]
+    Shuffle,
+    /// In the order the videos were first published
+    Chronological,
+    /// Random order, but videos that played less often tend to come first
+    Weighted,
+}
+
+/// How shuffles deal with videos that are parts of a series.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SeriesConfig {
+    /// Whether the parts of a series play together and in order
+    pub keep_together: bool,
+    /// Number of parts that play back to back, the whole series if 0. Long
+    /// running shows like the Bombcast count as a series too, so this should
+    /// stay small.
+    pub chunk_size: usize,
+    /// Archive subjects, like `Endurance Run`, whose videos form a series in
+    /// the order they were published
+    pub subjects: Vec<String>,
+}
+
+impl Default for SeriesConfig {
+    fn default() -> Self {
+        Self {
+            keep_together: true,
+            chunk_size: 3,
+            subjects: vec![],
+        }
+    }
+}
+
+/// Decides which archive items may air at all. Videos that are excluded end
+/// up on the blocklist.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RulesConfig {
+    /// If there are any, only videos matching one of them air
+    pub include: Vec<RuleConfig>,
+    /// Videos matching one of these never air
+    pub exclude: Vec<RuleConfig>,
+    /// Identifiers of archive items that never air
+    pub blocklist: Vec<String>,
+}
+
+/// Matches videos for which all set conditions hold. Conditions on values a
+/// video does not have, like an unknown duration, never hold.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub name: String,
+    /// Matches case-insensitively anywhere in a collection name
+    pub collection: Option<String>,
+    /// Matches case-insensitively anywhere in the creator
+    pub creator: Option<String>,
+    /// Regular expression matched case-insensitively against the title
+    pub title: Option<String>,
+    /// Earliest publication date as `YYYY-MM-DD`
+    pub published_after: Option<String>,
+    /// Latest publication date as `YYYY-MM-DD`
+    pub published_before: Option<String>,
+    /// Item size in bytes
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    /// Runtime in seconds
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+}
+
+/// Settings for the HTTP control API.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+    /// Bearer token that has to be sent with every request
+    pub token: Option<String>,
+    /// Seconds after which a dashboard login has to be repeated
+    pub session_max_age: u64,
+    pub health: HealthConfig,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: "127.0.0.1:8080".into(),
+            token: None,
+            session_max_age: 12 * 60 * 60,
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+/// Thresholds of the `/healthz` and `/readyz` endpoints.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds without progress from ffmpeg after which the stream counts as
+    /// stuck
+    pub max_progress_age: u64,
+    /// Seconds without progress from ffmpeg after which the player counts as
+    /// stuck even if it is not playing anything, because it keeps failing or
+    /// has nothing downloaded to play
+    pub max_idle: u64,
+    /// Seconds of downloaded videos that have to be waiting to play to be ready
+    pub min_buffer: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_progress_age: 30,
+            max_idle: 900,
+            min_buffer: 1800,
+        }
+    }
+}
+
+/// Settings for the periodic catalog sync with the archive.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub enabled: bool,
+    /// Hours between two syncs
+    pub interval_hours: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_hours: 24,
+        }
+    }
+}
+
+/// Settings for detecting black or silent intros and outros.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DeadAirConfig {
+    pub enabled: bool,
+    /// How many seconds at the start and at the end of each video are analyzed
+    pub scan_window: f64,
+    /// Minimum length in seconds of a black or silent section
+    pub min_duration: f64,
+    /// Ratio of dark pixels above which a frame counts as black
+    pub pixel_threshold: f64,
+    /// Volume in dB below which audio counts as silence
+    pub noise_threshold: f64,
+    /// Gap in seconds allowed between two dead sections to still merge them
+    pub tolerance: f64,
+}
+
+impl Default for DeadAirConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            scan_window: 900.0,
+            min_duration: 5.0,
+            pixel_threshold: 0.1,
+            noise_threshold: -50.0,
+            tolerance: 2.0,
+        }
+    }
+}
+
+/// Settings for the Twitch chat bot.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatConfig {
+    pub enabled: bool,
+    /// IRC server, e.g. a local one for testing
+    pub server: String,
+    pub port: u16,
+    /// Login name of the bot account
+    pub nickname: String,
+    /// `oauth:...` token of the bot account
+    pub password: Option<String>,
+    /// Channel to join, without the leading `#`
+    pub channel: String,
+    /// Users that may use moderator commands in addition to the ones Twitch
+    /// marks as moderators
+    pub moderators: Vec<String>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            server: "irc.chat.twitch.tv".into(),
+            port: 6667,
+            nickname: "gb_forever_bot".into(),
+            password: None,
+            channel: String::new(),
+            moderators: vec![],
+        }
+    }
+}
+
+/// Settings for letting viewers vote on what plays next.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct VotingConfig {
+    pub enabled: bool,
+    /// Number of videos viewers can choose from
+    pub candidates: i64,
+    /// Seconds before the end of the current video when a vote opens
+    pub open_before: f64,
+    /// Seconds before the end of the current video when a vote closes, this
+    /// leaves time to download the winner
+    pub close_before: f64,
+}
+
+impl Default for VotingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            candidates: 3,
+            open_before: 600.0,
+            close_before: 300.0,
+        }
+    }
+}
+
+/// Settings for viewer video requests.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RequestConfig {
+    pub enabled: bool,
+    /// Seconds a viewer has to wait between two requests
+    pub cooldown: u64,
+    /// Number of requests a viewer may have waiting for moderation
+    pub max_pending: i64,
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cooldown: 1800,
+            max_pending: 2,
+        }
+    }
+}
+
+/// Themed time slots, like a Quick Look hour every evening, that are filled
+/// with matching videos.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    pub enabled: bool,
+    /// Seconds before a block starts when its videos are put into the playlist,
+    /// so there is time to download them
+    pub lead_time: u64,
+    pub blocks: Vec<BlockConfig>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lead_time: 1800,
+            blocks: vec![],
+        }
+    }
+}
+
+/// A single programming block of the schedule.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BlockConfig {
+    pub name: String,
+    /// Days the block runs on, like `sun` or `sunday`. Runs every day if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Start time in UTC as `HH:MM`
+    pub start: String,
+    /// Length of the block in minutes
+    pub duration: u64,
+    #[serde(default)]
+    pub filter: CatalogFilter,
+}
+
+/// Selects catalog videos. All set fields have to match, each matches
+/// case-insensitively anywhere in the title, a subject, the creator or a
+/// collection name.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CatalogFilter {
+    pub title: Option<String>,
+    pub subject: Option<String>,
+    pub creator: Option<String>,
+    pub collection: Option<String>,
+}
+
+impl CatalogFilter {
+    /// Whether every video matches.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.subject.is_none()
+            && self.creator.is_none()
+            && self.collection.is_none()
+    }
+
+    pub fn matches(
+        &self,
+        title: &str,
+        subjects: &[String],
+        creator: Option<&str>,
+        collections: &[String],
+    ) -> bool {
+        let contains = |pattern: &Option<String>, text: &str| {
+            pattern
+                .as_ref()
+                .is_none_or(|p| text.to_lowercase().contains(&p.to_lowercase()))
+        };
+        let any_contains = |pattern: &Option<String>, texts: &[String]| {
+            pattern.is_none() || texts.iter().any(|t| contains(pattern, t))
+        };
+
+        contains(&self.title, title)
+            && any_contains(&self.subject, subjects)
+            && (self.creator.is_none() || creator.is_some_and(|c| contains(&self.creator, c)))
+            && any_contains(&self.collection, collections)
+    }
+}
+
+/// Plays videos first published on today's date in earlier years ahead of
+/// the shuffled playlist.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct OnThisDayConfig {
+    pub enabled: bool,
+}
+
+/// Settings for the program guide in XMLTV format. With the API enabled it is
+/// served at `/epg.xml`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EpgConfig {
+    pub enabled: bool,
+    /// Hours ahead the guide covers
+    pub hours: u64,
+    /// File the guide is written to as well
+    pub path: Option<Utf8PathBuf>,
+    /// Minutes between two writes of the file
+    pub interval: u64,
+    /// Name of the channel shown in the guide
+    pub channel_name: String,
+}
+
+impl Default for EpgConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hours: 24,
+            path: None,
+            interval: 15,
+            channel_name: "Giant Bomb Forever".into(),
+        }
+    }
+}
+
+/// Outgoing webhooks that are told about events on the main channel.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    /// Seconds the stream has to be down before it is reported
+    pub stream_down_after: u64,
+    /// Downloads that have to fail in a row before it is reported
+    pub download_failures: u32,
+    /// Percentage of free space on the disk of the video path below which it
+    /// counts as almost full
+    pub min_free_disk_percent: f64,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: vec![],
+            stream_down_after: 60,
+            download_failures: 3,
+            min_free_disk_percent: 10.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    NowPlaying,
+    StreamDown,
+    StreamRecovered,
+    DownloadFailed,
+    DiskAlmostFull,
+}
+
+/// Payload format a webhook expects.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// `{"content": ...}`
+    #[default]
+    Discord,
+    /// `{"text": ...}`, also understood by Mattermost and Rocket.Chat
+    Slack,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Events sent to this webhook, all of them if empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Messages replacing the default ones per event. `{name}` is replaced by
+    /// the value of the event's field, e.g. `{title}` for "now playing".
+    #[serde(default)]
+    pub templates: HashMap<EventKind, String>,
+    /// Minimum seconds between two messages, later ones wait
+    #[serde(default = "default_webhook_interval")]
+    pub min_interval: u64,
+    /// How often a message is retried when sending it failed
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+fn default_webhook_interval() -> u64 {
+    2
+}
+
+fn default_webhook_retries() -> u32 {
+    3
 }
 
 pub fn load_config() -> Result<AppConfig> {
@@ -0,0 +1,355 @@
+use std::{collections::HashMap, fmt::Write};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    api::{ApiError, ApiState, SESSION_COOKIE},
+    disk,
+    ia::format_bytes,
+    player::format_duration,
+    Result,
+};
+
+const UPCOMING_COUNT: i64 = 15;
+const RECENT_PLAYS_COUNT: i64 = 10;
+const RECENT_ERRORS_COUNT: usize = 20;
+
+pub fn router(state: ApiState) -> Router {
+    let protected = Router::new()
+        .route("/dashboard", get(index))
+        .route("/dashboard/skip", post(skip))
+        .route("/dashboard/pause", post(pause))
+        .route("/dashboard/resume", post(resume))
+        .route("/dashboard/requeue", post(requeue))
+        .route("/dashboard/requests/approve", post(approve_request))
+        .route("/dashboard/requests/reject", post(reject_request))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_login));
+
+    Router::new()
+        .merge(protected)
+        .route("/dashboard/login", get(login_form).post(login))
+        .route("/dashboard/logout", post(logout))
+        .with_state(state)
+}
+
+async fn require_login(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    if state.is_authorized(request.headers()) {
+        next.run(request).await
+    } else {
+        Redirect::to("/dashboard/login").into_response()
+    }
+}
+
+type PageResult = std::result::Result<Html<String>, ApiError>;
+
+/// Escapes text for use in HTML element content and attribute values.
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+fn page(title: &str, body: &str, refresh: bool) -> Html<String> {
+    let refresh = if refresh {
+        r#"<meta http-equiv="refresh" content="15">"#
+    } else {
+        ""
+    };
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+{refresh}
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; max-width: 70em; }}
+table {{ border-collapse: collapse; width: 100%; }}
+td, th {{ border-bottom: 1px solid #ddd; padding: 0.3em; text-align: left; }}
+progress {{ width: 100%; }}
+form {{ display: inline; }}
+.error {{ color: #a00; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>"#,
+        title = escape(title),
+    ))
+}
+
+async fn index(State(state): State<ApiState>) -> PageResult {
+    Ok(page("gb-forever", &render_index(&state).await?, true))
+}
+
+async fn render_index(state: &ApiState) -> Result<String> {
+    let mut body = String::from(
+        r#"<h1>gb-forever</h1>
+<p><form method="post" action="/dashboard/logout"><button>Log out</button></form></p>"#,
+    );
+    let player = state.player.state();
+
+    body.push_str("<h2>Now playing</h2>");
+    let current = state.database.current_video().await?;
+    match current {
+        Some(entry) => {
+            let video = state.database.fetch_videos(&[entry.video_id]).await?.pop();
+            let trim = state.database.video_trim(entry.video_id).await?;
+            let duration = trim
+                .map(|t| t.duration)
+                .or(video.as_ref().and_then(|v| v.runtime));
+            let title = video.as_ref().map(|v| v.title.as_str()).unwrap_or("?");
+            write!(body, "<p><strong>{}</strong></p>", escape(title))?;
+            match duration {
+                Some(duration) => write!(
+                    body,
+                    r#"<progress value="{:.0}" max="{:.0}"></progress><p>{} / {}</p>"#,
+                    player.position,
+                    duration,
+                    format_duration(player.position),
+                    format_duration(duration)
+                )?,
+                None => write!(body, "<p>{}</p>", format_duration(player.position))?,
+            }
+        }
+        None if player.paused => body.push_str("<p>Paused</p>"),
+        None => body.push_str("<p>Nothing is playing, waiting for downloads</p>"),
+    }
+
+    body.push_str(
+        r#"<p><form method="post" action="/dashboard/skip"><button>Skip</button></form> "#,
+    );
+    if player.paused {
+        body.push_str(
+            r#"<form method="post" action="/dashboard/resume"><button>Resume</button></form></p>"#,
+        );
+    } else {
+        body.push_str(
+            r#"<form method="post" action="/dashboard/pause"><button>Pause</button></form></p>"#,
+        );
+    }
+
+    body.push_str("<h2>Up next</h2><table><tr><th>#</th><th>Title</th><th>Status</th></tr>");
+    let upcoming = state.database.upcoming_entries(UPCOMING_COUNT).await?;
+    for item in state.database.with_videos(upcoming).await? {
+        write!(
+            body,
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            item.entry.position,
+            escape(&item.video.title),
+            item.entry.status
+        )?;
+    }
+    body.push_str("</table>");
+
+    let requests = state.database.pending_requests().await?;
+    if !requests.is_empty() {
+        body.push_str(
+            "<h2>Requests</h2><table><tr><th>Requested</th><th>By</th><th>Title</th><th></th></tr>",
+        );
+        for request in requests {
+            write!(
+                body,
+                r#"<tr><td>{}</td><td>{}</td><td>{}<br><small>{}</small></td><td><form method="post" action="/dashboard/requests/approve"><input type="hidden" name="id" value="{id}"><button>Approve</button></form> <form method="post" action="/dashboard/requests/reject"><input type="hidden" name="id" value="{id}"><input name="reason" placeholder="Reason"><button>Reject</button></form></td></tr>"#,
+                format_time(request.requested_at),
+                escape(&request.requested_by),
+                escape(&request.title),
+                escape(&request.query),
+                id = request.id
+            )?;
+        }
+        body.push_str("</table>");
+    }
+
+    body.push_str("<h2>Recently played</h2><table><tr><th>Started</th><th>Title</th><th>Outcome</th><th></th></tr>");
+    let plays = state.database.recent_plays(RECENT_PLAYS_COUNT).await?;
+    let ids: Vec<_> = plays.iter().map(|p| p.video_id).collect();
+    let videos: HashMap<_, _> = state
+        .database
+        .fetch_videos(&ids)
+        .await?
+        .into_iter()
+        .map(|v| (v.id, v))
+        .collect();
+    for play in plays {
+        let title = videos.get(&play.video_id).map(|v| v.title.as_str());
+        let outcome = match (play.outcome, play.reason) {
+            (Some(outcome), Some(reason)) => format!("{outcome:?} ({reason})"),
+            (Some(outcome), None) => format!("{outcome:?}"),
+            (None, _) => "playing".into(),
+        };
+        write!(
+            body,
+            r#"<tr><td>{}</td><td>{}</td><td>{}</td><td><form method="post" action="/dashboard/requeue"><input type="hidden" name="video_id" value="{}"><button>Requeue</button></form></td></tr>"#,
+            format_time(play.started_at),
+            escape(title.unwrap_or("?")),
+            escape(&outcome),
+            play.video_id
+        )?;
+    }
+    body.push_str("</table>");
+
+    body.push_str("<h2>Disk</h2>");
+    match disk::folder_size(&state.video_path).await {
+        Ok(size) => write!(
+            body,
+            "<p>{} used in {}</p>",
+            format_bytes(size),
+            escape(state.video_path.as_str())
+        )?,
+        Err(e) => write!(
+            body,
+            r#"<p class="error">failed to read {}: {}</p>"#,
+            escape(state.video_path.as_str()),
+            escape(&e.to_string())
+        )?,
+    }
+
+    body.push_str(
+        "<h2>Recent errors</h2><table><tr><th>Time</th><th>Level</th><th>Message</th></tr>",
+    );
+    for error in state.errors.list().into_iter().take(RECENT_ERRORS_COUNT) {
+        write!(
+            body,
+            r#"<tr class="error"><td>{}</td><td>{}</td><td>{}: {}</td></tr>"#,
+            format_time(error.timestamp),
+            error.level,
+            escape(&error.target),
+            escape(&error.message)
+        )?;
+    }
+    body.push_str("</table>");
+
+    Ok(body)
+}
+
+async fn skip(State(state): State<ApiState>) -> std::result::Result<Redirect, ApiError> {
+    state.player.skip("skipped from the dashboard").await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+async fn pause(State(state): State<ApiState>) -> std::result::Result<Redirect, ApiError> {
+    state.player.pause().await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+async fn resume(State(state): State<ApiState>) -> std::result::Result<Redirect, ApiError> {
+    state.player.resume().await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+#[derive(Deserialize)]
+struct RequeueForm {
+    video_id: i64,
+}
+
+async fn requeue(
+    State(state): State<ApiState>,
+    Form(form): Form<RequeueForm>,
+) -> std::result::Result<Redirect, ApiError> {
+    state.database.enqueue_next(form.video_id).await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+#[derive(Deserialize)]
+struct DecideForm {
+    id: i64,
+    #[serde(default)]
+    reason: String,
+}
+
+async fn approve_request(
+    State(state): State<ApiState>,
+    Form(form): Form<DecideForm>,
+) -> std::result::Result<Redirect, ApiError> {
+    state.database.approve_request(form.id, "dashboard").await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+async fn reject_request(
+    State(state): State<ApiState>,
+    Form(form): Form<DecideForm>,
+) -> std::result::Result<Redirect, ApiError> {
+    let reason = Some(form.reason.trim()).filter(|r| !r.is_empty());
+    state
+        .database
+        .reject_request(form.id, "dashboard", reason)
+        .await?;
+    Ok(Redirect::to("/dashboard"))
+}
+
+async fn login_form() -> Html<String> {
+    page(
+        "gb-forever login",
+        r#"<h1>gb-forever</h1>
+<form method="post" action="/dashboard/login">
+<input type="password" name="token" placeholder="Token" autofocus>
+<button>Log in</button>
+</form>"#,
+        false,
+    )
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    token: String,
+}
+
+async fn login(State(state): State<ApiState>, Form(form): Form<LoginForm>) -> Response {
+    if !state.token_matches(&form.token) {
+        return (StatusCode::UNAUTHORIZED, login_form().await).into_response();
+    }
+    let cookie = format!(
+        "{SESSION_COOKIE}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+        state.start_session(),
+        state.session_max_age().as_secs()
+    );
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/dashboard")).into_response()
+}
+
+async fn logout(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    state.end_session(&headers);
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict");
+    (
+        [(header::SET_COOKIE, cookie)],
+        Redirect::to("/dashboard/login"),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape(r#"<script>alert("Jeff's & Vinny's")</script>"#),
+            "&lt;script&gt;alert(&quot;Jeff&#39;s &amp; Vinny&#39;s&quot;)&lt;/script&gt;"
+        );
+        assert_eq!(escape("Quick Look: Halo 3"), "Quick Look: Halo 3");
+    }
+}
@@ -1,9 +1,33 @@
-use std::{fmt, str::FromStr, time::Instant};
+use std::{collections::HashMap, fmt, str::FromStr, time::Instant};
 
+use clap::ValueEnum;
 use color_eyre::eyre::{bail, Context};
+use serde::Serialize;
+use time::OffsetDateTime;
 use tracing::info;
 
-use crate::{ia::MetadataItem, Result};
+mod catalog;
+mod history;
+mod premieres;
+mod queue;
+mod requests;
+mod schedule;
+mod voting;
+
+pub use catalog::{CatalogVideo, SeriesOverride, TimedVideo};
+pub use history::{
+    CreatorPlays, DailyPlays, PlayHistoryEntry, PlayOutcome, ShowPlays, VideoPlayCount,
+};
+pub use premieres::{InvalidPremiere, Premiere, PremiereStatus};
+pub use queue::{QueueItem, StatusCount};
+pub use requests::{InvalidRequest, RequestStatus, VideoRequest};
+pub use voting::{InvalidVote, VideoVoteStats, VoteRound, VoteTally};
+
+use crate::{
+    analysis::TrimRange,
+    ia::{self, MetadataItem},
+    Result,
+};
 
 #[derive(Debug)]
 pub enum VideoId {
@@ -11,7 +35,7 @@ pub enum VideoId {
     DatabaseId(i64),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct GbVideo {
     pub id: i64,
     pub date: Option<String>,
@@ -22,18 +46,68 @@ pub struct GbVideo {
     pub external_identifier: Option<String>,
     pub collections: Option<Vec<String>>,
     pub creator: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub removed_at: Option<OffsetDateTime>,
+    pub subjects: Option<Vec<String>>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub published_at: Option<OffsetDateTime>,
+    pub runtime: Option<f64>,
+    pub thumbnail_url: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub details_fetched_at: Option<OffsetDateTime>,
 }
 
 #[derive(Debug)]
+pub struct GbVideoFile {
+    pub id: i64,
+    pub video_id: i64,
+    pub name: String,
+    pub format: String,
+    pub size: Option<i64>,
+    pub length: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub md5: String,
+}
+
+#[derive(Debug, Serialize)]
 pub struct PlaylistEntry {
     pub id: i64,
     pub video_id: i64,
-    pub status: String,
+    pub status: PlaylistEntryStatus,
     pub file_path: Option<String>,
     pub last_progress: Option<i32>,
+    pub position: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub status_changed_at: OffsetDateTime,
+}
+
+/// Outcome of upserting a batch of catalog items.
+#[derive(Debug, Default)]
+pub struct UpsertResult {
+    /// Database ids of videos that were not in the catalog before
+    pub inserted: Vec<i64>,
+    /// Number of existing videos whose metadata changed
+    pub updated: usize,
+    /// Database ids of videos that were marked as removed and are back in the
+    /// archive, they are counted as updated as well
+    pub restored: Vec<i64>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
+pub struct VideoTrim {
+    pub video_id: i64,
+    pub duration: f64,
+    pub inpoint: Option<f64>,
+    pub outpoint: Option<f64>,
+    pub analyzed_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, ValueEnum)]
+#[sqlx(type_name = "playlist_entry_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum PlaylistEntryStatus {
     /// The video has not been downloaded or played
     Unplayed,
@@ -47,6 +121,50 @@ pub enum PlaylistEntryStatus {
     Finished,
 }
 
+impl PlaylistEntryStatus {
+    /// Whether an entry in this status may move to `next`.
+    pub fn can_transition_to(self, next: Self) -> bool {
+        use PlaylistEntryStatus::*;
+
+        matches!(
+            (self, next),
+            (Unplayed, Pending)
+                | (Pending, Downloaded)
+                // the download failed
+                | (Pending, Unplayed)
+                | (Downloaded, Active)
+                // the downloaded file was evicted before it was played
+                | (Downloaded, Unplayed)
+                | (Active, Finished)
+                // playback was interrupted and resumes later
+                | (Active, Downloaded)
+                // the entry is queued again
+                | (Finished, Unplayed)
+        )
+    }
+}
+
+/// Returned when a playlist entry is asked to make a move its state machine
+/// does not allow.
+#[derive(Debug)]
+pub struct InvalidTransition {
+    pub entry_id: i64,
+    pub from: PlaylistEntryStatus,
+    pub to: PlaylistEntryStatus,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "playlist entry {} can not move from {} to {}",
+            self.entry_id, self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
 impl FromStr for PlaylistEntryStatus {
     type Err = color_eyre::Report;
 
@@ -74,21 +192,62 @@ impl fmt::Display for PlaylistEntryStatus {
     }
 }
 
+/// Id of the channel that exists from the start.
+pub const MAIN_CHANNEL: i64 = 1;
+
+/// Connection to the database. Playlist queries are limited to one channel,
+/// the main one unless the handle comes from [`Database::channel`].
 #[derive(Clone)]
 pub struct Database {
     pool: sqlx::PgPool,
+    channel: i64,
 }
 
 impl Database {
     pub async fn connect(url: &str) -> Result<Self> {
         let pool = sqlx::PgPool::connect(url).await?;
         sqlx::migrate!("./migrations").run(&pool).await?;
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            channel: MAIN_CHANNEL,
+        })
+    }
+
+    /// A handle whose playlist queries go to the channel called `name`, which
+    /// is created if it does not exist yet.
+    pub async fn channel(&self, name: &str) -> Result<Self> {
+        let channel = sqlx::query_scalar!(
+            r#"INSERT INTO channel ("name") VALUES ($1)
+            ON CONFLICT ("name") DO UPDATE SET "name" = EXCLUDED."name"
+            RETURNING id"#,
+            name
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err_with(|| format!("failed to create channel {name}"))?;
+
+        Ok(Self {
+            pool: self.pool.clone(),
+            channel,
+        })
+    }
+
+    /// Fails if the database can not be reached.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query!("SELECT 1 AS one")
+            .fetch_one(&self.pool)
+            .await
+            .wrap_err("database is not reachable")?;
+
+        Ok(())
     }
 
-    pub async fn insert_items(&self, items: &[MetadataItem]) -> Result<()> {
+    /// Inserts new items and updates existing ones whose metadata changed. Items
+    /// that were previously marked as removed are restored.
+    pub async fn upsert_items(&self, items: &[MetadataItem]) -> Result<UpsertResult> {
+        let mut result = UpsertResult::default();
         if items.is_empty() {
-            return Ok(());
+            return Ok(result);
         }
         let start = Instant::now();
         let mut tx = self
@@ -97,7 +256,7 @@ impl Database {
             .await
             .wrap_err("failed to start transaction")?;
         for item in items {
-            sqlx::query!(
+            let row = sqlx::query!(
                 r#"
                 INSERT INTO gb_videos(
                     "date",
@@ -107,10 +266,55 @@ impl Database {
                     identifier,
                     external_identifier,
                     collections,
-                    creator
+                    creator,
+                    subjects,
+                    published_at,
+                    runtime,
+                    thumbnail_url
                 )
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-                ON CONFLICT (identifier) DO NOTHING
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                ON CONFLICT (identifier) DO UPDATE SET
+                    "date" = EXCLUDED."date",
+                    "description" = EXCLUDED."description",
+                    title = EXCLUDED.title,
+                    item_size = EXCLUDED.item_size,
+                    external_identifier = EXCLUDED.external_identifier,
+                    collections = EXCLUDED.collections,
+                    creator = EXCLUDED.creator,
+                    subjects = EXCLUDED.subjects,
+                    published_at = EXCLUDED.published_at,
+                    runtime = COALESCE(EXCLUDED.runtime, gb_videos.runtime),
+                    thumbnail_url = EXCLUDED.thumbnail_url,
+                    updated_at = now(),
+                    removed_at = NULL
+                WHERE gb_videos.removed_at IS NOT NULL
+                    OR (
+                        gb_videos."date",
+                        gb_videos."description",
+                        gb_videos.title,
+                        gb_videos.item_size,
+                        gb_videos.external_identifier,
+                        gb_videos.collections,
+                        gb_videos.creator,
+                        gb_videos.subjects,
+                        gb_videos.published_at
+                    ) IS DISTINCT FROM (
+                        EXCLUDED."date",
+                        EXCLUDED."description",
+                        EXCLUDED.title,
+                        EXCLUDED.item_size,
+                        EXCLUDED.external_identifier,
+                        EXCLUDED.collections,
+                        EXCLUDED.creator,
+                        EXCLUDED.subjects,
+                        EXCLUDED.published_at
+                    )
+                RETURNING id, (xmax = 0) AS "inserted!",
+                    -- the subquery still sees the row as it was before the update
+                    EXISTS(
+                        SELECT 1 FROM gb_videos old
+                        WHERE old.id = gb_videos.id AND old.removed_at IS NOT NULL
+                    ) AS "restored!"
                 "#,
                 item.date,
                 item.description,
@@ -119,98 +323,476 @@ impl Database {
                 item.identifier,
                 item.external_identifier,
                 item.collections.as_ref().map(|c| c.as_slice()),
-                item.creator.as_deref()
+                item.creator.as_deref(),
+                item.subject.as_ref().map(|s| s.as_slice()),
+                item.published_at(),
+                item.runtime_seconds(),
+                item.thumbnail_url()
             )
-            .execute(&mut *tx)
+            .fetch_optional(&mut *tx)
             .await
-            .wrap_err("failed to insert item")?;
+            .wrap_err("failed to upsert item")?;
+
+            match row {
+                Some(row) if row.inserted => result.inserted.push(row.id),
+                Some(row) => {
+                    result.updated += 1;
+                    if row.restored {
+                        result.restored.push(row.id);
+                    }
+                }
+                None => {}
+            }
         }
         tx.commit().await.wrap_err("failed to commit transaction")?;
 
         let elapsed = start.elapsed();
-        info!("inserted {} items in {:?}", items.len(), elapsed);
-        Ok(())
+        info!(
+            "upserted {} items in {:?} ({} new, {} changed)",
+            items.len(),
+            elapsed,
+            result.inserted.len(),
+            result.updated
+        );
+        Ok(result)
     }
 
-    pub async fn random_video(&self) -> Result<GbVideo> {
-        sqlx::query_as!(GbVideo, "SELECT * FROM gb_videos ORDER BY random() LIMIT 1")
-            .fetch_one(&self.pool)
-            .await
-            .wrap_err("failed to fetch random video from database")
+    /// Marks every video whose identifier is not in `seen` as removed from the
+    /// archive and drops it from the upcoming playlist. Returns the number of
+    /// newly removed videos.
+    pub async fn mark_missing_removed(&self, seen: &[String]) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let removed = sqlx::query!(
+            "UPDATE gb_videos SET removed_at = now()
+            WHERE removed_at IS NULL AND NOT (identifier = ANY($1))",
+            seen
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to mark videos as removed")?
+        .rows_affected();
+
+        sqlx::query!(
+            "DELETE FROM playlist_entry
+            WHERE status = 'unplayed'
+                AND video_id IN (SELECT id FROM gb_videos WHERE removed_at IS NOT NULL)"
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to remove playlist entries of removed videos")?;
+
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    /// Returns the ids and identifiers of videos whose file listing was never
+    /// fetched or is older than their last metadata change.
+    pub async fn videos_missing_details(&self) -> Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            "SELECT id, identifier FROM gb_videos
+            WHERE removed_at IS NULL
+                AND (details_fetched_at IS NULL OR details_fetched_at < updated_at)"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch videos without details")?;
+
+        Ok(rows.into_iter().map(|r| (r.id, r.identifier)).collect())
     }
 
-    pub async fn create_random_playlist(&self) -> Result<()> {
+    /// Replaces the stored file listing of a video. If the catalog does not know
+    /// the runtime yet, it is taken from the length of the video file.
+    pub async fn set_video_files(&self, video_id: i64, files: &[ia::File]) -> Result<()> {
         let mut tx = self.pool.begin().await?;
-        sqlx::query!("DELETE FROM playlist_entry")
+        sqlx::query!("DELETE FROM gb_video_files WHERE video_id = $1", video_id)
             .execute(&mut *tx)
             .await?;
-
-        sqlx::query!("DELETE FROM active_playlist_entry")
+        for file in files {
+            sqlx::query!(
+                r#"
+                INSERT INTO gb_video_files (video_id, "name", format, "size", "length", width, height, md5)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                "#,
+                video_id,
+                file.name,
+                file.format,
+                file.size_bytes(),
+                file.length_seconds(),
+                file.width.as_deref().and_then(|w| w.parse::<i32>().ok()),
+                file.height.as_deref().and_then(|h| h.parse::<i32>().ok()),
+                file.md5
+            )
             .execute(&mut *tx)
-            .await?;
+            .await
+            .wrap_err("failed to insert video file")?;
+        }
 
+        let runtime = files
+            .iter()
+            .find(|f| f.is_video())
+            .and_then(|f| f.length_seconds());
         sqlx::query!(
-            "INSERT INTO playlist_entry (video_id, status) 
-            SELECT id, 'unplayed' FROM gb_videos ORDER BY random()"
+            "UPDATE gb_videos SET details_fetched_at = now(), runtime = COALESCE(runtime, $2)
+            WHERE id = $1",
+            video_id,
+            runtime
         )
         .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
-        sqlx::query!("INSERT INTO active_playlist_entry (id, entry_index) VALUES (1, 1)")
-            .execute(&mut *tx)
+        Ok(())
+    }
+
+    pub async fn video_files(&self, video_id: i64) -> Result<Vec<GbVideoFile>> {
+        sqlx::query_as!(
+            GbVideoFile,
+            "SELECT * FROM gb_video_files WHERE video_id = $1 ORDER BY id",
+            video_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch video files from database")
+    }
+
+    pub async fn random_video(&self) -> Result<GbVideo> {
+        sqlx::query_as!(
+            GbVideo,
+            "SELECT * FROM gb_videos
+            WHERE removed_at IS NULL AND id NOT IN (SELECT video_id FROM blocklist)
+            ORDER BY random() LIMIT 1"
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to fetch random video from database")
+    }
+
+    /// Appends the videos to the end of the playlist in the given order.
+    /// Videos that already played are queued again, keeping their history, and
+    /// videos that are still upcoming stay where they are. Returns the number of
+    /// queued videos.
+    pub async fn append_to_playlist(&self, video_ids: &[i64]) -> Result<usize> {
+        let mut tx = self.pool.begin().await?;
+        let end = sqlx::query_scalar!(
+            r#"SELECT COALESCE(MAX(position), 0) AS "end!" FROM playlist_entry
+            WHERE channel_id = $1"#,
+            self.channel
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let finished = sqlx::query_scalar!(
+            "SELECT id FROM playlist_entry
+            WHERE channel_id = $1 AND status = 'finished' AND video_id = ANY($2) FOR UPDATE",
+            self.channel,
+            video_ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for id in &finished {
+            Self::transition_in(&mut tx, *id, PlaylistEntryStatus::Unplayed).await?;
+        }
+
+        let queued = sqlx::query!(
+            "WITH ordered AS (
+                SELECT video_id, $2 + n AS position
+                FROM UNNEST($1::BIGINT[]) WITH ORDINALITY AS o(video_id, n)
+            ),
+            requeued AS (
+                UPDATE playlist_entry e
+                SET position = ordered.position, file_path = NULL, last_progress = NULL
+                FROM ordered
+                WHERE e.video_id = ordered.video_id AND e.id = ANY($3)
+            )
+            INSERT INTO playlist_entry (channel_id, video_id, status, position)
+            SELECT $4, video_id, 'unplayed', position FROM ordered
+            ON CONFLICT (channel_id, video_id) DO NOTHING",
+            video_ids,
+            end,
+            &finished,
+            self.channel
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to append to playlist")?
+        .rows_affected();
+        tx.commit().await?;
+
+        Ok(finished.len() + queued as usize)
+    }
+
+    pub async fn playlist_is_empty(&self) -> Result<bool> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM playlist_entry WHERE channel_id = $1) AS "exists!""#,
+            self.channel
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(!exists)
+    }
+
+    /// Inserts the videos at random positions among the entries that have not been
+    /// played yet, shifting the following entries back by one. Blocklisted videos
+    /// are left out.
+    pub async fn insert_into_playlist_randomly(&self, video_ids: &[i64]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for video_id in video_ids {
+            let position = sqlx::query_scalar!(
+                "SELECT position FROM playlist_entry
+                WHERE channel_id = $1 AND status = 'unplayed' ORDER BY random() LIMIT 1",
+                self.channel
+            )
+            .fetch_optional(&mut *tx)
             .await?;
+            let position =
+                match position {
+                    Some(position) => position,
+                    None => sqlx::query_scalar!(
+                        r#"SELECT COALESCE(MAX(position), 0) + 1 AS "position!" FROM playlist_entry
+                    WHERE channel_id = $1"#,
+                        self.channel
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?,
+                };
 
+            sqlx::query!(
+                "UPDATE playlist_entry SET position = position + 1
+                WHERE channel_id = $1 AND position >= $2",
+                self.channel,
+                position
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO playlist_entry (channel_id, video_id, status, position)
+                SELECT $1, $2, 'unplayed', $3
+                WHERE NOT EXISTS(SELECT 1 FROM blocklist WHERE video_id = $2)
+                ON CONFLICT (channel_id, video_id) DO NOTHING",
+                self.channel,
+                video_id,
+                position
+            )
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to insert playlist entry")?;
+        }
         tx.commit().await?;
 
         Ok(())
     }
 
-    pub async fn set_video_pending(&self, video_id: i64) -> Result<()> {
+    /// Moves a playlist entry to a new status, rejecting moves that the status
+    /// state machine does not allow. Every transition is recorded with a timestamp.
+    pub async fn transition_entry(&self, entry_id: i64, to: PlaylistEntryStatus) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::transition_in(&mut tx, entry_id, to).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn transition_in(
+        tx: &mut sqlx::PgConnection,
+        entry_id: i64,
+        to: PlaylistEntryStatus,
+    ) -> Result<()> {
+        let from = sqlx::query_scalar!(
+            r#"SELECT status AS "status: PlaylistEntryStatus"
+            FROM playlist_entry WHERE id = $1 FOR UPDATE"#,
+            entry_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .wrap_err_with(|| format!("failed to fetch playlist entry {entry_id}"))?;
+
+        if !from.can_transition_to(to) {
+            return Err(InvalidTransition { entry_id, from, to }.into());
+        }
+
         sqlx::query!(
-            "UPDATE playlist_entry SET status = 'pending' WHERE video_id = $1",
-            video_id
+            "UPDATE playlist_entry SET status = $1, status_changed_at = now() WHERE id = $2",
+            to as PlaylistEntryStatus,
+            entry_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO playlist_entry_transition (entry_id, from_status, to_status)
+            VALUES ($1, $2, $3)",
+            entry_id,
+            from as PlaylistEntryStatus,
+            to as PlaylistEntryStatus
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Moves the entries of a video in every channel that are in status `from`
+    /// to `to`. Returns their ids.
+    async fn transition_video_in(
+        tx: &mut sqlx::PgConnection,
+        video_id: i64,
+        from: PlaylistEntryStatus,
+        to: PlaylistEntryStatus,
+    ) -> Result<Vec<i64>> {
+        let ids = sqlx::query_scalar!(
+            "SELECT id FROM playlist_entry WHERE video_id = $1 AND status = $2 FOR UPDATE",
+            video_id,
+            from as PlaylistEntryStatus
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for id in &ids {
+            Self::transition_in(&mut *tx, *id, to).await?;
+        }
+
+        Ok(ids)
+    }
+
+    /// Marks a video as being downloaded for every channel waiting for it.
+    /// Returns false if no channel is, e.g. because another download of it is
+    /// already running.
+    pub async fn set_video_pending(&self, video_id: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let ids = Self::transition_video_in(
+            &mut tx,
+            video_id,
+            PlaylistEntryStatus::Unplayed,
+            PlaylistEntryStatus::Pending,
         )
-        .execute(&self.pool)
         .await
         .wrap_err("failed to set video to pending")?;
+        tx.commit().await?;
+
+        Ok(!ids.is_empty())
+    }
+
+    pub async fn set_video_download_failed(&self, video_id: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::transition_video_in(
+            &mut tx,
+            video_id,
+            PlaylistEntryStatus::Pending,
+            PlaylistEntryStatus::Unplayed,
+        )
+        .await
+        .wrap_err("failed to reset video after failed download")?;
+        tx.commit().await?;
 
         Ok(())
     }
 
+    /// Puts back the entries of every channel whose download was cut off by a
+    /// crash, so they are downloaded again. Returns how many there were.
+    pub async fn reset_pending_downloads(&self) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let pending = sqlx::query_scalar!(
+            "SELECT id FROM playlist_entry WHERE status = 'pending' ORDER BY id FOR UPDATE"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .wrap_err("failed to fetch pending downloads")?;
+        for id in &pending {
+            Self::transition_in(&mut tx, *id, PlaylistEntryStatus::Unplayed).await?;
+        }
+        tx.commit().await?;
+
+        Ok(pending.len() as u64)
+    }
+
+    /// Marks a video as downloaded for every channel that waited for it, as all
+    /// channels share the downloaded files.
     pub async fn set_video_downloaded(
         &self,
         video_id: i64,
         file_path: impl Into<String>,
     ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let ids = Self::transition_video_in(
+            &mut tx,
+            video_id,
+            PlaylistEntryStatus::Pending,
+            PlaylistEntryStatus::Downloaded,
+        )
+        .await
+        .wrap_err("failed to set video to downloaded")?;
         sqlx::query!(
-            "UPDATE playlist_entry SET status = 'downloaded', file_path = $1 WHERE video_id = $2",
+            "UPDATE playlist_entry SET file_path = $1 WHERE id = ANY($2)",
             file_path.into(),
+            &ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// A file of the video that was downloaded before, for any channel.
+    pub async fn downloaded_file(&self, video_id: i64) -> Result<Option<String>> {
+        let path = sqlx::query_scalar!(
+            "SELECT file_path FROM playlist_entry
+            WHERE video_id = $1 AND file_path IS NOT NULL
+            ORDER BY status_changed_at DESC LIMIT 1",
             video_id
         )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(path.flatten())
+    }
+
+    /// Stores how far into the video playback got, in seconds, so playback can
+    /// resume there after an interruption.
+    pub async fn set_entry_progress(&self, entry_id: i64, seconds: i32) -> Result<()> {
+        sqlx::query!(
+            "UPDATE playlist_entry SET last_progress = $1 WHERE id = $2",
+            seconds,
+            entry_id
+        )
         .execute(&self.pool)
         .await
-        .wrap_err("failed to set video to downloaded")?;
+        .wrap_err("failed to update playback progress")?;
 
         Ok(())
     }
 
-    // TOOD add method to update current position in the video
+    /// Stops the active entry without finishing it. It stays first in line and
+    /// resumes from its last progress when playback continues.
+    pub async fn interrupt_current_video(&self, reason: &str) -> Result<()> {
+        let Some(current) = self.current_video().await? else {
+            return Ok(());
+        };
+        let mut tx = self.pool.begin().await?;
+        Self::transition_in(&mut tx, current.id, PlaylistEntryStatus::Downloaded).await?;
+        Self::finish_play_in(&mut tx, current.id, PlayOutcome::Interrupted, Some(reason)).await?;
+        tx.commit().await?;
 
-    // TODO add method to get the current video
+        Ok(())
+    }
 
     pub async fn current_video(&self) -> Result<Option<PlaylistEntry>> {
-        let index = sqlx::query_scalar!("SELECT entry_index FROM active_playlist_entry")
-            .fetch_one(&self.pool)
-            .await?;
+        let index = sqlx::query_scalar!(
+            "SELECT entry_index FROM active_playlist_entry WHERE channel_id = $1",
+            self.channel
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(index) = index else {
+            return Ok(None);
+        };
         let entry = sqlx::query_as!(
             PlaylistEntry,
-            "SELECT * FROM playlist_entry WHERE id = $1",
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry WHERE id = $1"#,
             index
         )
         .fetch_one(&self.pool)
         .await?;
-        let status: PlaylistEntryStatus = entry.status.parse()?;
-        if let PlaylistEntryStatus::Active = status {
+        if let PlaylistEntryStatus::Active = entry.status {
             Ok(Some(entry))
         } else {
             Ok(None)
@@ -220,7 +802,11 @@ impl Database {
     pub async fn peek_next_videos(&self, count: usize) -> Result<Vec<PlaylistEntry>> {
         sqlx::query_as!(
             PlaylistEntry,
-            "SELECT * FROM playlist_entry WHERE status = 'unplayed' ORDER BY id LIMIT $1",
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry WHERE channel_id = $1 AND status = 'unplayed'
+            ORDER BY position LIMIT $2"#,
+            self.channel,
             count as i64
         )
         .fetch_all(&self.pool)
@@ -228,14 +814,92 @@ impl Database {
         .wrap_err("failed to fetch next videos from database")
     }
 
+    /// Finishes the active entry and activates the next one in playlist order.
+    /// Returns `None` if the next entry has not been downloaded yet, in which case
+    /// it is activated by a later call.
     pub async fn move_to_next_video(&self) -> Result<Option<PlaylistEntry>> {
-        let entry = self.current_video().await?;
-        if let Some(entry) = entry {
-            let next_index = entry.id + 1;
-            todo!()
-        } else {
-            Ok(None)
+        self.advance(PlayOutcome::Completed, None).await
+    }
+
+    /// Like [`Database::move_to_next_video`], but records the current video as
+    /// skipped.
+    pub async fn skip_to_next_video(&self, reason: &str) -> Result<Option<PlaylistEntry>> {
+        self.advance(PlayOutcome::Skipped, Some(reason)).await
+    }
+
+    /// Finishes the active entry without activating the next one, e.g. to wait
+    /// for a premiere. Records it as skipped if there is a `skip_reason`.
+    pub async fn finish_current_video(&self, skip_reason: Option<&str>) -> Result<()> {
+        let outcome = match skip_reason {
+            Some(_) => PlayOutcome::Skipped,
+            None => PlayOutcome::Completed,
+        };
+        let mut tx = self.pool.begin().await?;
+        self.finish_current_in(&mut tx, outcome, skip_reason)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn finish_current_in(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        outcome: PlayOutcome,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        if let Some(current) = self.current_video().await? {
+            Self::transition_in(&mut *tx, current.id, PlaylistEntryStatus::Finished).await?;
+            Self::finish_play_in(&mut *tx, current.id, outcome, reason).await?;
         }
+
+        Ok(())
+    }
+
+    async fn advance(
+        &self,
+        outcome: PlayOutcome,
+        reason: Option<&str>,
+    ) -> Result<Option<PlaylistEntry>> {
+        let mut tx = self.pool.begin().await?;
+        self.finish_current_in(&mut tx, outcome, reason).await?;
+
+        // entries of premieres that have not started yet are held back
+        let next = sqlx::query_as!(
+            PlaylistEntry,
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry e
+            WHERE channel_id = $1 AND status <> 'finished' AND NOT EXISTS(
+                SELECT 1 FROM premiere p
+                WHERE p.entry_id = e.id AND p.status = 'queued' AND p.starts_at > now()
+            )
+            ORDER BY position LIMIT 1"#,
+            self.channel
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let next = match next {
+            Some(mut next) if next.status == PlaylistEntryStatus::Downloaded => {
+                Self::transition_in(&mut tx, next.id, PlaylistEntryStatus::Active).await?;
+                sqlx::query!(
+                    "INSERT INTO active_playlist_entry (channel_id, entry_index) VALUES ($1, $2)
+                    ON CONFLICT (channel_id) DO UPDATE SET entry_index = EXCLUDED.entry_index",
+                    self.channel,
+                    next.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                Self::start_play_in(&mut tx, next.video_id, next.id).await?;
+                next.status = PlaylistEntryStatus::Active;
+                Some(next)
+            }
+            _ => None,
+        };
+        tx.commit().await?;
+
+        Ok(next)
     }
 
     pub async fn fetch_video(&self, id: &VideoId) -> Result<GbVideo> {
@@ -264,8 +928,163 @@ impl Database {
             .wrap_err("failed to fetch video id from database")
     }
 
-    // TODO add method to move to the next video (unless it's not downloaded yet)
+    pub async fn set_video_trim(&self, video_id: i64, range: &TrimRange) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO video_trim (video_id, duration, inpoint, outpoint)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (video_id) DO UPDATE SET
+                duration = EXCLUDED.duration,
+                inpoint = EXCLUDED.inpoint,
+                outpoint = EXCLUDED.outpoint,
+                analyzed_at = now()",
+            video_id,
+            range.duration,
+            range.inpoint,
+            range.outpoint
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to store video trim")?;
+
+        Ok(())
+    }
+
+    pub async fn video_trim(&self, video_id: i64) -> Result<Option<VideoTrim>> {
+        sqlx::query_as!(
+            VideoTrim,
+            "SELECT * FROM video_trim WHERE video_id = $1",
+            video_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .wrap_err("failed to fetch video trim from database")
+    }
+
+    /// Where playback of a video stops, in seconds into the file. Uses the
+    /// trim if the video was analyzed and the archive runtime otherwise.
+    pub async fn playback_end(&self, video_id: i64) -> Result<Option<f64>> {
+        if let Some(trim) = self.video_trim(video_id).await? {
+            return Ok(Some(trim.outpoint.unwrap_or(trim.duration)));
+        }
+        let runtime = sqlx::query_scalar!("SELECT runtime FROM gb_videos WHERE id = $1", video_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .flatten();
+
+        Ok(runtime)
+    }
+
+    /// [`Database::playback_end`] of several videos at once. Videos whose end
+    /// is unknown are left out.
+    pub async fn playback_ends(&self, video_ids: &[i64]) -> Result<HashMap<i64, f64>> {
+        let rows = sqlx::query!(
+            r#"SELECT g.id, COALESCE(t.outpoint, t.duration, g.runtime) AS "end!"
+            FROM gb_videos g LEFT JOIN video_trim t ON t.video_id = g.id
+            WHERE g.id = ANY($1) AND COALESCE(t.duration, g.runtime) IS NOT NULL"#,
+            video_ids
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch playback ends")?;
+
+        Ok(rows.into_iter().map(|row| (row.id, row.end)).collect())
+    }
+}
+
+#[cfg(test)]
+impl GbVideo {
+    /// A video with only an id, a title and an identifier.
+    pub(crate) fn for_tests(id: i64, title: &str) -> Self {
+        Self {
+            id,
+            date: None,
+            description: None,
+            title: title.into(),
+            item_size: None,
+            identifier: "item".into(),
+            external_identifier: None,
+            collections: None,
+            creator: None,
+            updated_at: OffsetDateTime::UNIX_EPOCH,
+            removed_at: None,
+            subjects: None,
+            published_at: None,
+            runtime: None,
+            thumbnail_url: None,
+            details_fetched_at: None,
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+impl Database {
+    /// The main channel of the database `#[sqlx::test]` set up.
+    pub(crate) fn for_tests(pool: sqlx::PgPool) -> Self {
+        Self {
+            pool,
+            channel: MAIN_CHANNEL,
+        }
+    }
+
+    /// Adds `Video 1` to `Video {count}` with identifiers `v-1`... and 25
+    /// minutes of runtime to the catalog and returns their ids.
+    pub(crate) async fn insert_test_videos(&self, count: i32) -> Result<Vec<i64>> {
+        let ids = sqlx::query_scalar!(
+            "INSERT INTO gb_videos (identifier, title, runtime)
+            SELECT 'v-' || n, 'Video ' || n, 1500 FROM generate_series(1, $1) n
+            ORDER BY n RETURNING id",
+            count
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlaylistEntryStatus::{self, *};
+
+    #[test]
+    fn status_happy_path() {
+        let path = [Unplayed, Pending, Downloaded, Active, Finished, Unplayed];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{pair:?}");
+        }
+    }
+
+    #[test]
+    fn status_rejects_illegal_moves() {
+        let illegal: [(PlaylistEntryStatus, PlaylistEntryStatus); 5] = [
+            (Finished, Pending),
+            (Unplayed, Active),
+            (Unplayed, Downloaded),
+            (Pending, Active),
+            (Active, Active),
+        ];
+        for (from, to) in illegal {
+            assert!(!from.can_transition_to(to), "{from} -> {to}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn pending_downloads_are_reset(pool: sqlx::PgPool) -> crate::Result<()> {
+        let db = super::Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        db.append_to_playlist(&ids).await?;
+        db.set_video_pending(ids[0]).await?;
+
+        assert_eq!(db.reset_pending_downloads().await?, 1);
+        let logged = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM playlist_entry_transition
+            WHERE from_status = 'pending' AND to_status = 'unplayed'"
+        )
+        .fetch_one(&db.pool)
+        .await?;
+        assert_eq!(logged, Some(1));
+        // it can be downloaded again
+        assert!(db.set_video_pending(ids[0]).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+use color_eyre::eyre::Context;
+use time::{Date, OffsetDateTime};
+
+use super::{Database, GbVideo};
+use crate::{config::CatalogFilter, Result};
+
+/// A video that matched a filter, with how long it plays.
+#[derive(Debug, Clone)]
+pub struct TimedVideo {
+    pub id: i64,
+    pub title: String,
+    /// Seconds, taking the trim into account
+    pub duration: f64,
+}
+
+/// What playlist strategies know about a video.
+#[derive(Debug, Clone)]
+pub struct CatalogVideo {
+    pub id: i64,
+    pub title: String,
+    pub published_at: Option<OffsetDateTime>,
+    pub creator: Option<String>,
+    pub collections: Vec<String>,
+    pub subjects: Vec<String>,
+    /// How often the video played so far, not counting interrupted plays
+    pub plays: i64,
+    pub series_override: Option<SeriesOverride>,
+}
+
+/// A series set by hand, replacing the one detected from the metadata.
+#[derive(Debug, Clone)]
+pub struct SeriesOverride {
+    /// Not part of any series if `None`
+    pub series: Option<String>,
+    pub part: Option<i32>,
+}
+
+/// An `ILIKE` pattern matching `text` anywhere.
+fn contains_pattern(text: &str) -> String {
+    format!("%{}%", text.replace('%', r"\%").replace('_', r"\_"))
+}
+
+impl Database {
+    /// Case-insensitive search over title, identifier and description.
+    pub async fn search_videos(&self, query: &str, limit: i64) -> Result<Vec<GbVideo>> {
+        let pattern = contains_pattern(query);
+        sqlx::query_as!(
+            GbVideo,
+            r#"SELECT * FROM gb_videos
+            WHERE removed_at IS NULL
+                AND (title ILIKE $1 OR identifier ILIKE $1 OR "description" ILIKE $1)
+            ORDER BY published_at NULLS LAST, title
+            LIMIT $2"#,
+            pattern,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to search videos")
+    }
+
+    /// Keeps a video from ever being played again and removes it from the
+    /// upcoming playlist.
+    pub async fn blocklist_video(&self, video_id: i64, reason: Option<&str>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO blocklist (video_id, reason) VALUES ($1, $2)
+            ON CONFLICT (video_id) DO UPDATE SET reason = EXCLUDED.reason",
+            video_id,
+            reason
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to blocklist video")?;
+        sqlx::query!(
+            "DELETE FROM playlist_entry WHERE video_id = $1 AND status <> 'active'",
+            video_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Replaces the videos on the blocklist because of content rules with
+    /// `videos`, as pairs of video id and rule name. Videos that were
+    /// blocklisted by hand stay. Returns the number of blocklisted videos.
+    pub async fn set_rule_blocklist(&self, videos: &[(i64, String)]) -> Result<u64> {
+        let (ids, rules): (Vec<_>, Vec<_>) = videos.iter().cloned().unzip();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"DELETE FROM blocklist WHERE "rule" IS NOT NULL AND video_id <> ALL($1)"#,
+            &ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        let blocklisted = sqlx::query!(
+            r#"INSERT INTO blocklist (video_id, reason, "rule")
+            SELECT video_id, 'content rule ' || "rule", "rule"
+            FROM UNNEST($1::BIGINT[], $2::VARCHAR[]) AS r(video_id, "rule")
+            ON CONFLICT (video_id) DO UPDATE SET reason = EXCLUDED.reason, "rule" = EXCLUDED."rule"
+            WHERE blocklist."rule" IS NOT NULL"#,
+            &ids,
+            &rules
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to blocklist videos by rule")?
+        .rows_affected();
+        sqlx::query!(
+            "DELETE FROM playlist_entry WHERE video_id = ANY($1) AND status <> 'active'",
+            &ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(blocklisted)
+    }
+
+    pub async fn unblocklist_video(&self, video_id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM blocklist WHERE video_id = $1", video_id)
+            .execute(&self.pool)
+            .await
+            .wrap_err("failed to remove video from blocklist")?;
+
+        Ok(())
+    }
+
+    pub async fn is_blocklisted(&self, video_id: i64) -> Result<bool> {
+        let blocked = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM blocklist WHERE video_id = $1) AS "exists!""#,
+            video_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(blocked)
+    }
+
+    /// Random videos matching `filter` whose duration is known. Videos that are
+    /// playing or about to play are left out.
+    pub async fn filtered_videos(
+        &self,
+        filter: &CatalogFilter,
+        limit: i64,
+    ) -> Result<Vec<TimedVideo>> {
+        let rows = sqlx::query!(
+            r#"SELECT g.id, g.title, g.creator,
+                COALESCE(g.subjects, '{}') AS "subjects!",
+                COALESCE(g.collections, '{}') AS "collections!",
+                COALESCE(t.outpoint, t.duration, g.runtime) - COALESCE(t.inpoint, 0) AS "duration!"
+            FROM gb_videos g LEFT JOIN video_trim t ON t.video_id = g.id
+            WHERE g.removed_at IS NULL
+                AND COALESCE(t.duration, g.runtime) IS NOT NULL
+                AND g.id NOT IN (SELECT video_id FROM blocklist)
+                AND g.id NOT IN (
+                    SELECT video_id FROM playlist_entry
+                    WHERE channel_id = $1 AND status IN ('pending', 'downloaded', 'active')
+                )
+            ORDER BY random()"#,
+            self.channel
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch videos matching filter")?;
+
+        Ok(rows
+            .into_iter()
+            .filter(|row| {
+                filter.matches(
+                    &row.title,
+                    &row.subjects,
+                    row.creator.as_deref(),
+                    &row.collections,
+                )
+            })
+            .take(limit as usize)
+            .map(|row| TimedVideo {
+                id: row.id,
+                title: row.title,
+                duration: row.duration,
+            })
+            .collect())
+    }
+
+    /// Videos first published on the same day and month as `day` in an earlier
+    /// year, in random order. Videos with only a year as their date are
+    /// published at the start of that year, so that moment never matches.
+    pub async fn anniversary_videos(&self, day: Date) -> Result<Vec<GbVideo>> {
+        sqlx::query_as!(
+            GbVideo,
+            r#"SELECT * FROM gb_videos
+            WHERE removed_at IS NULL
+                AND id NOT IN (SELECT video_id FROM blocklist)
+                AND published_at <> date_trunc('year', published_at AT TIME ZONE 'UTC')
+                    AT TIME ZONE 'UTC'
+                AND to_char(published_at AT TIME ZONE 'UTC', 'MM-DD')
+                    = to_char($1::DATE, 'MM-DD')
+                AND published_at < $1::DATE AT TIME ZONE 'UTC'
+            ORDER BY random()"#,
+            day
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch anniversary videos")
+    }
+
+    /// Every video that is still in the archive, for checking content rules.
+    pub async fn available_videos(&self) -> Result<Vec<GbVideo>> {
+        sqlx::query_as!(GbVideo, "SELECT * FROM gb_videos WHERE removed_at IS NULL")
+            .fetch_all(&self.pool)
+            .await
+            .wrap_err("failed to fetch videos")
+    }
+
+    /// Every video that may play, for building a playlist.
+    pub async fn playlist_candidates(&self) -> Result<Vec<CatalogVideo>> {
+        let rows = sqlx::query!(
+            r#"SELECT g.id, g.title, g.published_at, g.creator,
+                COALESCE(g.collections, '{}') AS "collections!",
+                COALESCE(g.subjects, '{}') AS "subjects!",
+                (SELECT COUNT(*) FROM play_history h
+                    WHERE h.video_id = g.id AND h.outcome IS DISTINCT FROM 'interrupted') AS "plays!",
+                s.video_id IS NOT NULL AS "overridden!", s.series AS "series?", s.part AS "part?"
+            FROM gb_videos g LEFT JOIN series_override s ON s.video_id = g.id
+            WHERE g.removed_at IS NULL AND g.id NOT IN (SELECT video_id FROM blocklist)"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch playlist candidates")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| CatalogVideo {
+                id: row.id,
+                title: row.title,
+                published_at: row.published_at,
+                creator: row.creator,
+                collections: row.collections,
+                subjects: row.subjects,
+                plays: row.plays,
+                series_override: row.overridden.then_some(SeriesOverride {
+                    series: row.series,
+                    part: row.part,
+                }),
+            })
+            .collect())
+    }
+
+    /// Puts a video into a series by hand, or keeps it out of any series if
+    /// `series` is `None`.
+    pub async fn set_series_override(
+        &self,
+        video_id: i64,
+        series: Option<&str>,
+        part: Option<i32>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO series_override (video_id, series, part) VALUES ($1, $2, $3)
+            ON CONFLICT (video_id) DO UPDATE SET series = EXCLUDED.series, part = EXCLUDED.part",
+            video_id,
+            series,
+            part
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to set series override")?;
+
+        Ok(())
+    }
+
+    /// Goes back to detecting the series of a video from its metadata.
+    pub async fn clear_series_override(&self, video_id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM series_override WHERE video_id = $1", video_id)
+            .execute(&self.pool)
+            .await
+            .wrap_err("failed to clear series override")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::{date, datetime};
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn rules_remove_played_entries(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        db.append_to_playlist(&ids).await?;
+        // the finished entry stays the last active one
+        db.move_to_next_video().await?;
+        db.finish_current_video(None).await?;
+
+        let blocklisted = db.set_rule_blocklist(&[(ids[0], "test".into())]).await?;
+        assert_eq!(blocklisted, 1);
+        let entries = sqlx::query_scalar!("SELECT video_id FROM playlist_entry")
+            .fetch_all(&db.pool)
+            .await?;
+        assert_eq!(entries, [ids[1]]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn anniversaries_match_day_and_month(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(4).await?;
+        let published = [
+            datetime!(2010-06-05 23:00 -2),
+            datetime!(2010-06-05 23:00 UTC),
+            datetime!(2025-06-05 10:00 UTC),
+            datetime!(2010-01-01 0:00 UTC),
+        ];
+        for (id, published_at) in ids.iter().zip(published) {
+            sqlx::query!(
+                "UPDATE gb_videos SET published_at = $1 WHERE id = $2",
+                published_at,
+                id
+            )
+            .execute(&db.pool)
+            .await?;
+        }
+
+        let videos = db.anniversary_videos(date!(2025 - 06 - 05)).await?;
+        assert_eq!(videos.iter().map(|v| v.id).collect::<Vec<_>>(), [ids[1]]);
+        // only the year of the last video is known
+        assert!(db
+            .anniversary_videos(date!(2025 - 01 - 01))
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
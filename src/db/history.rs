@@ -0,0 +1,260 @@
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use time::{Date, OffsetDateTime};
+
+use super::Database;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(type_name = "play_outcome", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PlayOutcome {
+    /// The video played until the end
+    Completed,
+    /// Playback was stopped before the end
+    Skipped,
+    /// Playback was paused or the stream went down, the video plays again.
+    /// Play counts leave these out, as the play continues later.
+    Interrupted,
+}
+
+#[derive(Debug)]
+pub struct PlayHistoryEntry {
+    pub id: i64,
+    pub video_id: i64,
+    pub entry_id: Option<i64>,
+    pub started_at: OffsetDateTime,
+    pub ended_at: Option<OffsetDateTime>,
+    pub outcome: Option<PlayOutcome>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct VideoPlayCount {
+    pub video_id: i64,
+    pub identifier: String,
+    pub title: String,
+    pub plays: i64,
+}
+
+#[derive(Debug)]
+pub struct DailyPlays {
+    pub day: Date,
+    pub plays: i64,
+}
+
+#[derive(Debug)]
+pub struct ShowPlays {
+    pub show: String,
+    pub plays: i64,
+}
+
+#[derive(Debug)]
+pub struct CreatorPlays {
+    pub creator: String,
+    pub plays: i64,
+}
+
+impl Database {
+    /// Opens a play history record for an entry that just became active.
+    pub(super) async fn start_play_in(
+        tx: &mut sqlx::PgConnection,
+        video_id: i64,
+        entry_id: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO play_history (video_id, entry_id) VALUES ($1, $2)",
+            video_id,
+            entry_id
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to record start of play")?;
+
+        Ok(())
+    }
+
+    /// Closes the open play history record of an entry.
+    pub(super) async fn finish_play_in(
+        tx: &mut sqlx::PgConnection,
+        entry_id: i64,
+        outcome: PlayOutcome,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE play_history SET ended_at = now(), outcome = $1, reason = $2
+            WHERE entry_id = $3 AND ended_at IS NULL",
+            outcome as PlayOutcome,
+            reason,
+            entry_id
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to record end of play")?;
+
+        Ok(())
+    }
+
+    pub async fn recent_plays(&self, limit: i64) -> Result<Vec<PlayHistoryEntry>> {
+        sqlx::query_as!(
+            PlayHistoryEntry,
+            r#"SELECT id, video_id, entry_id, started_at, ended_at,
+                outcome AS "outcome: PlayOutcome", reason
+            FROM play_history ORDER BY started_at DESC LIMIT $1"#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch play history")
+    }
+
+    pub async fn most_played_videos(&self, limit: i64) -> Result<Vec<VideoPlayCount>> {
+        sqlx::query_as!(
+            VideoPlayCount,
+            r#"SELECT v.id AS video_id, v.identifier, v.title, COUNT(*) AS "plays!"
+            FROM play_history h JOIN gb_videos v ON v.id = h.video_id
+            WHERE h.outcome IS DISTINCT FROM 'interrupted'
+            GROUP BY v.id
+            ORDER BY 4 DESC, v.title
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch most played videos")
+    }
+
+    /// Plays per show, like Quick Look or Endurance Run. The show is the
+    /// series set by hand, the part of the title before the colon or else the
+    /// title without a trailing part number.
+    pub async fn most_played_shows(&self, limit: i64) -> Result<Vec<ShowPlays>> {
+        sqlx::query_as!(
+            ShowPlays,
+            r#"SELECT COALESCE(
+                    s.series,
+                    CASE WHEN v.title LIKE '%:%' THEN btrim(split_part(v.title, ':', 1))
+                    ELSE regexp_replace(v.title, '\s*(-\s*|\()?(#|part |episode )\d+.*$', '', 'i')
+                    END
+                ) AS "show!",
+                COUNT(*) AS "plays!"
+            FROM play_history h
+                JOIN gb_videos v ON v.id = h.video_id
+                LEFT JOIN series_override s ON s.video_id = v.id
+            WHERE h.outcome IS DISTINCT FROM 'interrupted'
+            GROUP BY 1
+            ORDER BY 2 DESC, 1
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch most played shows")
+    }
+
+    /// Total time spent playing videos, in hours. Plays that are still running
+    /// count up to now, interrupted ones as far as they aired.
+    pub async fn total_hours_streamed(&self) -> Result<f64> {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(
+                SUM(EXTRACT(EPOCH FROM COALESCE(ended_at, now()) - started_at)), 0
+            )::DOUBLE PRECISION / 3600.0 AS "hours!"
+            FROM play_history"#
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to fetch total hours streamed")
+    }
+
+    pub async fn plays_per_day(&self, days: i32) -> Result<Vec<DailyPlays>> {
+        sqlx::query_as!(
+            DailyPlays,
+            r#"SELECT started_at::DATE AS "day!", COUNT(*) AS "plays!"
+            FROM play_history
+            WHERE started_at >= now() - make_interval(days => $1)
+                AND outcome IS DISTINCT FROM 'interrupted'
+            GROUP BY 1
+            ORDER BY 1"#,
+            days
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch plays per day")
+    }
+
+    pub async fn plays_per_creator(&self, limit: i64) -> Result<Vec<CreatorPlays>> {
+        sqlx::query_as!(
+            CreatorPlays,
+            r#"SELECT COALESCE(v.creator, 'unknown') AS "creator!", COUNT(*) AS "plays!"
+            FROM play_history h JOIN gb_videos v ON v.id = h.video_id
+            WHERE h.outcome IS DISTINCT FROM 'interrupted'
+            GROUP BY 1
+            ORDER BY 2 DESC, 1
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch plays per creator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn interrupted_plays_do_not_count(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        sqlx::query!(
+            "UPDATE gb_videos SET title = 'Quick Look: ' || title, creator = 'Jeff'
+            WHERE id = $1",
+            ids[0]
+        )
+        .execute(&db.pool)
+        .await?;
+        let plays = [
+            (ids[0], PlayOutcome::Interrupted),
+            (ids[0], PlayOutcome::Interrupted),
+            (ids[0], PlayOutcome::Completed),
+            (ids[1], PlayOutcome::Completed),
+            (ids[1], PlayOutcome::Skipped),
+        ];
+        for (video_id, outcome) in plays {
+            sqlx::query!(
+                "INSERT INTO play_history (video_id, started_at, ended_at, outcome)
+                VALUES ($1, now() - interval '1 hour', now(), $2)",
+                video_id,
+                outcome as PlayOutcome
+            )
+            .execute(&db.pool)
+            .await?;
+        }
+
+        let videos: Vec<_> = db
+            .most_played_videos(10)
+            .await?
+            .into_iter()
+            .map(|v| (v.video_id, v.plays))
+            .collect();
+        assert_eq!(videos, [(ids[1], 2), (ids[0], 1)]);
+        let shows: Vec<_> = db
+            .most_played_shows(10)
+            .await?
+            .into_iter()
+            .map(|s| (s.show, s.plays))
+            .collect();
+        assert_eq!(shows, [("Video 2".into(), 2), ("Quick Look".into(), 1)]);
+        let creators: Vec<_> = db
+            .plays_per_creator(10)
+            .await?
+            .into_iter()
+            .map(|c| (c.creator, c.plays))
+            .collect();
+        assert_eq!(creators, [("unknown".into(), 2), ("Jeff".into(), 1)]);
+        let days = db.plays_per_day(1).await?;
+        assert_eq!(days.iter().map(|d| d.plays).sum::<i64>(), 3);
+        assert!((db.total_hours_streamed().await? - 5.0).abs() < 0.01);
+        Ok(())
+    }
+}
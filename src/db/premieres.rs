@@ -0,0 +1,300 @@
+use std::fmt;
+
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::{Database, PlaylistEntryStatus};
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(type_name = "premiere_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PremiereStatus {
+    Scheduled,
+    /// In the playlist, held back until it starts
+    Queued,
+    Started,
+    Cancelled,
+}
+
+/// A video that starts playing at a fixed time, together with its title.
+#[derive(Debug, Clone, Serialize)]
+pub struct Premiere {
+    pub id: i64,
+    pub video_id: i64,
+    pub title: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub starts_at: OffsetDateTime,
+    pub status: PremiereStatus,
+    pub entry_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// Returned when a premiere can not be created or cancelled.
+#[derive(Debug)]
+pub enum InvalidPremiere {
+    InPast,
+    Blocklisted(i64),
+    NotCancellable(i64),
+}
+
+impl fmt::Display for InvalidPremiere {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InPast => write!(f, "premieres have to start in the future"),
+            Self::Blocklisted(video_id) => write!(f, "video {video_id} is blocklisted"),
+            Self::NotCancellable(id) => write!(f, "premiere {id} already started or was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidPremiere {}
+
+impl Database {
+    pub async fn create_premiere(
+        &self,
+        video_id: i64,
+        starts_at: OffsetDateTime,
+    ) -> Result<Premiere> {
+        if starts_at <= OffsetDateTime::now_utc() {
+            return Err(InvalidPremiere::InPast.into());
+        }
+        if self.is_blocklisted(video_id).await? {
+            return Err(InvalidPremiere::Blocklisted(video_id).into());
+        }
+        let id = sqlx::query_scalar!(
+            "INSERT INTO premiere (channel_id, video_id, starts_at) VALUES ($1, $2, $3)
+            RETURNING id",
+            self.channel,
+            video_id,
+            starts_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to create premiere")?;
+
+        self.fetch_premiere(id).await
+    }
+
+    pub async fn fetch_premiere(&self, id: i64) -> Result<Premiere> {
+        sqlx::query_as!(
+            Premiere,
+            r#"SELECT p.id, p.video_id, g.title, p.starts_at,
+                p.status AS "status: PremiereStatus", p.entry_id, p.created_at
+            FROM premiere p JOIN gb_videos g ON g.id = p.video_id
+            WHERE p.id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err_with(|| format!("failed to fetch premiere {id}"))
+    }
+
+    /// Premieres that did not start yet, soonest first.
+    pub async fn upcoming_premieres(&self) -> Result<Vec<Premiere>> {
+        sqlx::query_as!(
+            Premiere,
+            r#"SELECT p.id, p.video_id, g.title, p.starts_at,
+                p.status AS "status: PremiereStatus", p.entry_id, p.created_at
+            FROM premiere p JOIN gb_videos g ON g.id = p.video_id
+            WHERE p.channel_id = $1 AND p.status IN ('scheduled', 'queued')
+            ORDER BY p.starts_at"#,
+            self.channel
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch upcoming premieres")
+    }
+
+    /// Cancels a premiere and takes its video out of the playlist again, unless
+    /// it was in the playlist before or is being downloaded right now. In that
+    /// case it stays and plays like any other video.
+    pub async fn cancel_premiere(&self, id: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let cancelled = sqlx::query!(
+            "UPDATE premiere SET status = 'cancelled'
+            WHERE id = $1 AND status IN ('scheduled', 'queued')
+            RETURNING entry_id, created_entry",
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .wrap_err("failed to cancel premiere")?;
+        let Some(cancelled) = cancelled else {
+            // distinguishes a missing premiere from one that started or ended
+            self.fetch_premiere(id).await?;
+            return Err(InvalidPremiere::NotCancellable(id).into());
+        };
+
+        if let (Some(entry_id), true) = (cancelled.entry_id, cancelled.created_entry) {
+            // the downloader and player move an entry on under this lock
+            let status = sqlx::query_scalar!(
+                r#"SELECT status AS "status: PlaylistEntryStatus"
+                FROM playlist_entry WHERE id = $1 FOR UPDATE"#,
+                entry_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if matches!(
+                status,
+                PlaylistEntryStatus::Unplayed | PlaylistEntryStatus::Downloaded
+            ) {
+                sqlx::query!("DELETE FROM playlist_entry WHERE id = $1", entry_id)
+                    .execute(&mut *tx)
+                    .await
+                    .wrap_err("failed to remove premiere entry")?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The premiere that is held in the playlist. If there is none, the next
+    /// premiere starting within `lead_time` seconds is put at the front of the
+    /// playlist, so it is downloaded in time.
+    pub async fn queue_next_premiere(&self, lead_time: f64) -> Result<Option<Premiere>> {
+        let queued = sqlx::query_scalar!(
+            "SELECT id FROM premiere WHERE channel_id = $1 AND status = 'queued'
+            ORDER BY starts_at LIMIT 1",
+            self.channel
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(id) = queued {
+            return self.fetch_premiere(id).await.map(Some);
+        }
+
+        let due = sqlx::query!(
+            "SELECT id, video_id FROM premiere
+            WHERE channel_id = $1 AND status = 'scheduled'
+                AND starts_at <= now() + make_interval(secs => $2)
+            ORDER BY starts_at LIMIT 1",
+            self.channel,
+            lead_time
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(due) = due else {
+            return Ok(None);
+        };
+        let mut tx = self.pool.begin().await?;
+        let existed = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM playlist_entry WHERE channel_id = $1 AND video_id = $2
+            ) AS "exists!""#,
+            self.channel,
+            due.video_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let entry = self.enqueue_at_in(&mut tx, due.video_id, Some(0)).await?;
+        sqlx::query!(
+            "UPDATE premiere SET status = 'queued', entry_id = $1, created_entry = $2
+            WHERE id = $3",
+            entry.id,
+            !existed,
+            due.id
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to queue premiere")?;
+        tx.commit().await?;
+
+        self.fetch_premiere(due.id).await.map(Some)
+    }
+
+    /// Marks the premiere of an entry that just became active as started.
+    pub async fn mark_premiere_started(&self, entry_id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE premiere SET status = 'started' WHERE entry_id = $1 AND status = 'queued'",
+            entry_id
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to mark premiere as started")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn cancelling_keeps_entries_from_before(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        db.append_to_playlist(&ids[..1]).await?;
+        let starts_at = OffsetDateTime::now_utc() + Duration::minutes(5);
+        let existing = db.create_premiere(ids[0], starts_at).await?;
+        let new = db
+            .create_premiere(ids[1], starts_at + Duration::hours(1))
+            .await?;
+
+        for premiere in [existing, new] {
+            db.queue_next_premiere(3.0 * 3600.0).await?;
+            db.cancel_premiere(premiere.id).await?;
+        }
+
+        let entries = sqlx::query_scalar!("SELECT video_id FROM playlist_entry")
+            .fetch_all(&db.pool)
+            .await?;
+        assert_eq!(entries, [ids[0]]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn cancelling_keeps_entries_being_downloaded(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(1).await?;
+        let starts_at = OffsetDateTime::now_utc() + Duration::minutes(5);
+        let premiere = db.create_premiere(ids[0], starts_at).await?;
+        db.queue_next_premiere(3600.0).await?;
+        db.set_video_pending(ids[0]).await?;
+
+        db.cancel_premiere(premiere.id).await?;
+        let error = db.cancel_premiere(premiere.id).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(InvalidPremiere::NotCancellable(_))
+        ));
+
+        // the download finishes and the video plays like any other
+        db.set_video_downloaded(ids[0], "video.mp4").await?;
+        let next = db.move_to_next_video().await?.expect("video plays");
+        assert_eq!(next.video_id, ids[0]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn premieres_are_held_back_until_due(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        db.append_to_playlist(&ids[1..]).await?;
+        let starts_at = OffsetDateTime::now_utc() + Duration::hours(1);
+        let premiere = db.create_premiere(ids[0], starts_at).await?;
+        db.queue_next_premiere(2.0 * 3600.0).await?;
+        for id in &ids {
+            db.set_video_pending(*id).await?;
+            db.set_video_downloaded(*id, "video.mp4").await?;
+        }
+
+        // the premiere is in front, the video after it fits the gap
+        let next = db.move_to_next_video().await?.expect("filler plays");
+        assert_eq!(next.video_id, ids[1]);
+        db.mark_premiere_started(next.id).await?;
+        assert_eq!(
+            db.fetch_premiere(premiere.id).await?.status,
+            PremiereStatus::Queued
+        );
+        // nothing else is left, the premiere still waits
+        assert!(db.move_to_next_video().await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{bail, Context};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::{Database, GbVideo, PlaylistEntry, PlaylistEntryStatus};
+use crate::Result;
+
+/// A playlist entry together with the video it plays.
+#[derive(Debug, Serialize)]
+pub struct QueueItem {
+    pub entry: PlaylistEntry,
+    pub video: GbVideo,
+}
+
+/// How many entries of a channel are in a status.
+#[derive(Debug)]
+pub struct StatusCount {
+    pub channel: String,
+    pub status: PlaylistEntryStatus,
+    pub entries: i64,
+}
+
+impl Database {
+    pub async fn fetch_videos(&self, ids: &[i64]) -> Result<Vec<GbVideo>> {
+        sqlx::query_as!(GbVideo, "SELECT * FROM gb_videos WHERE id = ANY($1)", ids)
+            .fetch_all(&self.pool)
+            .await
+            .wrap_err("failed to fetch videos from database")
+    }
+
+    /// Joins the entries with their videos, keeping the order of the entries.
+    pub async fn with_videos(&self, entries: Vec<PlaylistEntry>) -> Result<Vec<QueueItem>> {
+        let ids: Vec<_> = entries.iter().map(|e| e.video_id).collect();
+        let mut videos: HashMap<_, _> = self
+            .fetch_videos(&ids)
+            .await?
+            .into_iter()
+            .map(|v| (v.id, v))
+            .collect();
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let video = videos.remove(&entry.video_id)?;
+                Some(QueueItem { entry, video })
+            })
+            .collect())
+    }
+
+    /// Entries that have not played yet, in playlist order.
+    pub async fn upcoming_entries(&self, limit: i64) -> Result<Vec<PlaylistEntry>> {
+        sqlx::query_as!(
+            PlaylistEntry,
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry WHERE channel_id = $1 AND status NOT IN ('active', 'finished')
+            ORDER BY position LIMIT $2"#,
+            self.channel,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch upcoming entries")
+    }
+
+    /// Entries that are being downloaded or are downloaded and waiting to play.
+    pub async fn download_queue(&self) -> Result<Vec<PlaylistEntry>> {
+        sqlx::query_as!(
+            PlaylistEntry,
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry WHERE channel_id = $1 AND status IN ('pending', 'downloaded')
+            ORDER BY position"#,
+            self.channel
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch download queue")
+    }
+
+    /// The position right after the active entry, or the front of the queue if
+    /// nothing is playing. `entry_id` is the entry that is about to move there.
+    async fn next_position_in(&self, tx: &mut sqlx::PgConnection, entry_id: i64) -> Result<i64> {
+        let position = sqlx::query_scalar!(
+            r#"SELECT COALESCE(
+                (SELECT position + 1 FROM playlist_entry
+                    WHERE channel_id = $1 AND status = 'active' LIMIT 1),
+                (SELECT MIN(position) FROM playlist_entry
+                    WHERE channel_id = $1 AND status <> 'finished' AND id <> $2),
+                (SELECT MAX(position) + 1 FROM playlist_entry WHERE channel_id = $1 AND id <> $2),
+                1
+            ) AS "position!""#,
+            self.channel,
+            entry_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(position)
+    }
+
+    /// Moves an entry to `position`, shifting the entries at and after it back.
+    async fn move_entry_in(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        entry_id: i64,
+        position: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE playlist_entry SET position = position + 1
+            WHERE channel_id = $1 AND position >= $2 AND id <> $3",
+            self.channel,
+            position,
+            entry_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE playlist_entry SET position = $1 WHERE id = $2",
+            position,
+            entry_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The position of the `slot`-th upcoming entry (starting at 0) other than
+    /// `entry_id`, or the end of the playlist if there are fewer upcoming entries.
+    async fn upcoming_position_in(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        entry_id: i64,
+        slot: i64,
+    ) -> Result<i64> {
+        let position = sqlx::query_scalar!(
+            r#"SELECT COALESCE(
+                (SELECT position FROM playlist_entry
+                    WHERE channel_id = $1 AND status NOT IN ('active', 'finished') AND id <> $2
+                    ORDER BY position OFFSET $3 LIMIT 1),
+                (SELECT MAX(position) + 1 FROM playlist_entry WHERE channel_id = $1 AND id <> $2),
+                1
+            ) AS "position!""#,
+            self.channel,
+            entry_id,
+            slot
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(position)
+    }
+
+    /// Puts a video right after the active entry. Videos that already played are
+    /// queued again, videos that are further back in the playlist move forward.
+    pub async fn enqueue_next(&self, video_id: i64) -> Result<PlaylistEntry> {
+        self.enqueue_at(video_id, None).await
+    }
+
+    /// Like [`Database::enqueue_next`], but puts the video at the `slot`-th
+    /// upcoming position instead (starting at 0).
+    pub async fn enqueue_at(&self, video_id: i64, slot: Option<i64>) -> Result<PlaylistEntry> {
+        let mut tx = self.pool.begin().await?;
+        let entry = self.enqueue_at_in(&mut tx, video_id, slot).await?;
+        tx.commit().await?;
+
+        Ok(entry)
+    }
+
+    /// [`Database::enqueue_at`] as part of a larger transaction.
+    pub(super) async fn enqueue_at_in(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        video_id: i64,
+        slot: Option<i64>,
+    ) -> Result<PlaylistEntry> {
+        let blocklisted = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM blocklist WHERE video_id = $1) AS "exists!""#,
+            video_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if blocklisted {
+            bail!("video {video_id} is blocklisted");
+        }
+        let existing = sqlx::query!(
+            r#"SELECT id, status AS "status: PlaylistEntryStatus"
+            FROM playlist_entry WHERE channel_id = $1 AND video_id = $2 FOR UPDATE"#,
+            self.channel,
+            video_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let entry_id = match existing {
+            Some(row) if row.status == PlaylistEntryStatus::Active => {
+                bail!("video {video_id} is already playing")
+            }
+            Some(row) => {
+                if row.status == PlaylistEntryStatus::Finished {
+                    Self::transition_in(&mut *tx, row.id, PlaylistEntryStatus::Unplayed).await?;
+                    // the file may be gone and it starts from the beginning
+                    sqlx::query!(
+                        "UPDATE playlist_entry SET file_path = NULL, last_progress = NULL
+                        WHERE id = $1",
+                        row.id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                row.id
+            }
+            None => sqlx::query_scalar!(
+                "INSERT INTO playlist_entry (channel_id, video_id, status, position)
+                    VALUES ($1, $2, 'unplayed', 0) RETURNING id",
+                self.channel,
+                video_id
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .wrap_err("failed to insert playlist entry")?,
+        };
+
+        let position = match slot {
+            Some(slot) => self.upcoming_position_in(&mut *tx, entry_id, slot).await?,
+            None => self.next_position_in(&mut *tx, entry_id).await?,
+        };
+        self.move_entry_in(&mut *tx, entry_id, position).await?;
+
+        let entry = sqlx::query_as!(
+            PlaylistEntry,
+            r#"SELECT id, video_id, status AS "status: PlaylistEntryStatus", file_path,
+                last_progress, position, status_changed_at
+            FROM playlist_entry WHERE id = $1"#,
+            entry_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(entry)
+    }
+
+    /// Removes an entry from the playlist. The active entry can not be removed,
+    /// skip it instead.
+    pub async fn remove_entry(&self, entry_id: i64) -> Result<()> {
+        let removed = sqlx::query!(
+            "DELETE FROM playlist_entry WHERE id = $1 AND channel_id = $2 AND status <> 'active'",
+            entry_id,
+            self.channel
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to remove playlist entry")?
+        .rows_affected();
+        if removed == 0 {
+            bail!("playlist entry {entry_id} does not exist or is playing");
+        }
+
+        Ok(())
+    }
+
+    /// Shuffles the entries that have not played yet among their current
+    /// positions. Played entries and the active one stay where they are.
+    pub async fn reshuffle_upcoming(&self) -> Result<u64> {
+        let shuffled = sqlx::query!(
+            "WITH upcoming AS (
+                SELECT id, position FROM playlist_entry
+                WHERE channel_id = $1 AND status NOT IN ('active', 'finished')
+            ),
+            shuffled AS (
+                SELECT id, row_number() OVER (ORDER BY random()) AS n FROM upcoming
+            ),
+            positions AS (
+                SELECT position, row_number() OVER (ORDER BY position) AS n FROM upcoming
+            )
+            UPDATE playlist_entry p SET position = positions.position
+            FROM shuffled JOIN positions USING (n)
+            WHERE p.id = shuffled.id",
+            self.channel
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to reshuffle playlist")?
+        .rows_affected();
+
+        Ok(shuffled)
+    }
+
+    /// Moves every entry in `status` back to unplayed, forgetting downloaded
+    /// files and playback progress. Returns the number of reset entries and the
+    /// files no entry uses anymore, which the caller deletes.
+    pub async fn reset_entries(&self, status: PlaylistEntryStatus) -> Result<(usize, Vec<String>)> {
+        let mut tx = self.pool.begin().await?;
+        let ids = sqlx::query_scalar!(
+            "SELECT id FROM playlist_entry WHERE channel_id = $1 AND status = $2 FOR UPDATE",
+            self.channel,
+            status as PlaylistEntryStatus
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for id in &ids {
+            Self::transition_in(&mut tx, *id, PlaylistEntryStatus::Unplayed).await?;
+        }
+        let files = sqlx::query_scalar!(
+            r#"WITH reset AS (
+                UPDATE playlist_entry new SET file_path = NULL, last_progress = NULL
+                FROM playlist_entry old
+                WHERE old.id = new.id AND new.id = ANY($1)
+                RETURNING old.file_path
+            )
+            SELECT DISTINCT file_path AS "file_path!" FROM reset
+            WHERE file_path IS NOT NULL
+                -- downloads are shared between channels
+                AND NOT EXISTS(
+                    SELECT 1 FROM playlist_entry e
+                    WHERE e.file_path = reset.file_path AND NOT (e.id = ANY($1))
+                )"#,
+            &ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok((ids.len(), files))
+    }
+
+    /// The number of entries per status in every channel.
+    pub async fn playlist_status_counts(&self) -> Result<Vec<StatusCount>> {
+        sqlx::query_as!(
+            StatusCount,
+            r#"SELECT c."name" AS channel, e.status AS "status: PlaylistEntryStatus",
+                COUNT(*) AS "entries!"
+            FROM playlist_entry e JOIN channel c ON c.id = e.channel_id
+            GROUP BY 1, 2
+            ORDER BY 1, 2"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to count playlist entries")
+    }
+
+    /// Seconds of downloaded videos waiting to play in this channel. Videos of
+    /// unknown duration do not count.
+    pub async fn buffered_seconds(&self) -> Result<f64> {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(GREATEST(
+                COALESCE(t.outpoint, t.duration, g.runtime)
+                    - COALESCE(e.last_progress, t.inpoint, 0),
+                0
+            )), 0)::DOUBLE PRECISION AS "seconds!"
+            FROM playlist_entry e
+            JOIN gb_videos g ON g.id = e.video_id
+            LEFT JOIN video_trim t ON t.video_id = e.video_id
+            WHERE e.channel_id = $1 AND e.status = 'downloaded'"#,
+            self.channel
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to sum up buffered playtime")
+    }
+
+    /// When the latest entry of this channel that is waiting to play was
+    /// downloaded.
+    pub async fn last_download_at(&self) -> Result<Option<OffsetDateTime>> {
+        sqlx::query_scalar!(
+            "SELECT MAX(status_changed_at) FROM playlist_entry
+            WHERE channel_id = $1 AND status = 'downloaded'",
+            self.channel
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to fetch the latest download")
+    }
+
+    /// Like [`Database::buffered_seconds`], for every channel by its name.
+    pub async fn buffered_playtime(&self) -> Result<Vec<(String, f64)>> {
+        let rows = sqlx::query!(
+            r#"SELECT c."name" AS channel, COALESCE(SUM(GREATEST(
+                COALESCE(t.outpoint, t.duration, g.runtime)
+                    - COALESCE(e.last_progress, t.inpoint, 0),
+                0
+            )), 0)::DOUBLE PRECISION AS "seconds!"
+            FROM channel c
+            LEFT JOIN playlist_entry e ON e.channel_id = c.id AND e.status = 'downloaded'
+            LEFT JOIN gb_videos g ON g.id = e.video_id
+            LEFT JOIN video_trim t ON t.video_id = e.video_id
+            GROUP BY c."name"
+            ORDER BY 1"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to sum up buffered playtime")?;
+
+        Ok(rows.into_iter().map(|r| (r.channel, r.seconds)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn requeued_videos_start_over(pool: sqlx::PgPool) -> Result<()> {
+        let database = Database::for_tests(pool);
+        let videos = database.insert_test_videos(2).await?;
+        database.append_to_playlist(&videos).await?;
+        sqlx::query!(
+            "UPDATE playlist_entry
+            SET status = 'finished', file_path = '/videos/v-1.mp4', last_progress = 1400
+            WHERE video_id = $1",
+            videos[0]
+        )
+        .execute(&database.pool)
+        .await?;
+
+        let entry = database.enqueue_next(videos[0]).await?;
+        assert_eq!(entry.status, PlaylistEntryStatus::Unplayed);
+        assert_eq!(entry.file_path, None);
+        assert_eq!(entry.last_progress, None);
+        let upcoming = database.upcoming_entries(10).await?;
+        assert_eq!(upcoming[0].id, entry.id);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn played_entries_can_be_removed(pool: sqlx::PgPool) -> Result<()> {
+        let database = Database::for_tests(pool);
+        let videos = database.insert_test_videos(2).await?;
+        database.append_to_playlist(&videos).await?;
+        for video_id in &videos {
+            database.set_video_pending(*video_id).await?;
+            database
+                .set_video_downloaded(*video_id, "/videos/v.mp4")
+                .await?;
+        }
+        let first = database.move_to_next_video().await?.unwrap();
+        database.interrupt_current_video("paused").await?;
+
+        // the interrupted entry is still the last one that was active
+        database.remove_entry(first.id).await?;
+        assert!(database.current_video().await?.is_none());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn reset_returns_unused_files(pool: sqlx::PgPool) -> Result<()> {
+        let database = Database::for_tests(pool);
+        let videos = database.insert_test_videos(2).await?;
+        database.append_to_playlist(&videos).await?;
+        for (video_id, path) in videos.iter().zip(["/videos/v-1.mp4", "/videos/v-2.mp4"]) {
+            database.set_video_pending(*video_id).await?;
+            database.set_video_downloaded(*video_id, path).await?;
+        }
+        let other = database.channel("other").await?;
+        other.append_to_playlist(&videos[1..]).await?;
+        other.set_video_pending(videos[1]).await?;
+        other
+            .set_video_downloaded(videos[1], "/videos/v-2.mp4")
+            .await?;
+
+        let (count, files) = database
+            .reset_entries(PlaylistEntryStatus::Downloaded)
+            .await?;
+        assert_eq!(count, 2);
+        assert_eq!(files, ["/videos/v-1.mp4"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt;
+
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::{Database, GbVideo, PlaylistEntry};
+use crate::{config::RequestConfig, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(type_name = "request_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    /// Waiting for a moderator
+    Pending,
+    /// Spliced into the playlist
+    Approved,
+    Rejected,
+}
+
+/// A video a viewer asked for, together with its title.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoRequest {
+    pub id: i64,
+    pub video_id: i64,
+    pub title: String,
+    pub requested_by: String,
+    pub query: String,
+    pub status: RequestStatus,
+    #[serde(with = "time::serde::rfc3339")]
+    pub requested_at: OffsetDateTime,
+    pub decided_by: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub decided_at: Option<OffsetDateTime>,
+    pub reason: Option<String>,
+    pub entry_id: Option<i64>,
+}
+
+/// Returned when a request can not be submitted or decided.
+#[derive(Debug)]
+pub enum InvalidRequest {
+    NotFound(String),
+    Blocklisted(String),
+    AlreadyRequested(String),
+    /// Seconds until the viewer may request again
+    Cooldown(i64),
+    TooManyPending(i64),
+    NotPending(i64),
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(query) => write!(f, "no video found for \"{query}\""),
+            Self::Blocklisted(title) => write!(f, "{title} can not be requested"),
+            Self::AlreadyRequested(title) => write!(f, "{title} was already requested"),
+            Self::Cooldown(seconds) => {
+                write!(f, "you can request again in {} minutes", seconds / 60 + 1)
+            }
+            Self::TooManyPending(limit) => {
+                write!(f, "you already have {limit} requests waiting")
+            }
+            Self::NotPending(id) => write!(f, "request {id} was already decided"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+impl Database {
+    /// Finds the video a viewer asked for, by identifier or else by the best
+    /// search match.
+    pub async fn resolve_request(&self, query: &str) -> Result<Option<GbVideo>> {
+        let exact = sqlx::query_as!(
+            GbVideo,
+            "SELECT * FROM gb_videos WHERE identifier = $1 AND removed_at IS NULL",
+            query
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        if exact.is_some() {
+            return Ok(exact);
+        }
+
+        Ok(self.search_videos(query, 1).await?.pop())
+    }
+
+    /// Puts a viewer request into the moderation queue, enforcing the
+    /// per-viewer cooldown and limit.
+    pub async fn submit_request(
+        &self,
+        requested_by: &str,
+        query: &str,
+        config: &RequestConfig,
+    ) -> Result<VideoRequest> {
+        let requested_by = requested_by.to_lowercase();
+        let video = self
+            .resolve_request(query)
+            .await?
+            .ok_or_else(|| InvalidRequest::NotFound(query.into()))?;
+        if self.is_blocklisted(video.id).await? {
+            return Err(InvalidRequest::Blocklisted(video.title).into());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        // requests of the same user or for the same video at once have to wait,
+        // so none of them gets past the checks below
+        sqlx::query!("SELECT pg_advisory_xact_lock(hashtext($1))", requested_by)
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "SELECT id FROM gb_videos WHERE id = $1 FOR UPDATE",
+            video.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let user = sqlx::query!(
+            r#"SELECT COUNT(*) FILTER (WHERE status = 'pending') AS "pending!",
+                EXTRACT(EPOCH FROM now() - MAX(requested_at))::BIGINT AS since_last
+            FROM video_request WHERE requested_by = $1"#,
+            requested_by
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if user.pending >= config.max_pending {
+            return Err(InvalidRequest::TooManyPending(config.max_pending).into());
+        }
+        if let Some(since_last) = user.since_last {
+            let cooldown = config.cooldown as i64;
+            if since_last < cooldown {
+                return Err(InvalidRequest::Cooldown(cooldown - since_last).into());
+            }
+        }
+
+        // pending, or approved and not played since
+        let open = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM video_request r
+                WHERE r.video_id = $1 AND (
+                    r.status = 'pending'
+                    OR (r.status = 'approved' AND NOT EXISTS(
+                        SELECT 1 FROM play_history h
+                        WHERE h.entry_id = r.entry_id AND h.started_at >= r.decided_at
+                    ))
+                )
+            ) AS "exists!""#,
+            video.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if open {
+            return Err(InvalidRequest::AlreadyRequested(video.title).into());
+        }
+
+        let id = sqlx::query_scalar!(
+            "INSERT INTO video_request (video_id, requested_by, query)
+            VALUES ($1, $2, $3) RETURNING id",
+            video.id,
+            requested_by,
+            query
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .wrap_err("failed to insert video request")?;
+        tx.commit().await?;
+
+        self.fetch_request(id).await
+    }
+
+    pub async fn fetch_request(&self, id: i64) -> Result<VideoRequest> {
+        sqlx::query_as!(
+            VideoRequest,
+            r#"SELECT r.id, r.video_id, g.title, r.requested_by, r.query,
+                r.status AS "status: RequestStatus", r.requested_at, r.decided_by,
+                r.decided_at, r.reason, r.entry_id
+            FROM video_request r JOIN gb_videos g ON g.id = r.video_id
+            WHERE r.id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err_with(|| format!("failed to fetch request {id}"))
+    }
+
+    /// The moderation queue, oldest first.
+    pub async fn pending_requests(&self) -> Result<Vec<VideoRequest>> {
+        sqlx::query_as!(
+            VideoRequest,
+            r#"SELECT r.id, r.video_id, g.title, r.requested_by, r.query,
+                r.status AS "status: RequestStatus", r.requested_at, r.decided_by,
+                r.decided_at, r.reason, r.entry_id
+            FROM video_request r JOIN gb_videos g ON g.id = r.video_id
+            WHERE r.status = 'pending'
+            ORDER BY r.requested_at"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch pending requests")
+    }
+
+    /// Splices a requested video into the playlist. It plays after the active
+    /// entry and after requests that were approved earlier but did not play yet,
+    /// ahead of the shuffled order.
+    pub async fn approve_request(&self, id: i64, moderator: &str) -> Result<PlaylistEntry> {
+        let mut tx = self.pool.begin().await?;
+        // a moderator approving the same request twice at once has to wait
+        let request = sqlx::query!(
+            r#"SELECT video_id, status AS "status: RequestStatus"
+            FROM video_request WHERE id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .wrap_err_with(|| format!("failed to fetch request {id}"))?;
+        if request.status != RequestStatus::Pending {
+            return Err(InvalidRequest::NotPending(id).into());
+        }
+
+        let slot = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "slot!" FROM playlist_entry
+            WHERE channel_id = $2 AND status NOT IN ('active', 'finished') AND video_id <> $1
+            AND position <= COALESCE((
+                SELECT MAX(e.position) FROM video_request r
+                JOIN playlist_entry e ON e.id = r.entry_id
+                WHERE r.status = 'approved' AND e.channel_id = $2
+                    AND e.status NOT IN ('active', 'finished')
+                    AND NOT EXISTS(
+                        SELECT 1 FROM play_history h
+                        WHERE h.entry_id = e.id AND h.started_at >= r.decided_at
+                    )
+            ), -1)"#,
+            request.video_id,
+            self.channel
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let entry = self
+            .enqueue_at_in(&mut tx, request.video_id, Some(slot))
+            .await?;
+
+        sqlx::query!(
+            "UPDATE video_request
+            SET status = 'approved', decided_by = $1, decided_at = now(), entry_id = $2
+            WHERE id = $3",
+            moderator,
+            entry.id,
+            id
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to approve request")?;
+        tx.commit().await?;
+
+        Ok(entry)
+    }
+
+    pub async fn reject_request(
+        &self,
+        id: i64,
+        moderator: &str,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let rejected = sqlx::query!(
+            "UPDATE video_request
+            SET status = 'rejected', decided_by = $1, decided_at = now(), reason = $2
+            WHERE id = $3 AND status = 'pending'",
+            moderator,
+            reason,
+            id
+        )
+        .execute(&self.pool)
+        .await
+        .wrap_err("failed to reject request")?
+        .rows_affected();
+        if rejected == 0 {
+            // distinguishes a missing request from a decided one
+            self.fetch_request(id).await?;
+            return Err(InvalidRequest::NotPending(id).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn requests_are_approved_once(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(1).await?;
+        let id = sqlx::query_scalar!(
+            "INSERT INTO video_request (video_id, requested_by, query)
+            VALUES ($1, 'viewer', 'video 1') RETURNING id",
+            ids[0]
+        )
+        .fetch_one(&db.pool)
+        .await?;
+
+        let (first, second) = tokio::join!(
+            db.approve_request(id, "first"),
+            db.approve_request(id, "second")
+        );
+        let error = first.err().or(second.err()).expect("approved twice");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(InvalidRequest::NotPending(_))
+        ));
+        assert_eq!(db.fetch_request(id).await?.status, RequestStatus::Approved);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn requests_at_once_are_checked_one_after_another(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        db.insert_test_videos(2).await?;
+        let config = RequestConfig::default();
+
+        let (first, second) = tokio::join!(
+            db.submit_request("viewer", "v-1", &config),
+            db.submit_request("Viewer", "v-2", &config)
+        );
+        let error = first.err().or(second.err()).expect("cooldown skipped");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(InvalidRequest::Cooldown(_))
+        ));
+
+        let (first, second) = tokio::join!(
+            db.submit_request("first", "v-2", &config),
+            db.submit_request("second", "v-2", &config)
+        );
+        let error = first.err().or(second.err()).expect("requested twice");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(InvalidRequest::AlreadyRequested(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use color_eyre::eyre::Context;
+use time::OffsetDateTime;
+
+use super::Database;
+use crate::Result;
+
+impl Database {
+    /// Whether an occurrence of a programming block was already filled.
+    pub async fn schedule_slot_filled(
+        &self,
+        block: &str,
+        starts_at: OffsetDateTime,
+    ) -> Result<bool> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM schedule_slot WHERE block = $1 AND starts_at = $2
+            ) AS "exists!""#,
+            block,
+            starts_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .wrap_err("failed to check schedule slot")
+    }
+
+    /// Marks an occurrence of a programming block as filled and puts the
+    /// videos into the playlist from the `slot`-th upcoming position on, at
+    /// once, so every occurrence is filled once and completely. Videos that
+    /// are playing right now are left out. Returns the queued videos, or
+    /// `None` if the slot was already filled.
+    pub async fn fill_schedule_slot(
+        &self,
+        block: &str,
+        starts_at: OffsetDateTime,
+        ends_at: OffsetDateTime,
+        video_ids: &[i64],
+        slot: i64,
+    ) -> Result<Option<Vec<i64>>> {
+        let mut tx = self.pool.begin().await?;
+        let claimed = sqlx::query!(
+            "INSERT INTO schedule_slot (block, starts_at, ends_at) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            block,
+            starts_at,
+            ends_at
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to claim schedule slot")?
+        .rows_affected();
+        if claimed == 0 {
+            return Ok(None);
+        }
+
+        let active = sqlx::query_scalar!(
+            "SELECT video_id FROM playlist_entry WHERE channel_id = $1 AND status = 'active'",
+            self.channel
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let video_ids: Vec<_> = video_ids
+            .iter()
+            .copied()
+            .filter(|id| !active.contains(id))
+            .collect();
+        for (offset, video_id) in video_ids.iter().enumerate() {
+            self.enqueue_at_in(&mut tx, *video_id, Some(slot + offset as i64))
+                .await?;
+        }
+        sqlx::query!(
+            "UPDATE schedule_slot SET video_ids = $1 WHERE block = $2 AND starts_at = $3",
+            &video_ids,
+            block,
+            starts_at
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to record schedule slot videos")?;
+        tx.commit().await?;
+
+        Ok(Some(video_ids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{macros::datetime, Duration};
+
+    use super::*;
+    use crate::db::PlaylistEntryStatus;
+
+    #[sqlx::test]
+    async fn slots_are_filled_once(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(4).await?;
+        db.append_to_playlist(&ids[..3]).await?;
+        db.move_to_next_video().await?;
+        db.finish_current_video(None).await?;
+        db.set_video_pending(ids[1]).await?;
+        db.set_video_downloaded(ids[1], "v-2.mp4").await?;
+
+        let starts_at = datetime!(2025-06-05 00:00 UTC);
+        let ends_at = starts_at + Duration::days(1);
+        let filled = db
+            .fill_schedule_slot("test", starts_at, ends_at, &[ids[0], ids[3]], 1)
+            .await?;
+        assert_eq!(filled, Some(vec![ids[0], ids[3]]));
+        let again = db
+            .fill_schedule_slot("test", starts_at, ends_at, &[ids[2]], 0)
+            .await?;
+        assert_eq!(again, None);
+
+        let upcoming = db.upcoming_entries(10).await?;
+        let order: Vec<_> = upcoming.iter().map(|entry| entry.video_id).collect();
+        assert_eq!(order, [ids[1], ids[0], ids[3], ids[2]]);
+        // the video that played before starts over
+        assert_eq!(upcoming[1].status, PlaylistEntryStatus::Unplayed);
+        assert_eq!(upcoming[1].file_path, None);
+        Ok(())
+    }
+}
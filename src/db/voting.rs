@@ -0,0 +1,275 @@
+use std::fmt;
+
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::{Database, GbVideo};
+use crate::Result;
+
+/// A vote among a few candidates for the video that plays next.
+#[derive(Debug, Clone, Serialize)]
+pub struct VoteRound {
+    pub id: i64,
+    pub entry_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub opened_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub closed_at: Option<OffsetDateTime>,
+    pub winner_video_id: Option<i64>,
+}
+
+/// The number of votes one candidate of a round got.
+#[derive(Debug, Clone, Serialize)]
+pub struct VoteTally {
+    pub choice: i32,
+    pub video_id: i64,
+    pub title: String,
+    pub votes: i64,
+}
+
+/// How a video did across all vote rounds.
+#[derive(Debug)]
+pub struct VideoVoteStats {
+    pub video_id: i64,
+    pub identifier: String,
+    pub title: String,
+    pub offered: i64,
+    pub won: i64,
+    pub votes: i64,
+}
+
+/// Returned when a vote can not be counted.
+#[derive(Debug)]
+pub enum InvalidVote {
+    NoOpenRound,
+    UnknownChoice(i32),
+}
+
+impl fmt::Display for InvalidVote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoOpenRound => write!(f, "there is no vote running right now"),
+            Self::UnknownChoice(choice) => write!(f, "there is no candidate {choice}"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidVote {}
+
+impl Database {
+    /// Random upcoming videos to offer in a vote. Only downloaded ones are
+    /// offered, so the winner can play right after the vote.
+    pub async fn vote_candidates(&self, count: i64) -> Result<Vec<GbVideo>> {
+        sqlx::query_as!(
+            GbVideo,
+            "SELECT v.* FROM gb_videos v JOIN playlist_entry e ON e.video_id = v.id
+            WHERE e.channel_id = $2 AND e.status = 'downloaded'
+                AND v.removed_at IS NULL
+                AND v.id NOT IN (SELECT video_id FROM blocklist)
+            ORDER BY random() LIMIT $1",
+            count,
+            self.channel
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch vote candidates")
+    }
+
+    /// Opens a round with the given videos as choices 1, 2, 3 and so on.
+    pub async fn open_vote_round(&self, entry_id: i64, video_ids: &[i64]) -> Result<VoteRound> {
+        let mut tx = self.pool.begin().await?;
+        let round = sqlx::query_as!(
+            VoteRound,
+            "INSERT INTO vote_round (entry_id) VALUES ($1)
+            RETURNING id, entry_id, opened_at, closed_at, winner_video_id",
+            entry_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .wrap_err("failed to open vote round")?;
+        sqlx::query!(
+            "INSERT INTO vote_candidate (round_id, choice, video_id)
+            SELECT $1, choice::INT, video_id FROM UNNEST($2::BIGINT[]) WITH ORDINALITY AS c(video_id, choice)",
+            round.id,
+            video_ids
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to insert vote candidates")?;
+        tx.commit().await?;
+
+        Ok(round)
+    }
+
+    /// The round that is currently running, if any.
+    pub async fn current_vote_round(&self) -> Result<Option<VoteRound>> {
+        sqlx::query_as!(
+            VoteRound,
+            "SELECT id, entry_id, opened_at, closed_at, winner_video_id
+            FROM vote_round WHERE closed_at IS NULL"
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .wrap_err("failed to fetch open vote round")
+    }
+
+    /// Whether a round was already held for an entry since `since`.
+    pub async fn vote_round_held(&self, entry_id: i64, since: OffsetDateTime) -> Result<bool> {
+        let held = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM vote_round WHERE entry_id = $1 AND opened_at >= $2
+            ) AS "exists!""#,
+            entry_id,
+            since
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(held)
+    }
+
+    /// Counts a vote in the open round. Voting again replaces the earlier vote.
+    pub async fn cast_vote(&self, voter: &str, choice: i32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let round_id =
+            sqlx::query_scalar!("SELECT id FROM vote_round WHERE closed_at IS NULL FOR SHARE")
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or(InvalidVote::NoOpenRound)?;
+        let valid = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM vote_candidate WHERE round_id = $1 AND choice = $2
+            ) AS "exists!""#,
+            round_id,
+            choice
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !valid {
+            return Err(InvalidVote::UnknownChoice(choice).into());
+        }
+        sqlx::query!(
+            "INSERT INTO vote (round_id, voter, choice) VALUES ($1, $2, $3)
+            ON CONFLICT (round_id, voter) DO UPDATE
+                SET choice = EXCLUDED.choice, cast_at = now()",
+            round_id,
+            voter.to_lowercase(),
+            choice
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to record vote")?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The candidates of a round with their votes, by choice.
+    pub async fn vote_tally(&self, round_id: i64) -> Result<Vec<VoteTally>> {
+        sqlx::query_as!(
+            VoteTally,
+            r#"SELECT c.choice, c.video_id, g.title, COUNT(v.voter) AS "votes!"
+            FROM vote_candidate c
+            JOIN gb_videos g ON g.id = c.video_id
+            LEFT JOIN vote v ON v.round_id = c.round_id AND v.choice = c.choice
+            WHERE c.round_id = $1
+            GROUP BY c.choice, c.video_id, g.title
+            ORDER BY c.choice"#,
+            round_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch vote tally")
+    }
+
+    /// Closes a round and returns the candidate with the most votes. Ties are
+    /// broken randomly, a round without votes has no winner.
+    pub async fn close_vote_round(&self, round_id: i64) -> Result<Option<VoteTally>> {
+        let mut tx = self.pool.begin().await?;
+        let winner = sqlx::query_as!(
+            VoteTally,
+            r#"SELECT c.choice, c.video_id, g.title, COUNT(*) AS "votes!"
+            FROM vote v
+            JOIN vote_candidate c ON c.round_id = v.round_id AND c.choice = v.choice
+            JOIN gb_videos g ON g.id = c.video_id
+            WHERE v.round_id = $1
+            GROUP BY c.choice, c.video_id, g.title
+            ORDER BY 4 DESC, random()
+            LIMIT 1"#,
+            round_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .wrap_err("failed to count votes")?;
+        sqlx::query!(
+            "UPDATE vote_round SET closed_at = now(), winner_video_id = $1 WHERE id = $2",
+            winner.as_ref().map(|w| w.video_id),
+            round_id
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("failed to close vote round")?;
+        tx.commit().await?;
+
+        Ok(winner)
+    }
+
+    /// Videos that won the most rounds, with how often they were offered and
+    /// how many votes they got in total.
+    pub async fn vote_stats(&self, limit: i64) -> Result<Vec<VideoVoteStats>> {
+        sqlx::query_as!(
+            VideoVoteStats,
+            r#"WITH counts AS (
+                SELECT round_id, choice, COUNT(*) AS votes FROM vote GROUP BY 1, 2
+            )
+            SELECT g.id AS video_id, g.identifier, g.title,
+                COUNT(*) AS "offered!",
+                COUNT(*) FILTER (WHERE r.winner_video_id = c.video_id) AS "won!",
+                COALESCE(SUM(counts.votes), 0)::BIGINT AS "votes!"
+            FROM vote_candidate c
+            JOIN vote_round r ON r.id = c.round_id
+            JOIN gb_videos g ON g.id = c.video_id
+            LEFT JOIN counts ON counts.round_id = c.round_id AND counts.choice = c.choice
+            GROUP BY g.id
+            ORDER BY 5 DESC, 6 DESC, 4 DESC, g.title
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .wrap_err("failed to fetch vote stats")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn candidates_are_downloaded_and_upcoming(pool: sqlx::PgPool) -> Result<()> {
+        let database = Database::for_tests(pool);
+        let videos = database.insert_test_videos(4).await?;
+        database.append_to_playlist(&videos).await?;
+        for video_id in &videos[..3] {
+            database.set_video_pending(*video_id).await?;
+            database
+                .set_video_downloaded(*video_id, "/videos/v.mp4")
+                .await?;
+        }
+        // the first one plays and finishes
+        database.move_to_next_video().await?;
+        database.finish_current_video(None).await?;
+
+        let mut candidates: Vec<_> = database
+            .vote_candidates(10)
+            .await?
+            .into_iter()
+            .map(|v| v.id)
+            .collect();
+        candidates.sort();
+        assert_eq!(candidates, videos[1..3]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+use camino::Utf8Path;
+use tokio::fs;
+
+use crate::Result;
+
+/// Total size in bytes of the files directly inside `path`.
+pub async fn folder_size(path: &Utf8Path) -> Result<u64> {
+    let mut size = 0;
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Bytes available to unprivileged users and the total size of the file
+/// system `path` is on.
+pub fn free_space(path: &Utf8Path) -> Result<(u64, u64)> {
+    let stats = rustix::fs::statvfs(path.as_std_path())?;
+    Ok((
+        stats.f_bavail * stats.f_frsize,
+        stats.f_blocks * stats.f_frsize,
+    ))
+}
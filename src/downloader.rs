@@ -1,17 +1,27 @@
 use crate::{
+    analysis,
+    config::DeadAirConfig,
     db::{Database, VideoId},
+    events::{Event, EventBus},
     ia::InternetArchive,
+    supervisor::Supervisor,
     Result,
 };
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::time::Instant;
 use tokio::sync::mpsc;
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub struct DownloadOrchestrator {
     database: Database,
     ia: InternetArchive,
     video_folder: Utf8PathBuf,
+    dead_air: DeadAirConfig,
+    events: EventBus,
+    /// Downloads in flight are abandoned once this is cancelled
+    shutdown: CancellationToken,
 }
 
 impl DownloadOrchestrator {
@@ -19,39 +29,147 @@ impl DownloadOrchestrator {
         database: Database,
         ia: InternetArchive,
         video_folder: Utf8PathBuf,
+        dead_air: DeadAirConfig,
     ) -> DownloadOrchestrator {
         Self {
             database,
             ia,
             video_folder,
+            dead_air,
+            events: EventBus::default(),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Publishes the start and the end of downloads to `events`, once the
+    /// playlist knows about the outcome.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Abandons downloads in flight when `shutdown` is cancelled and puts
+    /// their entries back, so the next start downloads them again.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     async fn resolve_id(&self, id: &VideoId) -> Result<(String, i64)> {
         let video = self.database.fetch_video(id).await?;
 
         Ok((video.identifier.clone(), video.id))
     }
 
+    /// Downloads the video file from the stored file listing, only asking the
+    /// archive for the item details if the listing was not synced yet.
+    async fn download_file(&self, identifier: &str, video_id: i64) -> Result<(Utf8PathBuf, u64)> {
+        let files = self.database.video_files(video_id).await?;
+        match files.iter().find(|f| f.format == "MPEG4") {
+            Some(file) => {
+                self.ia
+                    .download_file(identifier, &file.name, &self.video_folder)
+                    .await
+            }
+            None => self.ia.download_video(identifier, &self.video_folder).await,
+        }
+    }
+
     pub async fn download_single_video(&self, id: VideoId) -> Result<()> {
         let (identifier, video_id) = self.resolve_id(&id).await?;
-        self.database.set_video_pending(video_id).await?;
+        if !self.database.set_video_pending(video_id).await? {
+            // someone else is already downloading it, or it was downloaded meanwhile
+            debug!("not downloading {identifier}: no channel is waiting for it");
+            return Ok(());
+        }
+        // another channel may have downloaded it already
+        if let Some(path) = self.database.downloaded_file(video_id).await? {
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                debug!("reusing {path} for {identifier}");
+                self.database.set_video_downloaded(video_id, &path).await?;
+                self.events.publish(Event::DownloadCompleted {
+                    identifier,
+                    path: path.into(),
+                    bytes: metadata.len(),
+                    seconds: 0.0,
+                    reused: true,
+                });
+                return Ok(());
+            }
+        }
 
-        let file_path = self
-            .ia
-            .download_video(&identifier, &self.video_folder)
-            .await?;
+        self.events.publish(Event::DownloadStarted {
+            identifier: identifier.clone(),
+        });
+        let start = Instant::now();
+        let download = tokio::select! {
+            download = self.download_file(&identifier, video_id) => download,
+            _ = self.shutdown.cancelled() => {
+                info!("pausing the download of {identifier} until the next start");
+                return self.database.set_video_download_failed(video_id).await;
+            }
+        };
+        let (file_path, bytes) = match download {
+            Ok(download) => download,
+            Err(e) => {
+                self.database.set_video_download_failed(video_id).await?;
+                self.events.publish(Event::DownloadFailed {
+                    identifier,
+                    error: e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        let seconds = start.elapsed().as_secs_f64();
+        if self.dead_air.enabled {
+            tokio::select! {
+                _ = self.analyze_video(video_id, &file_path) => {}
+                // the video plays untrimmed, the download itself is kept
+                _ = self.shutdown.cancelled() => {
+                    info!("skipping the dead air analysis of {identifier}");
+                }
+            }
+        }
         self.database
-            .set_video_downloaded(video_id, file_path)
+            .set_video_downloaded(video_id, file_path.as_str())
             .await?;
+        self.events.publish(Event::DownloadCompleted {
+            identifier,
+            path: file_path,
+            bytes,
+            seconds,
+            reused: false,
+        });
 
         Ok(())
     }
 
+    /// Detects dead air at the start and end of the video. A failed analysis only
+    /// means the video plays untrimmed, so errors are logged and not propagated.
+    async fn analyze_video(&self, video_id: i64, path: &Utf8Path) {
+        let range = match analysis::detect_dead_air(path, &self.dead_air).await {
+            Ok(range) => range,
+            Err(e) => {
+                warn!("failed to analyze {path} for dead air: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.database.set_video_trim(video_id, &range).await {
+            warn!("failed to store trim range for {path}: {e}");
+        }
+    }
+
+    /// Downloads all videos concurrently. Every download runs to completion even
+    /// if another one fails, so no entry is left behind as pending.
     pub async fn download_videos(&self, ids: Vec<VideoId>) -> Result<()> {
         let futures = ids.into_iter().map(|id| self.download_single_video(id));
-        futures::future::try_join_all(futures).await?;
-        Ok(())
+        let results = futures::future::join_all(futures).await;
+        let mut errors = results.into_iter().filter_map(|r| r.err());
+        let first = errors.next();
+        for e in errors {
+            error!("failed to download video: {e}");
+        }
+        first.map_or(Ok(()), Err)
     }
 }
 
@@ -61,13 +179,16 @@ pub struct BackgroundDownloader {
 }
 
 impl BackgroundDownloader {
-    pub fn start_new(downloader: DownloadOrchestrator) -> mpsc::Sender<Vec<VideoId>> {
+    /// Downloads until shutdown, which lets the downloads in flight put their
+    /// entries back. Entries left pending by a crash have to be reset with
+    /// [`Database::reset_pending_downloads`] before.
+    pub fn start_new(
+        downloader: DownloadOrchestrator,
+        supervisor: &Supervisor,
+    ) -> mpsc::Sender<Vec<VideoId>> {
+        let downloader = downloader.with_shutdown(supervisor.token());
         let (mut this, tx) = Self::new(downloader);
-        tokio::spawn(async move {
-            if let Err(e) = this.run().await {
-                tracing::error!("background downloader failed: {e}");
-            }
-        });
+        supervisor.spawn_graceful("background downloader", async move { this.run().await });
         tx
     }
 
@@ -79,7 +200,11 @@ impl BackgroundDownloader {
 
     async fn run(&mut self) -> Result<()> {
         loop {
-            if let Some(ids) = self.rx.recv().await {
+            let ids = tokio::select! {
+                ids = self.rx.recv() => ids,
+                _ = self.downloader.shutdown.cancelled() => break Ok(()),
+            };
+            if let Some(ids) = ids {
                 if let Err(e) = self.downloader.download_videos(ids).await {
                     error!("failed to download video: {e}");
                 }
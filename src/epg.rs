@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use color_eyre::eyre::Context;
+use time::{macros::format_description, OffsetDateTime};
+use tracing::{error, info};
+
+use crate::{
+    config::EpgConfig,
+    dashboard::escape,
+    db::{Database, GbVideo},
+    player::PlayerHandle,
+    schedule::UNKNOWN_DURATION,
+    supervisor::Supervisor,
+    Result,
+};
+
+/// Channel id used in the guide.
+const CHANNEL_ID: &str = "gb-forever";
+/// How many upcoming entries the guide looks at at most.
+const LOOKAHEAD: i64 = 1000;
+
+/// A video in the program guide with its estimated start and end.
+#[derive(Debug)]
+pub struct Programme {
+    pub start: OffsetDateTime,
+    pub stop: OffsetDateTime,
+    pub video: GbVideo,
+}
+
+/// The video that is playing and how many seconds into it playback is.
+#[derive(Debug, Clone, Copy)]
+pub struct Playing {
+    pub video_id: i64,
+    pub position: f64,
+}
+
+impl Playing {
+    pub fn from_player(player: &PlayerHandle) -> Option<Self> {
+        let state = player.state();
+        Some(Self {
+            video_id: state.video_id?,
+            position: state.position,
+        })
+    }
+
+    /// Uses the progress the player saves regularly, for when there is no
+    /// player to ask.
+    pub async fn from_database(database: &Database) -> Result<Option<Self>> {
+        Ok(database.current_video().await?.map(|entry| Self {
+            video_id: entry.video_id,
+            position: entry.last_progress.unwrap_or_default() as f64,
+        }))
+    }
+}
+
+/// The playing video and up to `limit` upcoming ones that start before
+/// `until`. Times are estimated from the durations of the videos; premieres
+/// start when they are scheduled to.
+pub async fn programmes(
+    database: &Database,
+    playing: Option<Playing>,
+    until: OffsetDateTime,
+    limit: i64,
+) -> Result<Vec<Programme>> {
+    let now = OffsetDateTime::now_utc();
+    let entries = database.upcoming_entries(limit).await?;
+    let ids: Vec<_> = playing
+        .iter()
+        .map(|playing| playing.video_id)
+        .chain(entries.iter().map(|entry| entry.video_id))
+        .collect();
+    let ends = database.playback_ends(&ids).await?;
+    let mut slots = vec![];
+
+    let mut time = now;
+    if let Some(playing) = playing {
+        let start = now - time::Duration::seconds_f64(playing.position);
+        let end = ends.get(&playing.video_id);
+        time +=
+            time::Duration::seconds_f64(end.map_or(0.0, |end| (end - playing.position).max(0.0)));
+        slots.push((playing.video_id, start, time));
+    }
+
+    let premieres: HashMap<_, _> = database
+        .upcoming_premieres()
+        .await?
+        .into_iter()
+        .filter_map(|p| Some((p.entry_id?, p.starts_at)))
+        .collect();
+    for entry in entries {
+        if let Some(starts_at) = premieres.get(&entry.id) {
+            time = time.max(*starts_at);
+        }
+        if time >= until {
+            break;
+        }
+        let progress = entry.last_progress.unwrap_or_default() as f64;
+        let duration = ends
+            .get(&entry.video_id)
+            .map_or(UNKNOWN_DURATION, |end| (end - progress).max(0.0));
+        let start = time;
+        time += time::Duration::seconds_f64(duration);
+        slots.push((entry.video_id, start, time));
+    }
+
+    let ids: Vec<_> = slots.iter().map(|(id, _, _)| *id).collect();
+    let mut videos: HashMap<_, _> = database
+        .fetch_videos(&ids)
+        .await?
+        .into_iter()
+        .map(|v| (v.id, v))
+        .collect();
+
+    Ok(slots
+        .into_iter()
+        .filter_map(|(video_id, start, stop)| {
+            let video = videos.remove(&video_id)?;
+            Some(Programme { start, stop, video })
+        })
+        .collect())
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(format_description!(
+        "[year][month][day][hour][minute][second] [offset_hour sign:mandatory][offset_minute]"
+    ))
+    .unwrap_or_default()
+}
+
+/// The programmes of the next `hours`.
+pub async fn guide(
+    database: &Database,
+    player: &PlayerHandle,
+    hours: u64,
+) -> Result<Vec<Programme>> {
+    let until = OffsetDateTime::now_utc() + time::Duration::hours(hours as i64);
+    programmes(database, Playing::from_player(player), until, LOOKAHEAD).await
+}
+
+/// Renders the guide as an XMLTV document.
+pub fn to_xmltv(channel_name: &str, programmes: &[Programme]) -> String {
+    let mut xml = String::new();
+    let _ = write!(
+        xml,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE tv SYSTEM "xmltv.dtd">
+<tv generator-info-name="gb-forever">
+  <channel id="{CHANNEL_ID}">
+    <display-name>{}</display-name>
+  </channel>
+"#,
+        escape(channel_name)
+    );
+    for programme in programmes {
+        let video = &programme.video;
+        let _ = writeln!(
+            xml,
+            r#"  <programme start="{}" stop="{}" channel="{CHANNEL_ID}">"#,
+            format_time(programme.start),
+            format_time(programme.stop)
+        );
+        let _ = writeln!(xml, "    <title>{}</title>", escape(&video.title));
+        if let Some(description) = video.description.as_deref().filter(|d| !d.is_empty()) {
+            let _ = writeln!(xml, "    <desc>{}</desc>", escape(description));
+        }
+        if let Some(published_at) = video.published_at {
+            let date = published_at
+                .format(format_description!("[year][month][day]"))
+                .unwrap_or_default();
+            let _ = writeln!(xml, "    <date>{date}</date>");
+        }
+        for subject in video.subjects.iter().flatten() {
+            let _ = writeln!(xml, "    <category>{}</category>", escape(subject));
+        }
+        if let Some(thumbnail) = &video.thumbnail_url {
+            let _ = writeln!(xml, r#"    <icon src="{}" />"#, escape(thumbnail));
+        }
+        xml.push_str("  </programme>\n");
+    }
+    xml.push_str("</tv>\n");
+
+    xml
+}
+
+/// Regularly writes the program guide to the file from the config.
+pub struct EpgWriter {
+    config: EpgConfig,
+    database: Database,
+    player: PlayerHandle,
+}
+
+impl EpgWriter {
+    pub fn new(config: EpgConfig, database: Database, player: PlayerHandle) -> Self {
+        Self {
+            config,
+            database,
+            player,
+        }
+    }
+
+    pub fn start(self, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval.max(1) * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.write().await {
+                    error!("writing the program guide failed: {e}");
+                }
+            }
+        });
+    }
+
+    async fn write(&self) -> Result<()> {
+        let Some(path) = &self.config.path else {
+            return Ok(());
+        };
+        let programmes = guide(&self.database, &self.player, self.config.hours).await?;
+        let xml = to_xmltv(&self.config.channel_name, &programmes);
+
+        // readers never see a partially written guide
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, xml)
+            .await
+            .wrap_err_with(|| format!("failed to write {temp_path}"))?;
+        tokio::fs::rename(&temp_path, path)
+            .await
+            .wrap_err_with(|| format!("failed to move program guide to {path}"))?;
+        info!("wrote {} programmes to {path}", programmes.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn xmltv_has_times_and_escaped_titles() {
+        let video = GbVideo {
+            description: Some("Jeff & Brad play <b>Halo</b>".into()),
+            published_at: Some(datetime!(2007-09-25 0:00 UTC)),
+            ..GbVideo::for_tests(1, "Quick Look: Halo 3")
+        };
+        let programme = Programme {
+            start: datetime!(2025-06-01 20:00 UTC),
+            stop: datetime!(2025-06-01 20:30:15 UTC),
+            video,
+        };
+        let xml = to_xmltv("GB & Friends", &[programme]);
+
+        assert!(xml.contains("<display-name>GB &amp; Friends</display-name>"));
+        assert!(xml.contains(
+            r#"<programme start="20250601200000 +0000" stop="20250601203015 +0000" channel="gb-forever">"#
+        ));
+        assert!(xml.contains("<desc>Jeff &amp; Brad play &lt;b&gt;Halo&lt;/b&gt;</desc>"));
+        assert!(xml.contains("<date>20070925</date>"));
+    }
+}
@@ -0,0 +1,83 @@
+use camino::Utf8PathBuf;
+use tokio::sync::broadcast;
+
+/// Events published by receivers that fall this far behind are skipped.
+const CAPACITY: usize = 256;
+
+/// Something that happened in the downloader or a player.
+#[derive(Debug, Clone)]
+pub enum Event {
+    DownloadStarted {
+        identifier: String,
+    },
+    /// Published every few hundred chunks
+    DownloadProgress {
+        identifier: String,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// The entries waiting for the video are downloaded
+    DownloadCompleted {
+        identifier: String,
+        path: Utf8PathBuf,
+        bytes: u64,
+        seconds: f64,
+        /// The file downloaded for another channel is used
+        reused: bool,
+    },
+    DownloadFailed {
+        identifier: String,
+        error: String,
+    },
+    /// A playlist entry of `channel` became the active one
+    EntryStarted {
+        channel: String,
+        entry_id: i64,
+        video_id: i64,
+    },
+    /// Playback of the entry ended, whether it completed or not
+    EntryFinished {
+        channel: String,
+        entry_id: i64,
+        video_id: i64,
+        /// Seconds into the video where playback stopped
+        position: f64,
+    },
+    /// The ffmpeg process streaming to the server started
+    StreamStarted {
+        channel: String,
+    },
+    /// The ffmpeg process streaming to the server stopped, with the reason if
+    /// it failed
+    StreamStopped {
+        channel: String,
+        error: Option<String>,
+    },
+}
+
+/// Broadcasts [`Event`]s to every subscriber. Clones publish to the same
+/// subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    /// Does nothing if nobody subscribed.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receives the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, fmt::Write};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    dashboard::escape,
+    db::{Database, GbVideo},
+    epg::{self, Playing},
+    Result,
+};
+
+/// File formats the programming can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[serde(alias = "m3u8")]
+    #[value(alias = "m3u8")]
+    M3u,
+    Xspf,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::M3u => "audio/x-mpegurl; charset=utf-8",
+            Self::Xspf => "application/xspf+xml; charset=utf-8",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+/// A video that played or is going to play, with when it starts.
+#[derive(Debug, Serialize)]
+pub struct ExportItem {
+    pub identifier: String,
+    pub title: String,
+    /// Details page of the item on archive.org
+    pub url: String,
+    /// Original air date as recorded by the archive
+    pub date: Option<String>,
+    /// Estimated for upcoming videos
+    #[serde(with = "time::serde::rfc3339")]
+    pub starts_at: OffsetDateTime,
+    /// Seconds, not known for videos that are still playing
+    pub duration: Option<f64>,
+}
+
+impl ExportItem {
+    fn new(video: &GbVideo, starts_at: OffsetDateTime, ends_at: Option<OffsetDateTime>) -> Self {
+        let duration = ends_at.map(|end| (end - starts_at).as_seconds_f64());
+        Self {
+            identifier: video.identifier.clone(),
+            title: video.title.clone(),
+            url: format!("https://archive.org/details/{}", video.identifier),
+            date: video.date.clone(),
+            starts_at: starts_at.replace_nanosecond(0).unwrap_or(starts_at),
+            duration,
+        }
+    }
+}
+
+/// The playing video and the next `limit` ones, with estimated start times.
+pub async fn upcoming(
+    database: &Database,
+    playing: Option<Playing>,
+    limit: i64,
+) -> Result<Vec<ExportItem>> {
+    let until = OffsetDateTime::now_utc() + time::Duration::days(365);
+    Ok(epg::programmes(database, playing, until, limit)
+        .await?
+        .into_iter()
+        .map(|p| ExportItem::new(&p.video, p.start, Some(p.stop)))
+        .collect())
+}
+
+/// The last `limit` plays, oldest first.
+pub async fn history(database: &Database, limit: i64) -> Result<Vec<ExportItem>> {
+    let mut plays = database.recent_plays(limit).await?;
+    plays.reverse();
+    let ids: Vec<_> = plays.iter().map(|p| p.video_id).collect();
+    let videos: HashMap<_, _> = database
+        .fetch_videos(&ids)
+        .await?
+        .into_iter()
+        .map(|v| (v.id, v))
+        .collect();
+
+    Ok(plays
+        .into_iter()
+        .filter_map(|play| {
+            let video = videos.get(&play.video_id)?;
+            Some(ExportItem::new(video, play.started_at, play.ended_at))
+        })
+        .collect())
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+/// M3U directives end at the line break.
+fn one_line(text: &str) -> String {
+    text.replace(|c: char| c.is_control(), " ")
+}
+
+/// Renders the items as a playlist file called `title`.
+pub fn render(format: ExportFormat, title: &str, items: &[ExportItem]) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        ExportFormat::M3u => {
+            let _ = writeln!(out, "#EXTM3U\n#PLAYLIST:{}", one_line(title));
+            for item in items {
+                let duration = item.duration.map_or(-1, |d| d.round() as i64);
+                let _ = writeln!(out, "# {}", format_time(item.starts_at));
+                let _ = writeln!(
+                    out,
+                    "#EXTINF:{duration},{}\n{}",
+                    one_line(&item.title),
+                    item.url
+                );
+            }
+        }
+        ExportFormat::Xspf => {
+            let _ = writeln!(
+                out,
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>{}</title>
+  <trackList>"#,
+                escape(title)
+            );
+            for item in items {
+                out.push_str("    <track>\n");
+                let _ = writeln!(out, "      <location>{}</location>", escape(&item.url));
+                let _ = writeln!(out, "      <title>{}</title>", escape(&item.title));
+                if let Some(duration) = item.duration {
+                    let _ = writeln!(out, "      <duration>{:.0}</duration>", duration * 1000.0);
+                }
+                let _ = writeln!(
+                    out,
+                    "      <annotation>{}{}</annotation>",
+                    format_time(item.starts_at),
+                    item.date
+                        .as_deref()
+                        .map(|date| format!(", originally aired {}", escape(date)))
+                        .unwrap_or_default()
+                );
+                out.push_str("    </track>\n");
+            }
+            out.push_str("  </trackList>\n</playlist>\n");
+        }
+        ExportFormat::Json => out = serde_json::to_string_pretty(items)?,
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn items() -> Vec<ExportItem> {
+        let video = GbVideo {
+            date: Some("2007-09-25".into()),
+            ..GbVideo::for_tests(1, "Quick Look:\nHalo 3 & <friends>")
+        };
+        vec![ExportItem::new(
+            &video,
+            datetime!(2025-06-01 20:00 UTC),
+            Some(datetime!(2025-06-01 20:30:15 UTC)),
+        )]
+    }
+
+    #[test]
+    fn renders_m3u_on_one_line() {
+        let m3u = render(ExportFormat::M3u, "GB\nForever", &items()).unwrap();
+        assert_eq!(
+            m3u,
+            "#EXTM3U
+#PLAYLIST:GB Forever
+# 2025-06-01T20:00:00Z
+#EXTINF:1815,Quick Look: Halo 3 & <friends>
+https://archive.org/details/item
+"
+        );
+    }
+
+    #[test]
+    fn renders_escaped_xspf() {
+        let xspf = render(ExportFormat::Xspf, "GB & Friends", &items()).unwrap();
+        assert!(xspf.contains("<title>GB &amp; Friends</title>"));
+        assert!(xspf.contains("<title>Quick Look:\nHalo 3 &amp; &lt;friends&gt;</title>"));
+        assert!(xspf.contains("<duration>1815000</duration>"));
+        assert!(xspf.contains(
+            "<annotation>2025-06-01T20:00:00Z, originally aired 2007-09-25</annotation>"
+        ));
+    }
+
+    #[test]
+    fn renders_json() {
+        let json = render(ExportFormat::Json, "GB Forever", &items()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["starts_at"], "2025-06-01T20:00:00Z");
+        assert_eq!(value[0]["duration"], 1815.0);
+        assert_eq!(value[0]["url"], "https://archive.org/details/item");
+    }
+}
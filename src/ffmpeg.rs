@@ -1,53 +1,252 @@
-use camino::Utf8PathBuf;
-use tokio::process::Command;
-use tracing::error;
+use std::{process::Stdio, time::Duration};
 
-use crate::Result;
+use camino::Utf8Path;
+use rustix::process::{kill_process, Pid, Signal};
+use tokio::process::{Child, ChildStdin, Command};
 
-fn stream_inner(concat_file: String, stream_key: String, cwd: Utf8PathBuf) -> Result<()> {
-    let destination = format!("rtmp://live-ber.twitch.tv/app/{stream_key}");
+use crate::{config::OutputConfig, Result};
 
+/// How long ffmpeg gets to close the stream or finish a skipped or paused
+/// video before it is killed.
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encoder settings shared by everything a channel streams. Every video is
+/// scaled to the same size and frame rate, so the stream continues seamlessly
+/// from one video to the next. `filter` runs before the scaling.
+fn encoder_args(output: &OutputConfig, filter: Option<&str>) -> Vec<String> {
+    let (width, height) = (output.width, output.height);
+    let scale = format!(
+        "scale={width}:{height}:force_original_aspect_ratio=decrease,\
+        pad={width}:{height}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={}",
+        output.framerate
+    );
+    let video_bitrate = output.video_bitrate.as_str();
+    [
+        "-vf",
+        &match filter {
+            Some(filter) => format!("{filter},{scale}"),
+            None => scale,
+        },
+        "-c:v",
+        "libx264",
+        "-preset",
+        &output.preset,
+        "-b:v",
+        video_bitrate,
+        "-maxrate",
+        video_bitrate,
+        "-bufsize",
+        video_bitrate,
+        "-pix_fmt",
+        "yuv420p",
+        "-g",
+        &(output.framerate * 2).to_string(),
+        "-c:a",
+        "aac",
+        "-b:a",
+        &output.audio_bitrate,
+        "-ac",
+        "2",
+        "-ar",
+        "44100",
+        // MPEG-TS can be concatenated as is, the stream process takes care of
+        // the timestamps starting over with every video
+        "-f",
+        "mpegts",
+        "pipe:1",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// The ffmpeg process that holds the connection to the streaming server. The
+/// videos are written to its input one after another, see [`spawn_video`], so
+/// the stream stays up from one video to the next.
+pub struct Stream {
+    child: Child,
+    input: ChildStdin,
+}
+
+impl Stream {
+    pub fn spawn(output: &OutputConfig, stream_key: &str) -> Result<Self> {
+        let destination = format!("{}/{stream_key}", output.destination.trim_end_matches('/'));
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-hide_banner",
+                "-nostats",
+                "-loglevel",
+                "warning",
+                "-f",
+                "mpegts",
+                "-i",
+                "pipe:0",
+                "-c",
+                "copy",
+                "-f",
+                "flv",
+                &destination,
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            // signals for the whole group, like Ctrl+C, are left to the player
+            .process_group(0)
+            .spawn()?;
+        let input = child.stdin.take().expect("stdin is piped");
+
+        Ok(Self { child, input })
+    }
+
+    /// Where the videos are written to.
+    pub fn input(&mut self) -> &mut ChildStdin {
+        &mut self.input
+    }
+
+    /// Whether ffmpeg exited, e.g. because the server closed the connection.
+    pub fn has_exited(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Ends the stream once everything written so far is sent, and kills
+    /// ffmpeg if that does not happen in time.
+    pub async fn close(mut self) {
+        drop(self.input);
+        if tokio::time::timeout(STOP_TIMEOUT, self.child.wait())
+            .await
+            .is_err()
+        {
+            let _ = self.child.kill().await;
+        }
+    }
+}
+
+/// Starts encoding the video listed in the concat file in real time, writing it
+/// to stdout for the [`Stream`]. Progress is reported on stderr in ffmpeg's
+/// `-progress` key/value format, see [`Progress`], mixed with errors.
+pub fn spawn_video(concat_file: &Utf8Path, output: &OutputConfig) -> Result<Child> {
     let process = Command::new("ffmpeg")
-        .current_dir(cwd)
-        .args(vec![
+        .args([
+            "-hide_banner",
+            "-nostats",
+            "-loglevel",
+            "error",
+            "-progress",
+            "pipe:2",
             "-re",
             "-f",
             "concat",
-            "-",
-            &concat_file,
-            "-preset",
-            "veryfast",
-            "-b:v",
-            "6000k",
-            "-maxrate",
-            "6000k",
-            "-bufsize",
-            "6000k",
-            "-pix_fmt",
-            "yuv420p",
-            "-g",
-            "50",
-            "-c:a",
-            "aac",
-            "-b:a",
-            "160k",
-            "-ac",
-            "2",
-            "-ar",
-            "44100",
+            "-safe",
+            "0",
+            "-i",
+            concat_file.as_str(),
+        ])
+        .args(encoder_args(output, None))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .process_group(0)
+        .spawn()?;
+
+    Ok(process)
+}
+
+/// Encodes a black slate with `title` and a countdown from `seconds` to zero,
+/// like [`spawn_video`] does with videos.
+pub fn spawn_countdown(seconds: f64, title: &str, output: &OutputConfig) -> Result<Child> {
+    // characters with a meaning in filter graphs or drawtext expansions
+    let title: String = title
+        .chars()
+        .filter(|c| !matches!(c, '\\' | '\'' | ':' | '%' | ',' | ';' | '[' | ']'))
+        .collect();
+    let filter = format!(
+        "drawtext=text='{title}':fontcolor=white:fontsize=48:x=(w-tw)/2:y=h/2-80,\
+        drawtext=text='%{{eif\\:trunc(({seconds:.0}-t)/3600)\\:d}}\\:%{{eif\\:mod(trunc(({seconds:.0}-t)/60)\\,60)\\:d\\:2}}\\:%{{eif\\:mod(trunc({seconds:.0}-t)\\,60)\\:d\\:2}}':\
+        fontcolor=white:fontsize=96:x=(w-tw)/2:y=h/2"
+    );
+
+    let process = Command::new("ffmpeg")
+        .args([
+            "-hide_banner",
+            "-nostats",
+            "-re",
+            "-f",
+            "lavfi",
+            "-i",
+            &format!(
+                "color=c=black:s={}x{}:r={}",
+                output.width, output.height, output.framerate
+            ),
             "-f",
-            "flv",
-            &destination,
+            "lavfi",
+            "-i",
+            "anullsrc=r=44100:cl=stereo",
+            "-t",
+            &format!("{seconds:.3}"),
         ])
+        .args(encoder_args(output, Some(&filter)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .process_group(0)
         .spawn()?;
 
-    Ok(())
+    Ok(process)
+}
+
+/// Asks ffmpeg to stop like Ctrl+C would, so that it finishes its output
+/// cleanly. Does not wait for it to exit.
+pub fn interrupt(child: &Child) {
+    if let Some(pid) = child.id().and_then(|id| Pid::from_raw(id as i32)) {
+        let _ = kill_process(pid, Signal::Int);
+    }
+}
+
+/// The latest values reported by ffmpeg's `-progress` output.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// Seconds of output written so far
+    pub out_time: f64,
+    pub frame: u64,
+    pub fps: f64,
+    /// Output bitrate in kbit/s
+    pub bitrate: f64,
+    pub drop_frames: u64,
+    pub dup_frames: u64,
+    /// Encoding speed relative to real time
+    pub speed: f64,
 }
 
-pub fn start_stream(concat_file: String, stream_key: String, cwd: Utf8PathBuf) {
-    tokio::spawn(async move {
-        if let Err(e) = stream_inner(concat_file, stream_key, cwd) {
-            error!("error starting stream: {}", e);
+impl Progress {
+    /// Applies a single `key=value` line. Returns `true` when the line ends a
+    /// progress block, i.e. all values are up to date.
+    pub fn update(&mut self, line: &str) -> bool {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            return false;
+        };
+        let value = value.trim();
+        match key {
+            "out_time_us" | "out_time_ms" => {
+                // both keys are in microseconds, out_time_ms is misnamed by ffmpeg
+                if let Ok(us) = value.parse::<f64>() {
+                    self.out_time = us / 1_000_000.0;
+                }
+            }
+            "frame" => self.frame = value.parse().unwrap_or(self.frame),
+            "fps" => self.fps = value.parse().unwrap_or(self.fps),
+            "bitrate" => {
+                self.bitrate = value
+                    .trim_end_matches("kbits/s")
+                    .parse()
+                    .unwrap_or(self.bitrate)
+            }
+            "drop_frames" => self.drop_frames = value.parse().unwrap_or(self.drop_frames),
+            "dup_frames" => self.dup_frames = value.parse().unwrap_or(self.dup_frames),
+            "speed" => self.speed = value.trim_end_matches('x').parse().unwrap_or(self.speed),
+            "progress" => return true,
+            _ => {}
         }
-    });
+        false
+    }
 }
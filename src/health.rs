@@ -0,0 +1,67 @@
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{config::HealthConfig, db::Database, player::PlayerHandle};
+
+/// The state of the checks behind `/healthz` and `/readyz`.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// Nothing is broken that a restart could fix: the database is reachable
+    /// and ffmpeg is not stuck, failing or idle for long
+    pub healthy: bool,
+    /// Healthy, the stream is live and enough videos are downloaded
+    pub ready: bool,
+    pub database: bool,
+    /// ffmpeg is streaming and its progress is advancing
+    pub streaming: bool,
+    pub paused: bool,
+    pub counting_down: bool,
+    /// Seconds since ffmpeg last reported progress or started a video
+    pub progress_age: Option<f64>,
+    /// Seconds of downloaded videos waiting to play
+    pub buffered_seconds: Option<f64>,
+    /// When the latest video that is waiting to play was downloaded
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_download_at: Option<OffsetDateTime>,
+}
+
+pub async fn check(
+    database: &Database,
+    player: &PlayerHandle,
+    config: &HealthConfig,
+) -> HealthReport {
+    let database_ok = database.ping().await.is_ok();
+    let buffered_seconds = database.buffered_seconds().await.ok();
+    let last_download_at = database.last_download_at().await.ok().flatten();
+
+    let state = player.state();
+    // a video that just started did not report progress yet
+    let progress_age = state
+        .progress_at
+        .max(state.started_at)
+        .map(|at| (OffsetDateTime::now_utc() - at).as_seconds_f64());
+    let advancing = progress_age.is_some_and(|age| age <= config.max_progress_age as f64);
+    // between two videos nothing is playing for a moment
+    let streaming = state.counting_down || advancing;
+    // a player that keeps failing or has nothing to play does not progress
+    // either, it gets more time as it may be waiting for a download
+    let idle = state.progress_at.is_some_and(|at| {
+        (OffsetDateTime::now_utc() - at).as_seconds_f64() > config.max_idle as f64
+    });
+    let stuck =
+        !state.paused && !state.counting_down && (idle || (state.video_id.is_some() && !advancing));
+
+    let healthy = database_ok && !stuck;
+    let buffered = buffered_seconds.is_some_and(|seconds| seconds >= config.min_buffer as f64);
+    HealthReport {
+        healthy,
+        ready: healthy && (streaming || state.paused) && buffered,
+        database: database_ok,
+        streaming,
+        paused: state.paused,
+        counting_down: state.counting_down,
+        progress_age,
+        buffered_seconds,
+        last_download_at,
+    }
+}
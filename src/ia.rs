@@ -1,12 +1,20 @@
 use std::time::Instant;
 
-use crate::Result;
+use crate::{
+    events::{Event, EventBus},
+    metrics::Metrics,
+    Result,
+};
 use async_stream::try_stream;
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::eyre::{Context, OptionExt};
 use futures::Stream;
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use time::{
+    format_description::well_known::Rfc3339, macros::format_description, Date, OffsetDateTime,
+    PrimitiveDateTime,
+};
 use tokio::io::AsyncWriteExt;
 use tracing::info;
 
@@ -46,6 +54,21 @@ pub struct File {
     pub summation: Option<String>,
 }
 
+impl File {
+    pub fn size_bytes(&self) -> Option<i64> {
+        self.size.as_deref()?.parse().ok()
+    }
+
+    /// Length of the file in seconds, for audio and video files.
+    pub fn length_seconds(&self) -> Option<f64> {
+        parse_runtime(self.length.as_deref()?)
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.format == "MPEG4"
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MetadataItem {
     pub collections: Option<Vec<String>>,
@@ -57,6 +80,82 @@ pub struct MetadataItem {
     pub identifier: String,
     pub item_size: Option<u64>,
     pub title: String,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub subject: Option<Vec<String>>,
+    pub runtime: Option<String>,
+}
+
+impl MetadataItem {
+    /// The original publication date. The date the item was uploaded to the
+    /// archive says nothing about when the video aired, so it is not used.
+    pub fn published_at(&self) -> Option<OffsetDateTime> {
+        self.date.as_deref().and_then(parse_date)
+    }
+
+    pub fn runtime_seconds(&self) -> Option<f64> {
+        parse_runtime(self.runtime.as_deref()?)
+    }
+
+    pub fn thumbnail_url(&self) -> String {
+        format!("https://archive.org/services/img/{}", self.identifier)
+    }
+}
+
+/// The archive returns single-valued fields as a plain string and multi-valued
+/// ones as an array.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<OneOrMany>::deserialize(deserializer)?.map(|value| match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }),
+    )
+}
+
+/// Parses the loosely formatted dates used in archive metadata, e.g.
+/// `2014-05-12T00:00:00Z`, `2014-05-12 18:30:00`, `2014-05-12` or `2014`.
+pub fn parse_date(value: &str) -> Option<OffsetDateTime> {
+    let value = value.trim();
+    if let Ok(date) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(date);
+    }
+    if let Ok(date) = PrimitiveDateTime::parse(
+        value,
+        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+    ) {
+        return Some(date.assume_utc());
+    }
+    if let Ok(date) = Date::parse(value, format_description!("[year]-[month]-[day]")) {
+        return Some(date.midnight().assume_utc());
+    }
+    let year: i32 = value.parse().ok()?;
+    Some(
+        Date::from_ordinal_date(year, 1)
+            .ok()?
+            .midnight()
+            .assume_utc(),
+    )
+}
+
+/// Parses a runtime given either as seconds (`1234.5`) or as `HH:MM:SS`/`MM:SS`.
+pub fn parse_runtime(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    value.split(':').try_fold(0.0, |total, part| {
+        part.trim().parse::<f64>().ok().map(|v| total * 60.0 + v)
+    })
 }
 
 #[derive(Debug, Deserialize)]
@@ -70,9 +169,31 @@ pub struct MetadataResponse {
 #[derive(Default, Clone)]
 pub struct InternetArchive {
     client: reqwest::Client,
+    metrics: Metrics,
+    events: EventBus,
 }
 
 impl InternetArchive {
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Publishes the progress of downloads to `events`.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Sends a request, recording how long the archive took to respond.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let start = Instant::now();
+        let response = request.send().await.and_then(|r| r.error_for_status());
+        self.metrics
+            .ia_request(start.elapsed().as_secs_f64(), response.is_err());
+        Ok(response?)
+    }
+
     pub async fn search(
         &self,
         query: &str,
@@ -89,9 +210,7 @@ impl InternetArchive {
             url.query_pairs_mut().append_pair("cursor", &cursor);
         }
 
-        self.client
-            .get(url)
-            .send()
+        self.send(self.client.get(url))
             .await
             .wrap_err("failed to fetch search results")?
             .json()
@@ -124,30 +243,57 @@ impl InternetArchive {
         let mut url = Url::parse("https://archive.org")?;
         url.set_path(&format!("/metadata/{}", identifier));
         info!("Making request to {url}");
-        self.client
-            .get(url)
-            .send()
+        self.send(self.client.get(url))
             .await?
             .json()
             .await
             .map_err(From::from)
     }
 
-    pub async fn download_video(&self, identifier: &str, folder: &Utf8Path) -> Result<Utf8PathBuf> {
+    /// Returns the path of the file and its size.
+    pub async fn download_video(
+        &self,
+        identifier: &str,
+        folder: &Utf8Path,
+    ) -> Result<(Utf8PathBuf, u64)> {
         let details = self.get_item_details(identifier).await?;
         let video_file = details
             .files
             .iter()
-            .find(|f| f.format == "MPEG4")
+            .find(|f| f.is_video())
             .ok_or_eyre("no video file found")?;
 
         let url = format!(
             "https://{}{}/{}",
             details.server, details.directory, video_file.name
         );
+        self.download_to_file(identifier, &url, folder, &video_file.name)
+            .await
+    }
 
-        let mut response = self.client.get(&url).send().await?;
-        let path = folder.join(&video_file.name);
+    /// Downloads a file whose name is already known, letting the archive redirect
+    /// to the server currently holding the item. Returns the path of the file
+    /// and its size.
+    pub async fn download_file(
+        &self,
+        identifier: &str,
+        name: &str,
+        folder: &Utf8Path,
+    ) -> Result<(Utf8PathBuf, u64)> {
+        let url = format!("https://archive.org/download/{identifier}/{name}");
+        self.download_to_file(identifier, &url, folder, name).await
+    }
+
+    /// Returns the path of the file and its size.
+    async fn download_to_file(
+        &self,
+        identifier: &str,
+        url: &str,
+        folder: &Utf8Path,
+        name: &str,
+    ) -> Result<(Utf8PathBuf, u64)> {
+        let mut response = self.send(self.client.get(url)).await?;
+        let path = folder.join(name);
         info!("Downloading from URL {url} to {path}");
 
         let mut file = tokio::fs::File::create(&path).await?;
@@ -158,10 +304,16 @@ impl InternetArchive {
         while let Some(chunk) = response.chunk().await? {
             file.write_all(&chunk).await?;
             progress += chunk.len();
+            self.metrics.bytes_downloaded(chunk.len() as u64);
             let elapsed = start.elapsed().as_secs_f64();
             let speed = progress as f64 / elapsed;
             let mb_s = speed / 1024.0 / 1024.0;
             if reads % 500 == 0 {
+                self.events.publish(Event::DownloadProgress {
+                    identifier: identifier.into(),
+                    bytes: progress as u64,
+                    total: response.content_length(),
+                });
                 info!(
                     "Downloaded {} / {} ({:.2} MB/s)",
                     format_bytes(progress as u64),
@@ -172,11 +324,11 @@ impl InternetArchive {
             reads += 1;
         }
 
-        Ok(path)
+        Ok((path, progress as u64))
     }
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
     const GB: u64 = MB * 1024;
@@ -190,3 +342,60 @@ fn format_bytes(bytes: u64) -> String {
         format!("{:.2} GB", bytes as f64 / GB as f64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn parses_archive_dates() {
+        assert_eq!(
+            parse_date("2014-05-12T18:30:00Z"),
+            Some(datetime!(2014-05-12 18:30 UTC))
+        );
+        assert_eq!(
+            parse_date("2014-05-12 18:30:00"),
+            Some(datetime!(2014-05-12 18:30 UTC))
+        );
+        assert_eq!(
+            parse_date(" 2014-05-12 "),
+            Some(datetime!(2014-05-12 0:00 UTC))
+        );
+        assert_eq!(parse_date("2014"), Some(datetime!(2014-01-01 0:00 UTC)));
+        assert_eq!(parse_date("May 2014"), None);
+    }
+
+    #[test]
+    fn parses_runtimes() {
+        assert_eq!(parse_runtime("1234.5"), Some(1234.5));
+        assert_eq!(parse_runtime("1:02:03"), Some(3723.0));
+        assert_eq!(parse_runtime("25:12"), Some(1512.0));
+        assert_eq!(parse_runtime("about an hour"), None);
+    }
+
+    #[test]
+    fn subjects_are_one_or_many() {
+        let item = |subject: serde_json::Value| {
+            serde_json::from_value::<MetadataItem>(serde_json::json!({
+                "identifier": "ql-halo-3",
+                "title": "Quick Look: Halo 3",
+                "subject": subject,
+            }))
+            .unwrap()
+            .subject
+        };
+        assert_eq!(item("Halo".into()), Some(vec!["Halo".to_owned()]));
+        assert_eq!(
+            item(serde_json::json!(["Halo", "Xbox 360"])),
+            Some(vec!["Halo".to_owned(), "Xbox 360".to_owned()])
+        );
+        assert_eq!(item(serde_json::Value::Null), None);
+
+        let item: MetadataItem =
+            serde_json::from_str(r#"{"identifier": "ql-halo-3", "title": "Quick Look: Halo 3"}"#)
+                .unwrap();
+        assert_eq!(item.subject, None);
+    }
+}
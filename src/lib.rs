@@ -1,8 +1,28 @@
+pub mod analysis;
+pub mod api;
+pub mod chat;
 pub mod config;
+pub mod dashboard;
 pub mod db;
+pub mod disk;
 pub mod downloader;
+pub mod epg;
+pub mod events;
+pub mod export;
 pub mod ffmpeg;
+pub mod health;
 pub mod ia;
+pub mod metrics;
+pub mod notifications;
+pub mod on_this_day;
+pub mod player;
+pub mod playlist;
+pub mod recent_errors;
+pub mod rules;
+pub mod schedule;
 pub mod stream;
+pub mod supervisor;
+pub mod sync;
+pub mod voting;
 
 pub type Result<T> = color_eyre::Result<T>;
@@ -1,51 +1,220 @@
+use color_eyre::eyre::bail;
 use gb_forever::{
+    api::{self, ApiState, ChannelHandle},
+    chat::ChatBot,
     config::load_config,
-    db::{Database, VideoId},
+    db::Database,
     downloader::{BackgroundDownloader, DownloadOrchestrator},
+    epg::EpgWriter,
+    events::EventBus,
     ia::InternetArchive,
+    metrics::Metrics,
+    notifications::Notifier,
+    on_this_day::OnThisDay,
+    player::Player,
+    playlist,
+    recent_errors::RecentErrors,
+    rules::ContentRules,
+    schedule::Scheduler,
+    supervisor::Supervisor,
+    sync::CatalogSync,
+    voting::Voting,
     Result,
 };
+use std::{collections::HashSet, time::Duration};
+use tracing::{info, info_span, Instrument};
 
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let _ = dotenvy::dotenv();
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+    let recent_errors = RecentErrors::default();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .with(recent_errors.clone())
         .init();
 
     let config = load_config()?;
     let database = Database::connect(&config.database_url).await?;
-    let ia = InternetArchive::default();
-    let downloader =
-        DownloadOrchestrator::new(database.clone(), ia.clone(), config.video_path.clone());
+    let supervisor = Supervisor::default();
+    let events = EventBus::default();
+    let metrics = Metrics::default();
+    metrics.start(&events, &supervisor);
+    let ia = InternetArchive::default()
+        .with_metrics(metrics.clone())
+        .with_events(events.clone());
+    let downloader = DownloadOrchestrator::new(
+        database.clone(),
+        ia.clone(),
+        config.video_path.clone(),
+        config.dead_air.clone(),
+    )
+    .with_events(events.clone());
 
     tokio::fs::create_dir_all(&config.video_path).await?;
+    let reset = database.reset_pending_downloads().await?;
+    if reset > 0 {
+        info!("downloading {reset} entries again that were cut off by a crash");
+    }
+
+    let download_sender = BackgroundDownloader::start_new(downloader, &supervisor);
+
+    let rules = ContentRules::from_config(&config.rules)?;
+    rules.apply(&database).await?;
+    if config.sync.enabled {
+        if config.sync.interval_hours == 0 {
+            bail!("sync.interval_hours has to be at least 1");
+        }
+        CatalogSync::new(database.clone(), ia.clone())
+            .with_rules(rules)
+            .start_periodic(
+                Duration::from_secs(config.sync.interval_hours * 3600),
+                &supervisor,
+            );
+    }
 
-    match database.current_video().await? {
-        Some(_) => todo!(),
-        None => {
-            // no videos downloaded yet, get the first five videos
-            let items = database.peek_next_videos(5).await?;
-            downloader
-                .download_videos(
-                    items
-                        .iter()
-                        .map(|item| VideoId::DatabaseId(item.video_id))
-                        .collect(),
-                )
-                .await?;
+    let (player, player_handle) = Player::new(
+        database.clone(),
+        download_sender.clone(),
+        config.player.clone(),
+        playlist::from_config(&config.playlist),
+        config.output.clone(),
+        config.stream_key.clone(),
+        config.video_path.join("playlist.txt"),
+    );
+    let player = player
+        .with_metrics(metrics.clone(), "main")
+        .with_events(events.clone())
+        .with_shutdown(supervisor.token());
+
+    let mut channel_names = HashSet::new();
+    let mut channels = Vec::new();
+    for channel in &config.channels {
+        if channel.name == "main" {
+            bail!("the main channel is configured at the top level, not under channels");
+        }
+        if channel.name.is_empty()
+            || !channel
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        {
+            bail!(
+                "invalid channel name {:?}, only lowercase letters, digits, - and _ are allowed",
+                channel.name
+            );
+        }
+        if !channel_names.insert(&channel.name) {
+            bail!("channel {} is configured twice", channel.name);
         }
+        let channel_database = database.channel(&channel.name).await?;
+        let (channel_player, channel_handle) = Player::new(
+            channel_database.clone(),
+            download_sender.clone(),
+            config.player.clone(),
+            playlist::from_config(&channel.playlist),
+            channel.output.clone(),
+            channel.stream_key.clone(),
+            config
+                .video_path
+                .join(format!("playlist-{}.txt", channel.name)),
+        );
+        let channel_player = channel_player
+            .with_metrics(metrics.clone(), &channel.name)
+            .with_events(events.clone())
+            .with_shutdown(supervisor.token());
+        let span = info_span!("channel", name = %channel.name);
+        supervisor.spawn_graceful(
+            format!("player of channel {}", channel.name),
+            channel_player.run().instrument(span),
+        );
+        channels.push((
+            channel.name.clone(),
+            ChannelHandle {
+                database: channel_database,
+                player: channel_handle,
+            },
+        ));
+    }
+
+    if config.schedule.enabled {
+        Scheduler::new(&config.schedule, database.clone(), player_handle.clone())?
+            .start(&supervisor);
     }
 
-    let download_sender = BackgroundDownloader::start_new(downloader);
+    if config.epg.enabled {
+        EpgWriter::new(config.epg.clone(), database.clone(), player_handle.clone())
+            .start(&supervisor);
+    }
+
+    if !config.notifications.webhooks.is_empty() {
+        Notifier::new(
+            config.notifications.clone(),
+            config.api.health.clone(),
+            database.clone(),
+            player_handle.clone(),
+            config.video_path.clone(),
+        )
+        .start(&events, &supervisor);
+    }
+
+    if config.on_this_day.enabled {
+        OnThisDay::new(database.clone()).start(&supervisor);
+    }
+
+    let chat = if config.chat.enabled {
+        let (bot, chat) = ChatBot::new(
+            config.chat.clone(),
+            config.requests.clone(),
+            database.clone(),
+            player_handle.clone(),
+        );
+        bot.start(&supervisor);
+        Some(chat)
+    } else {
+        None
+    };
+
+    if config.voting.enabled {
+        Voting::new(
+            config.voting.clone(),
+            database.clone(),
+            player_handle.clone(),
+            chat,
+        )
+        .start(&supervisor);
+    }
+
+    if config.api.enabled {
+        let state = ApiState::new(
+            &config.api,
+            database.clone(),
+            player_handle,
+            config.video_path.clone(),
+            recent_errors,
+            config.requests.clone(),
+            config.epg.clone(),
+        )?
+        .with_metrics(metrics);
+        let state = channels.into_iter().fold(state, |state, (name, channel)| {
+            state.with_channel(name, channel)
+        });
+        let bind_address = config.api.bind_address.clone();
+        let shutdown = supervisor.token();
+        supervisor.spawn_graceful("control API", async move {
+            api::serve(&bind_address, state, shutdown).await
+        });
+    }
 
-    // TODO start background job to fetch new videos in the background
     // TODO start background job to clean up old videos
-    // TODO run ffmpeg
-    // TODO update the playlist file when required
 
+    // started last so that everything subscribed to its events already
+    supervisor.spawn_graceful("player", player.run());
+    supervisor.wait_for_shutdown().await?;
+    supervisor.shutdown().await;
+    info!("stopped");
     Ok(())
 }
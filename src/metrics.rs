@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use camino::Utf8Path;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    db::Database,
+    disk,
+    events::{Event, EventBus},
+    ffmpeg::Progress,
+    supervisor::Supervisor,
+    Result,
+};
+
+/// What a single player reported.
+#[derive(Debug, Clone, Default)]
+struct ChannelStats {
+    ffmpeg_starts: u64,
+    ffmpeg_failures: u64,
+    /// Progress of the running ffmpeg process, if any
+    encoder: Option<Progress>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    downloads_started: u64,
+    downloads_succeeded: u64,
+    downloads_failed: u64,
+    bytes_downloaded: u64,
+    download_seconds: f64,
+    /// Average speed of the latest finished download, in bytes per second
+    last_throughput: Option<f64>,
+    ia_requests: u64,
+    ia_request_errors: u64,
+    ia_request_seconds: f64,
+    channels: BTreeMap<String, ChannelStats>,
+}
+
+/// Counters and gauges in the Prometheus text format, served at `/metrics`.
+/// Clones share the same values.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Metrics {
+    fn update(&self, f: impl FnOnce(&mut Counters)) {
+        f(&mut self.counters.lock().unwrap());
+    }
+
+    /// Records the published events until shutdown.
+    pub fn start(&self, events: &EventBus, supervisor: &Supervisor) {
+        let metrics = self.clone();
+        let mut events = events.subscribe();
+        supervisor.spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => metrics.record(&event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("metrics skipped {skipped} events")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    fn record(&self, event: &Event) {
+        self.update(|c| match event {
+            Event::DownloadStarted { .. } => c.downloads_started += 1,
+            Event::DownloadCompleted {
+                bytes,
+                seconds,
+                reused: false,
+                ..
+            } => {
+                c.downloads_succeeded += 1;
+                c.download_seconds += seconds;
+                c.last_throughput = (*seconds > 0.0).then(|| *bytes as f64 / seconds);
+            }
+            Event::DownloadFailed { .. } => c.downloads_failed += 1,
+            Event::StreamStarted { channel } => {
+                c.channels.entry(channel.clone()).or_default().ffmpeg_starts += 1
+            }
+            Event::StreamStopped {
+                channel,
+                error: Some(_),
+            } => {
+                c.channels
+                    .entry(channel.clone())
+                    .or_default()
+                    .ffmpeg_failures += 1
+            }
+            _ => {}
+        });
+    }
+
+    /// Bytes are counted as they arrive, so aborted downloads count as well.
+    pub fn bytes_downloaded(&self, bytes: u64) {
+        self.update(|c| c.bytes_downloaded += bytes);
+    }
+
+    pub fn ia_request(&self, seconds: f64, failed: bool) {
+        self.update(|c| {
+            c.ia_requests += 1;
+            c.ia_request_seconds += seconds;
+            if failed {
+                c.ia_request_errors += 1;
+            }
+        });
+    }
+
+    pub fn set_encoder(&self, channel: &str, progress: Option<&Progress>) {
+        self.update(|c| c.channels.entry(channel.into()).or_default().encoder = progress.cloned());
+    }
+
+    /// Renders the recorded values together with the playlist and disk gauges.
+    pub async fn render(&self, database: &Database, video_path: &Utf8Path) -> Result<String> {
+        let statuses = database.playlist_status_counts().await?;
+        let buffered = database.buffered_playtime().await?;
+        let disk_usage = disk::folder_size(video_path).await?;
+
+        let mut out = String::new();
+        let c = self.counters.lock().unwrap();
+        let single = |value: f64| vec![(String::new(), value)];
+        let per_channel = |f: &dyn Fn(&ChannelStats) -> Option<f64>| {
+            c.channels
+                .iter()
+                .filter_map(|(channel, stats)| Some((labels(&[("channel", channel)]), f(stats)?)))
+                .collect::<Vec<_>>()
+        };
+
+        #[rustfmt::skip]
+        let metrics = [
+            ("downloads_started_total", "counter", "Downloads that were started", single(c.downloads_started as f64)),
+            ("downloads_succeeded_total", "counter", "Downloads that finished", single(c.downloads_succeeded as f64)),
+            ("downloads_failed_total", "counter", "Downloads that failed", single(c.downloads_failed as f64)),
+            ("downloaded_bytes_total", "counter", "Bytes received from the archive", single(c.bytes_downloaded as f64)),
+            ("download_seconds_total", "counter", "Time spent on finished downloads", single(c.download_seconds)),
+            ("download_throughput_bytes_per_second", "gauge", "Average speed of the latest finished download", c.last_throughput.map(single).unwrap_or_default()),
+            ("ia_requests_total", "counter", "Requests to the archive", single(c.ia_requests as f64)),
+            ("ia_request_errors_total", "counter", "Requests to the archive that failed", single(c.ia_request_errors as f64)),
+            ("ia_request_duration_seconds", "summary", "Time until the archive responded", vec![("_sum".into(), c.ia_request_seconds), ("_count".into(), c.ia_requests as f64)]),
+            ("ffmpeg_starts_total", "counter", "ffmpeg processes started to stream to the server", per_channel(&|s| Some(s.ffmpeg_starts as f64))),
+            ("ffmpeg_failures_total", "counter", "ffmpeg processes streaming to the server that failed", per_channel(&|s| Some(s.ffmpeg_failures as f64))),
+            ("encoder_fps", "gauge", "Frames encoded per second", per_channel(&|s| Some(s.encoder.as_ref()?.fps))),
+            ("encoder_bitrate_kbps", "gauge", "Output bitrate in kbit/s", per_channel(&|s| Some(s.encoder.as_ref()?.bitrate))),
+            ("encoder_speed", "gauge", "Encoding speed relative to real time", per_channel(&|s| Some(s.encoder.as_ref()?.speed))),
+            ("encoder_dropped_frames", "gauge", "Frames dropped while streaming the current video", per_channel(&|s| Some(s.encoder.as_ref()?.drop_frames as f64))),
+            ("encoder_duplicated_frames", "gauge", "Frames duplicated while streaming the current video", per_channel(&|s| Some(s.encoder.as_ref()?.dup_frames as f64))),
+            ("video_path_bytes", "gauge", "Size of the downloaded videos", single(disk_usage as f64)),
+            ("playlist_entries", "gauge", "Playlist entries per channel and status", statuses.iter().map(|s| (labels(&[("channel", &s.channel), ("status", &s.status.to_string())]), s.entries as f64)).collect()),
+            ("buffered_seconds", "gauge", "Playtime of the downloaded videos waiting to play", buffered.iter().map(|(channel, seconds)| (labels(&[("channel", channel)]), *seconds)).collect()),
+        ];
+        for (name, kind, help, samples) in metrics {
+            let _ = writeln!(out, "# HELP gb_forever_{name} {help}");
+            let _ = writeln!(out, "# TYPE gb_forever_{name} {kind}");
+            // the suffix of summaries or the labels
+            for (rest, value) in samples {
+                let _ = writeln!(out, "gb_forever_{name}{rest} {value}");
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Formats label pairs as `{name="value",...}`.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('\n', r"\n");
+            format!(r#"{name}="{value}""#)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(
+            labels(&[("channel", "main"), ("status", "a \"b\"\\\nc")]),
+            r#"{channel="main",status="a \"b\"\\\nc"}"#
+        );
+    }
+
+    #[sqlx::test]
+    async fn renders_text_format(pool: sqlx::PgPool) -> Result<()> {
+        let db = Database::for_tests(pool);
+        let ids = db.insert_test_videos(2).await?;
+        db.append_to_playlist(&ids).await?;
+        let video_path = Utf8PathBuf::try_from(std::env::temp_dir())?
+            .join(format!("gb-forever-metrics-{}", std::process::id()));
+        tokio::fs::create_dir_all(&video_path).await?;
+        tokio::fs::write(video_path.join("v-1.mp4"), [0; 1000]).await?;
+
+        let metrics = Metrics::default();
+        metrics.record(&Event::DownloadCompleted {
+            identifier: "v-1".into(),
+            path: video_path.join("v-1.mp4"),
+            bytes: 1000,
+            seconds: 2.0,
+            reused: false,
+        });
+        metrics.record(&Event::StreamStarted {
+            channel: "main".into(),
+        });
+        let text = metrics.render(&db, &video_path).await;
+        tokio::fs::remove_dir_all(&video_path).await?;
+        let text = text?;
+
+        assert!(text.contains(
+            "# HELP gb_forever_downloads_succeeded_total Downloads that finished
+# TYPE gb_forever_downloads_succeeded_total counter
+gb_forever_downloads_succeeded_total 1
+"
+        ));
+        assert!(text.contains("gb_forever_download_throughput_bytes_per_second 500\n"));
+        assert!(text.contains("gb_forever_ffmpeg_starts_total{channel=\"main\"} 1\n"));
+        assert!(text.contains("gb_forever_video_path_bytes 1000\n"));
+        assert!(
+            text.contains("gb_forever_playlist_entries{channel=\"main\",status=\"unplayed\"} 2\n")
+        );
+        // no encoder is running
+        assert!(!text.contains("gb_forever_encoder_fps{"));
+        Ok(())
+    }
+}
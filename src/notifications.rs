@@ -0,0 +1,406 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use camino::Utf8PathBuf;
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde_json::json;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::{EventKind, HealthConfig, NotificationsConfig, WebhookConfig, WebhookFormat},
+    db::Database,
+    disk,
+    events::{Event, EventBus},
+    health,
+    player::{format_duration, PlayerHandle},
+    supervisor::Supervisor,
+    Result,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Messages waiting for a webhook beyond these are dropped.
+const QUEUE_SIZE: usize = 32;
+/// Longer waits the webhook asks for are cut short.
+const MAX_RETRY_AFTER: u64 = 600;
+
+/// Something that webhooks are told about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    NowPlaying {
+        title: String,
+        identifier: String,
+    },
+    /// Nothing was streamed for `seconds`
+    StreamDown {
+        seconds: u64,
+    },
+    /// Streaming again after being down for `seconds`
+    StreamRecovered {
+        seconds: u64,
+    },
+    DownloadFailed {
+        failures: u32,
+    },
+    DiskAlmostFull {
+        free_bytes: u64,
+        free_percent: f64,
+    },
+}
+
+impl Notification {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::NowPlaying { .. } => EventKind::NowPlaying,
+            Self::StreamDown { .. } => EventKind::StreamDown,
+            Self::StreamRecovered { .. } => EventKind::StreamRecovered,
+            Self::DownloadFailed { .. } => EventKind::DownloadFailed,
+            Self::DiskAlmostFull { .. } => EventKind::DiskAlmostFull,
+        }
+    }
+
+    /// Values the placeholders in templates are replaced with.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::NowPlaying { title, identifier } => vec![
+                ("title", title.clone()),
+                ("identifier", identifier.clone()),
+                ("url", format!("https://archive.org/details/{identifier}")),
+            ],
+            Self::StreamDown { seconds } | Self::StreamRecovered { seconds } => vec![
+                ("seconds", seconds.to_string()),
+                ("duration", format_duration(*seconds as f64)),
+            ],
+            Self::DownloadFailed { failures } => vec![("failures", failures.to_string())],
+            Self::DiskAlmostFull {
+                free_bytes,
+                free_percent,
+            } => vec![
+                ("free", format!("{:.1} GB", *free_bytes as f64 / 1e9)),
+                ("free_percent", format!("{free_percent:.1}")),
+            ],
+        }
+    }
+
+    fn default_template(kind: EventKind) -> &'static str {
+        match kind {
+            EventKind::NowPlaying => "Now playing: {title} <{url}>",
+            EventKind::StreamDown => "The stream has been down for {duration}",
+            EventKind::StreamRecovered => "The stream is back after {duration}",
+            EventKind::DownloadFailed => "{failures} downloads failed in a row",
+            EventKind::DiskAlmostFull => "The disk is almost full, {free} ({free_percent}%) left",
+        }
+    }
+
+    /// The message for the event, from `template` or the default one.
+    pub fn message(&self, template: Option<&str>) -> String {
+        let mut message = template
+            .unwrap_or(Self::default_template(self.kind()))
+            .to_owned();
+        for (name, value) in self.fields() {
+            message = message.replace(&format!("{{{name}}}"), &value);
+        }
+        message
+    }
+}
+
+/// The JSON body a webhook of the given format expects.
+pub fn payload(format: WebhookFormat, message: &str) -> serde_json::Value {
+    match format {
+        WebhookFormat::Discord => json!({ "content": message }),
+        WebhookFormat::Slack => json!({ "text": message }),
+    }
+}
+
+/// A webhook with the queue of messages its delivery task sends.
+struct Webhook {
+    config: WebhookConfig,
+    messages: mpsc::Sender<String>,
+}
+
+impl Webhook {
+    fn start(client: reqwest::Client, config: WebhookConfig, supervisor: &Supervisor) -> Self {
+        let (messages, receiver) = mpsc::channel(QUEUE_SIZE);
+        supervisor.spawn(deliver(client, config.clone(), receiver));
+        Self { config, messages }
+    }
+
+    fn notify(&self, notification: &Notification) {
+        let kind = notification.kind();
+        if !self.config.events.is_empty() && !self.config.events.contains(&kind) {
+            return;
+        }
+        let message = notification.message(self.config.templates.get(&kind).map(String::as_str));
+        if self.messages.try_send(message).is_err() {
+            warn!("too many messages for a webhook, dropping {kind:?}");
+        }
+    }
+}
+
+/// Sends the messages to a webhook in order, at most one every
+/// `min_interval` seconds.
+async fn deliver(
+    client: reqwest::Client,
+    config: WebhookConfig,
+    mut messages: mpsc::Receiver<String>,
+) {
+    while let Some(message) = messages.recv().await {
+        let body = payload(config.format, &message);
+        let mut attempt = 0;
+        loop {
+            match send(&client, &config.url, &body).await {
+                Ok(()) => {
+                    debug!("sent webhook message: {message}");
+                    break;
+                }
+                Err(e) if attempt < config.retries => {
+                    attempt += 1;
+                    let delay = e
+                        .downcast_ref::<RateLimited>()
+                        .map_or(Duration::from_secs(1 << attempt), |r| r.retry_after);
+                    warn!(
+                        "sending webhook message failed, retrying in {:.1}s: {e}",
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    error!("sending webhook message failed: {e}");
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(config.min_interval)).await;
+    }
+}
+
+/// Returned when a webhook asks to wait before sending more messages.
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited for {:.1}s", self.retry_after.as_secs_f64())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+async fn send(client: &reqwest::Client, url: &str, body: &serde_json::Value) -> Result<()> {
+    let response = client.post(url).json(body).send().await?;
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        // whole seconds, the date form is not supported
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.trim().parse::<u64>().ok())
+            .unwrap_or(1);
+        return Err(RateLimited {
+            retry_after: Duration::from_secs(retry_after.min(MAX_RETRY_AFTER)),
+        }
+        .into());
+    }
+    response.error_for_status()?;
+    Ok(())
+}
+
+/// What was already reported, so every change is reported once.
+#[derive(Default)]
+struct Reported {
+    /// Downloads that failed since the last one that completed
+    download_failures: u32,
+    /// When the stream was first seen down
+    down_since: Option<Instant>,
+    stream_down: bool,
+    download_failed: bool,
+    disk_almost_full: bool,
+}
+
+/// Watches the main channel and tells the configured webhooks when videos
+/// start, the stream goes down or comes back, downloads keep failing or the
+/// disk fills up.
+pub struct Notifier {
+    config: NotificationsConfig,
+    health: HealthConfig,
+    database: Database,
+    player: PlayerHandle,
+    video_path: Utf8PathBuf,
+}
+
+impl Notifier {
+    pub fn new(
+        config: NotificationsConfig,
+        health: HealthConfig,
+        database: Database,
+        player: PlayerHandle,
+        video_path: Utf8PathBuf,
+    ) -> Self {
+        Self {
+            config,
+            health,
+            database,
+            player,
+            video_path,
+        }
+    }
+
+    pub fn start(self, events: &EventBus, supervisor: &Supervisor) {
+        let client = reqwest::Client::new();
+        let webhooks: Vec<_> = self
+            .config
+            .webhooks
+            .iter()
+            .map(|config| Webhook::start(client.clone(), config.clone(), supervisor))
+            .collect();
+        info!("sending notifications to {} webhooks", webhooks.len());
+
+        let mut events = events.subscribe();
+        supervisor.spawn(async move {
+            let mut reported = Reported::default();
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                let notifications = tokio::select! {
+                    _ = interval.tick() => Ok(self.check(&mut reported).await),
+                    event = events.recv() => match event {
+                        Ok(event) => self.handle(event, &mut reported).await,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("notifications skipped {skipped} events");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                match notifications {
+                    Ok(notifications) => {
+                        for notification in notifications {
+                            info!("notifying about {notification:?}");
+                            for webhook in &webhooks {
+                                webhook.notify(&notification);
+                            }
+                        }
+                    }
+                    Err(e) => error!("checking for notifications failed: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Reports videos starting on the main channel and downloads failing
+    /// repeatedly.
+    async fn handle(&self, event: Event, reported: &mut Reported) -> Result<Vec<Notification>> {
+        let mut notifications = vec![];
+        match event {
+            Event::EntryStarted {
+                channel, video_id, ..
+            } if channel == "main" => {
+                if let Some(video) = self.database.fetch_videos(&[video_id]).await?.pop() {
+                    notifications.push(Notification::NowPlaying {
+                        title: video.title,
+                        identifier: video.identifier,
+                    });
+                }
+            }
+            Event::DownloadFailed { .. } => {
+                reported.download_failures += 1;
+                if reported.download_failures >= self.config.download_failures.max(1)
+                    && !reported.download_failed
+                {
+                    reported.download_failed = true;
+                    notifications.push(Notification::DownloadFailed {
+                        failures: reported.download_failures,
+                    });
+                }
+            }
+            Event::DownloadCompleted { .. } => {
+                reported.download_failures = 0;
+                reported.download_failed = false;
+            }
+            _ => {}
+        }
+
+        Ok(notifications)
+    }
+
+    /// Reports the stream going down or coming back and the disk filling up.
+    async fn check(&self, reported: &mut Reported) -> Vec<Notification> {
+        let mut notifications = vec![];
+
+        let report = health::check(&self.database, &self.player, &self.health).await;
+        if report.streaming || report.paused {
+            if let Some(since) = reported.down_since.take() {
+                if reported.stream_down {
+                    notifications.push(Notification::StreamRecovered {
+                        seconds: since.elapsed().as_secs(),
+                    });
+                }
+            }
+            reported.stream_down = false;
+        } else {
+            let since = *reported.down_since.get_or_insert_with(Instant::now);
+            if !reported.stream_down && since.elapsed().as_secs() >= self.config.stream_down_after {
+                reported.stream_down = true;
+                notifications.push(Notification::StreamDown {
+                    seconds: since.elapsed().as_secs(),
+                });
+            }
+        }
+
+        match disk::free_space(&self.video_path) {
+            Ok((free_bytes, total_bytes)) => {
+                self.check_disk(free_bytes, total_bytes, reported, &mut notifications)
+            }
+            // the stream notifications still go out
+            Err(e) => error!("checking the free disk space failed: {e}"),
+        }
+
+        notifications
+    }
+
+    fn check_disk(
+        &self,
+        free_bytes: u64,
+        total_bytes: u64,
+        reported: &mut Reported,
+        notifications: &mut Vec<Notification>,
+    ) {
+        let free_percent = free_bytes as f64 / total_bytes.max(1) as f64 * 100.0;
+        if free_percent < self.config.min_free_disk_percent {
+            if !reported.disk_almost_full {
+                reported.disk_almost_full = true;
+                notifications.push(Notification::DiskAlmostFull {
+                    free_bytes,
+                    free_percent,
+                });
+            }
+        } else {
+            reported.disk_almost_full = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_and_payloads() {
+        let event = Notification::StreamRecovered { seconds: 125 };
+        assert_eq!(event.message(None), "The stream is back after 0:02:05");
+
+        let event = Notification::NowPlaying {
+            title: "Quick Look: Halo 3".into(),
+            identifier: "ql-halo-3".into(),
+        };
+        let message = event.message(Some("**{title}** ({identifier}) {unknown}"));
+        assert_eq!(message, "**Quick Look: Halo 3** (ql-halo-3) {unknown}");
+        assert_eq!(
+            payload(WebhookFormat::Slack, &message),
+            json!({ "text": message })
+        );
+    }
+}
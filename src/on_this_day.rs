@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use time::{Date, Month, OffsetDateTime, Time, UtcOffset};
+use tracing::{error, info};
+
+use crate::{db::Database, supervisor::Supervisor, Result};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Name of the schedule slot that records that a day was already filled.
+const SLOT_NAME: &str = "on this day";
+
+/// How many years before `today` a video published at `published_at` was
+/// first published, if that was on the same day and month. Videos with only a
+/// year as their date are published at the start of that year and never match,
+/// like in [`Database::anniversary_videos`].
+pub fn years_ago(published_at: Option<OffsetDateTime>, today: Date) -> Option<i32> {
+    let published = published_at?.to_offset(UtcOffset::UTC);
+    if (published.month(), published.day(), published.time()) == (Month::January, 1, Time::MIDNIGHT)
+    {
+        return None;
+    }
+    let published = published.date();
+    let years = today.year() - published.year();
+    (years > 0 && (published.month(), published.day()) == (today.month(), today.day()))
+        .then_some(years)
+}
+
+/// Puts the videos that were first published on today's date in earlier years
+/// at the front of the playlist once a day, after the videos that are already
+/// downloaded. The shuffled playlist continues after them.
+pub struct OnThisDay {
+    database: Database,
+}
+
+impl OnThisDay {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+
+    pub fn start(self, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.check().await {
+                    error!("queueing today's anniversary videos failed: {e}");
+                }
+            }
+        });
+    }
+
+    async fn check(&self) -> Result<()> {
+        let today = OffsetDateTime::now_utc().date();
+        let starts_at = today.midnight().assume_utc();
+        let ends_at = starts_at + time::Duration::days(1);
+        if self
+            .database
+            .schedule_slot_filled(SLOT_NAME, starts_at)
+            .await?
+        {
+            return Ok(());
+        }
+
+        let ids: Vec<_> = self
+            .database
+            .anniversary_videos(today)
+            .await?
+            .into_iter()
+            .map(|video| video.id)
+            .collect();
+        // the downloaded videos play first, so there is time to download these
+        let slot = self.database.download_queue().await?.len() as i64;
+        if let Some(queued) = self
+            .database
+            .fill_schedule_slot(SLOT_NAME, starts_at, ends_at, &ids, slot)
+            .await?
+        {
+            info!("queued {} videos first published on {today}", queued.len());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::{date, datetime};
+
+    use super::*;
+
+    #[test]
+    fn years_ago_matches_day_and_month() {
+        let today = date!(2025 - 06 - 05);
+        assert_eq!(
+            years_ago(Some(datetime!(2010-06-05 0:00 UTC)), today),
+            Some(15)
+        );
+        assert_eq!(
+            years_ago(Some(datetime!(2010-06-05 18:00 UTC)), today),
+            Some(15)
+        );
+        assert_eq!(years_ago(Some(datetime!(2010-06-06 0:00 UTC)), today), None);
+        assert_eq!(years_ago(Some(datetime!(2025-06-05 0:00 UTC)), today), None);
+        assert_eq!(years_ago(None, today), None);
+
+        // only the year is known
+        let new_year = date!(2025 - 01 - 01);
+        assert_eq!(
+            years_ago(Some(datetime!(2010-01-01 0:00 UTC)), new_year),
+            None
+        );
+        assert_eq!(
+            years_ago(Some(datetime!(2010-01-01 18:00 UTC)), new_year),
+            Some(15)
+        );
+    }
+}
@@ -0,0 +1,701 @@
+use std::{collections::HashMap, time::Duration};
+
+use camino::Utf8PathBuf;
+use color_eyre::eyre::bail;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Child,
+    sync::{broadcast, mpsc, watch},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{OutputConfig, PlayerConfig},
+    db::{Database, PlaylistEntry, PlaylistEntryStatus, Premiere, VideoId},
+    events::{Event, EventBus},
+    ffmpeg::{self, Progress, Stream, STOP_TIMEOUT},
+    metrics::Metrics,
+    playlist::PlaylistStrategy,
+    stream::{ConcatEntry, ConcatFile},
+    Result,
+};
+
+/// How many upcoming entries are considered to fill the time before a premiere.
+const PREMIERE_LOOKAHEAD: i64 = 20;
+
+#[derive(Debug)]
+pub enum PlayerCommand {
+    Skip { reason: String },
+    Pause,
+    Resume,
+}
+
+/// What the player is doing right now.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlayerState {
+    pub paused: bool,
+    pub entry_id: Option<i64>,
+    pub video_id: Option<i64>,
+    /// Seconds into the current video
+    pub position: f64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started_at: Option<OffsetDateTime>,
+    /// When ffmpeg last reported progress, or when the player started, resumed
+    /// or finished a countdown. Kept after the video ends.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub progress_at: Option<OffsetDateTime>,
+    /// Set while the countdown to a premiere streams
+    pub counting_down: bool,
+}
+
+/// Formats seconds as `H:MM:SS`.
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/// Cheap to clone handle to control a running [`Player`].
+#[derive(Clone)]
+pub struct PlayerHandle {
+    commands: mpsc::Sender<PlayerCommand>,
+    state: watch::Receiver<PlayerState>,
+}
+
+impl PlayerHandle {
+    pub async fn skip(&self, reason: impl Into<String>) -> Result<()> {
+        self.send(PlayerCommand::Skip {
+            reason: reason.into(),
+        })
+        .await
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.send(PlayerCommand::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.send(PlayerCommand::Resume).await
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state.borrow().clone()
+    }
+
+    async fn send(&self, command: PlayerCommand) -> Result<()> {
+        self.commands.send(command).await?;
+        Ok(())
+    }
+}
+
+/// How playback of a single video ended.
+enum PlaybackEnd {
+    Completed,
+    Skipped(String),
+    Paused,
+    /// The video could not be played
+    Failed(String),
+    /// The stream broke down, which is not the video's fault
+    StreamFailed(String),
+    /// The process is shutting down
+    Stopped,
+}
+
+/// Plays the playlist entry by entry and keeps the next few videos downloaded.
+/// A single ffmpeg process streams to the server, see [`Stream`], and is fed
+/// one video after another.
+pub struct Player {
+    database: Database,
+    downloads: mpsc::Sender<Vec<VideoId>>,
+    config: PlayerConfig,
+    strategy: Box<dyn PlaylistStrategy>,
+    output: OutputConfig,
+    stream_key: String,
+    concat_path: Utf8PathBuf,
+    commands: mpsc::Receiver<PlayerCommand>,
+    state: watch::Sender<PlayerState>,
+    /// Videos whose download was requested, and when
+    requested: HashMap<i64, Instant>,
+    /// Entries whose playback failed, and how often
+    failures: HashMap<i64, u32>,
+    /// Running while videos play, stopped while paused or idle
+    stream: Option<Stream>,
+    metrics: Metrics,
+    events: EventBus,
+    /// Subscribed to `events` to start playing as soon as a download completes
+    downloads_completed: broadcast::Receiver<Event>,
+    /// Name of the channel in the metrics and events
+    channel: String,
+    /// Playback stops once this is cancelled
+    shutdown: CancellationToken,
+}
+
+impl Player {
+    pub fn new(
+        database: Database,
+        downloads: mpsc::Sender<Vec<VideoId>>,
+        config: PlayerConfig,
+        strategy: Box<dyn PlaylistStrategy>,
+        output: OutputConfig,
+        stream_key: String,
+        concat_path: Utf8PathBuf,
+    ) -> (Self, PlayerHandle) {
+        let (command_tx, command_rx) = mpsc::channel(16);
+        let (state_tx, state_rx) = watch::channel(PlayerState::default());
+        let events = EventBus::default();
+        let player = Self {
+            database,
+            downloads,
+            config,
+            strategy,
+            output,
+            stream_key,
+            concat_path,
+            commands: command_rx,
+            state: state_tx,
+            requested: HashMap::new(),
+            failures: HashMap::new(),
+            stream: None,
+            metrics: Metrics::default(),
+            downloads_completed: events.subscribe(),
+            events,
+            channel: "main".into(),
+            shutdown: CancellationToken::new(),
+        };
+        let handle = PlayerHandle {
+            commands: command_tx,
+            state: state_rx,
+        };
+        (player, handle)
+    }
+
+    /// Reports stream restarts and encoder stats as the channel `channel`.
+    pub fn with_metrics(mut self, metrics: Metrics, channel: impl Into<String>) -> Self {
+        self.metrics = metrics;
+        self.channel = channel.into();
+        self
+    }
+
+    /// Publishes when entries and the stream start and stop, and listens for
+    /// completed downloads.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.downloads_completed = events.subscribe();
+        self.events = events;
+        self
+    }
+
+    /// Stops playback when `shutdown` is cancelled, saving the position so
+    /// the next start resumes from it.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        // an entry that is still active was cut off by a crash or restart
+        self.database.interrupt_current_video("restart").await?;
+        self.state
+            .send_modify(|s| s.progress_at = Some(OffsetDateTime::now_utc()));
+        // set when the active entry should be finished as skipped instead of completed
+        let mut skip_reason: Option<String> = None;
+
+        while !self.shutdown.is_cancelled() {
+            if self.state.borrow().paused {
+                self.stop_stream().await;
+                self.wait_for_resume().await;
+                continue;
+            }
+
+            self.request_downloads().await?;
+            if let Some(premiere) = self.prepare_premiere().await? {
+                // nothing fits into the time before the premiere
+                self.database
+                    .finish_current_video(skip_reason.take().as_deref())
+                    .await?;
+                if let Err(e) = self.countdown(&premiere).await {
+                    error!("countdown to premiere {} failed: {e}", premiere.id);
+                    self.wait_before_retry().await;
+                }
+                continue;
+            }
+            // finishes the active entry, if any, and activates the next one
+            let next = match skip_reason.take() {
+                Some(reason) => self.database.skip_to_next_video(&reason).await?,
+                None => self.database.move_to_next_video().await?,
+            };
+            let Some(entry) = next else {
+                self.stop_stream().await;
+                self.refill_playlist().await?;
+                self.idle().await;
+                continue;
+            };
+            self.database.mark_premiere_started(entry.id).await?;
+
+            match self.play(&entry).await {
+                Ok(PlaybackEnd::Completed) => {
+                    info!("finished playing entry {}", entry.id);
+                    self.failures.remove(&entry.id);
+                }
+                Ok(PlaybackEnd::Skipped(reason)) => {
+                    info!("skipping entry {}: {reason}", entry.id);
+                    skip_reason = Some(reason);
+                }
+                Ok(PlaybackEnd::Paused) => {
+                    info!("pausing playback of entry {}", entry.id);
+                    self.database.interrupt_current_video("paused").await?;
+                }
+                Ok(PlaybackEnd::Failed(reason)) => {
+                    let attempts = self.failures.entry(entry.id).or_default();
+                    *attempts += 1;
+                    warn!(
+                        "playback of entry {} failed ({attempts} times): {reason}",
+                        entry.id
+                    );
+                    if *attempts >= self.config.max_attempts {
+                        // a broken file would otherwise be retried forever
+                        skip_reason = Some(format!("failed {attempts} times: {reason}"));
+                        self.failures.remove(&entry.id);
+                    } else {
+                        self.database.interrupt_current_video(&reason).await?;
+                        self.wait_before_retry().await;
+                    }
+                }
+                Ok(PlaybackEnd::StreamFailed(reason)) => {
+                    warn!("stream failed while playing entry {}: {reason}", entry.id);
+                    self.database.interrupt_current_video(&reason).await?;
+                    self.wait_before_retry().await;
+                }
+                Ok(PlaybackEnd::Stopped) => {
+                    info!("stopped playback of entry {}", entry.id);
+                    self.database.interrupt_current_video("shutdown").await?;
+                }
+                Err(e) => {
+                    error!("failed to play entry {}: {e}", entry.id);
+                    self.database
+                        .interrupt_current_video(&e.to_string())
+                        .await?;
+                    self.wait_before_retry().await;
+                }
+            }
+        }
+
+        self.stop_stream().await;
+        info!("player stopped");
+        Ok(())
+    }
+
+    /// The running stream, which is started if it is not running.
+    fn take_stream(&mut self) -> Result<Stream> {
+        if let Some(mut stream) = self.stream.take() {
+            if !stream.has_exited() {
+                return Ok(stream);
+            }
+            self.events.publish(Event::StreamStopped {
+                channel: self.channel.clone(),
+                error: Some("ffmpeg exited".into()),
+            });
+        }
+        info!("starting the stream");
+        let stream = Stream::spawn(&self.output, &self.stream_key)?;
+        self.events.publish(Event::StreamStarted {
+            channel: self.channel.clone(),
+        });
+        Ok(stream)
+    }
+
+    async fn close_stream(&self, stream: Stream, error: Option<String>) {
+        info!("stopping the stream");
+        stream.close().await;
+        self.events.publish(Event::StreamStopped {
+            channel: self.channel.clone(),
+            error,
+        });
+    }
+
+    /// Ends the stream while nothing plays.
+    async fn stop_stream(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.close_stream(stream, None).await;
+        }
+    }
+
+    fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.config.retry_delay)
+    }
+
+    async fn wait_before_retry(&self) {
+        tokio::select! {
+            _ = tokio::time::sleep(self.retry_delay()) => {}
+            _ = self.shutdown.cancelled() => {}
+        }
+    }
+
+    /// Starts the next run through the catalog once every entry has played.
+    async fn refill_playlist(&self) -> Result<()> {
+        if !self.database.upcoming_entries(1).await?.is_empty() {
+            return Ok(());
+        }
+        let order = self
+            .strategy
+            .order(self.database.playlist_candidates().await?);
+        if order.is_empty() {
+            warn!("the playlist strategy picked no videos");
+            return Ok(());
+        }
+        let queued = self.database.append_to_playlist(&order).await?;
+        info!("playlist ran out, queued {queued} videos");
+
+        Ok(())
+    }
+
+    /// Makes sure the next few entries are downloaded or being downloaded.
+    async fn request_downloads(&mut self) -> Result<()> {
+        let upcoming = self
+            .database
+            .upcoming_entries(self.config.download_buffer as i64)
+            .await?;
+        self.requested
+            .retain(|id, _| upcoming.iter().any(|e| e.video_id == *id));
+
+        // an entry that is still unplayed a while after it was requested had its
+        // download fail, so it is requested again
+        let retry_after = Duration::from_secs(self.config.download_retry);
+        let mut ids = vec![];
+        for entry in &upcoming {
+            if entry.status != PlaylistEntryStatus::Unplayed {
+                continue;
+            }
+            let due = self
+                .requested
+                .get(&entry.video_id)
+                .is_none_or(|at| at.elapsed() >= retry_after);
+            if due {
+                self.requested.insert(entry.video_id, Instant::now());
+                ids.push(entry.video_id);
+            }
+        }
+
+        if !ids.is_empty() {
+            info!("requesting download of {} videos", ids.len());
+            self.downloads
+                .send(ids.into_iter().map(VideoId::DatabaseId).collect())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Seconds left to play of an entry, if its duration is known.
+    async fn remaining_duration(&self, entry: &PlaylistEntry) -> Result<Option<f64>> {
+        let end = self.database.playback_end(entry.video_id).await?;
+        let progress = entry.last_progress.unwrap_or_default() as f64;
+        Ok(end.map(|end| (end - progress).max(0.0)))
+    }
+
+    /// Arranges the playlist so the next premiere starts on time: it moves to the
+    /// front once it is due, and before that only videos that end in time are
+    /// played. Returns the premiere if no downloaded video fits into the gap.
+    async fn prepare_premiere(&self) -> Result<Option<Premiere>> {
+        let lead_time = self.config.premiere_lead_time as f64;
+        let Some(premiere) = self.database.queue_next_premiere(lead_time).await? else {
+            return Ok(None);
+        };
+        let gap = (premiere.starts_at - OffsetDateTime::now_utc()).as_seconds_f64();
+        if gap <= 0.0 {
+            self.database.enqueue_at(premiere.video_id, Some(0)).await?;
+            return Ok(None);
+        }
+
+        let upcoming: Vec<_> = self
+            .database
+            .upcoming_entries(PREMIERE_LOOKAHEAD)
+            .await?
+            .into_iter()
+            .filter(|e| Some(e.id) != premiere.entry_id)
+            .collect();
+        let mut best_fit: Option<(&PlaylistEntry, f64)> = None;
+        for (index, entry) in upcoming.iter().enumerate() {
+            let Some(duration) = self.remaining_duration(entry).await? else {
+                continue;
+            };
+            if duration > gap {
+                continue;
+            }
+            if index == 0 {
+                // the next video ends in time anyway, the premiere entry in
+                // front of it is held back until it is due
+                return Ok(None);
+            }
+            let downloaded = entry.status == PlaylistEntryStatus::Downloaded;
+            if downloaded && best_fit.is_none_or(|(_, best)| duration > best) {
+                best_fit = Some((entry, duration));
+            }
+        }
+
+        match best_fit {
+            Some((entry, duration)) => {
+                info!(
+                    "playing entry {} ({duration:.0}s) to fill the {gap:.0}s before premiere {}",
+                    entry.id, premiere.id
+                );
+                self.database.enqueue_at(entry.video_id, Some(0)).await?;
+                Ok(None)
+            }
+            None => Ok(Some(premiere)),
+        }
+    }
+
+    /// Streams a countdown until the premiere starts.
+    async fn countdown(&mut self, premiere: &Premiere) -> Result<()> {
+        let seconds = (premiere.starts_at - OffsetDateTime::now_utc()).as_seconds_f64();
+        if seconds <= 0.0 {
+            return Ok(());
+        }
+        info!("counting down {seconds:.0}s to premiere {}", premiere.id);
+        let title = format!("Premiere: {}", premiere.title);
+        let mut child = ffmpeg::spawn_countdown(seconds, &title, &self.output)?;
+        let mut stream = self.take_stream()?;
+        self.state.send_modify(|s| s.counting_down = true);
+        let result = self.feed_countdown(&mut child, &mut stream).await;
+        self.state.send_modify(|s| {
+            s.counting_down = false;
+            s.progress_at = Some(OffsetDateTime::now_utc());
+        });
+        // a no-op if ffmpeg already exited
+        let _ = child.kill().await;
+        match &result {
+            Ok(()) => self.stream = Some(stream),
+            Err(e) => self.close_stream(stream, Some(e.to_string())).await,
+        }
+        result
+    }
+
+    async fn feed_countdown(&mut self, child: &mut Child, stream: &mut Stream) -> Result<()> {
+        let mut output = child.stdout.take().expect("stdout is piped");
+        let copy = tokio::io::copy(&mut output, stream.input());
+        tokio::pin!(copy);
+        loop {
+            tokio::select! {
+                copied = &mut copy => {
+                    copied?;
+                    let status = child.wait().await?;
+                    if !status.success() {
+                        bail!("ffmpeg exited with {status}");
+                    }
+                    return Ok(());
+                }
+                Some(command) = self.commands.recv() => match command {
+                    PlayerCommand::Pause => {
+                        self.state.send_modify(|s| s.paused = true);
+                        return Ok(());
+                    }
+                    command => info!("ignoring {command:?} during the countdown"),
+                },
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Waits a bit while the next video is not downloaded yet, or until a
+    /// download completes.
+    async fn idle(&mut self) {
+        // downloads that completed while playing are already in the playlist
+        self.downloads_completed = self.downloads_completed.resubscribe();
+        let sleep = tokio::time::sleep(self.retry_delay());
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return,
+                Ok(event) = self.downloads_completed.recv() => {
+                    if matches!(event, Event::DownloadCompleted { .. }) {
+                        return;
+                    }
+                }
+                Some(command) = self.commands.recv() => match command {
+                    PlayerCommand::Pause => {
+                        self.state.send_modify(|s| s.paused = true);
+                        return;
+                    }
+                    command => info!("ignoring {command:?}, nothing is playing"),
+                },
+                _ = self.shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    async fn wait_for_resume(&mut self) {
+        loop {
+            let command = tokio::select! {
+                Some(command) = self.commands.recv() => command,
+                _ = self.shutdown.cancelled() => return,
+            };
+            match command {
+                PlayerCommand::Resume => {
+                    self.state.send_modify(|s| {
+                        s.paused = false;
+                        s.progress_at = Some(OffsetDateTime::now_utc());
+                    });
+                    return;
+                }
+                command => info!("ignoring {command:?} while paused"),
+            }
+        }
+    }
+
+    async fn play(&mut self, entry: &PlaylistEntry) -> Result<PlaybackEnd> {
+        let Some(file_path) = entry.file_path.clone() else {
+            return Ok(PlaybackEnd::Skipped("no downloaded file".into()));
+        };
+        let trim = self.database.video_trim(entry.video_id).await?;
+        let mut concat_entry = ConcatEntry::with_trim(file_path.into(), trim.as_ref());
+        // resume where an interrupted playback stopped
+        if let Some(progress) = entry.last_progress.filter(|p| *p > 0) {
+            let progress = progress as f64;
+            if concat_entry
+                .inpoint
+                .is_none_or(|inpoint| inpoint < progress)
+            {
+                concat_entry.inpoint = Some(progress);
+            }
+        }
+        let offset = concat_entry.inpoint.unwrap_or_default();
+
+        let mut concat = ConcatFile::new(self.concat_path.clone());
+        concat.append_video(concat_entry).await?;
+
+        info!("starting playback of entry {} at {offset:.0}s", entry.id);
+        let mut video = ffmpeg::spawn_video(concat.path(), &self.output)?;
+        let mut stream = self.take_stream()?;
+        self.events.publish(Event::EntryStarted {
+            channel: self.channel.clone(),
+            entry_id: entry.id,
+            video_id: entry.video_id,
+        });
+        self.state.send_modify(|s| {
+            s.entry_id = Some(entry.id);
+            s.video_id = Some(entry.video_id);
+            s.position = offset;
+            s.started_at = Some(OffsetDateTime::now_utc());
+        });
+
+        let mut output = video.stdout.take().expect("stdout is piped");
+        let stderr = video.stderr.take().expect("stderr is piped");
+        let mut lines = BufReader::new(stderr).lines();
+        let mut lines_open = true;
+        let mut progress = Progress::default();
+        let progress_interval = Duration::from_secs(self.config.progress_interval);
+        let mut last_saved = Instant::now();
+        // set once the video was asked to stop early, it is fed to the stream
+        // until ffmpeg finished it
+        let mut ending = None;
+        let (mut interrupted, mut killed) = (false, false);
+        let kill = tokio::time::sleep(STOP_TIMEOUT);
+        tokio::pin!(kill);
+
+        let end = {
+            let copy = tokio::io::copy(&mut output, stream.input());
+            tokio::pin!(copy);
+            loop {
+                tokio::select! {
+                    copied = &mut copy => {
+                        if let Err(e) = copied {
+                            break PlaybackEnd::StreamFailed(format!("feeding the stream failed: {e}"));
+                        }
+                        let status = video.wait().await;
+                        break match (ending.take(), status) {
+                            (Some(end), _) => end,
+                            (None, Ok(status)) if status.success() => PlaybackEnd::Completed,
+                            (None, _) if self.shutdown.is_cancelled() => PlaybackEnd::Stopped,
+                            (None, Ok(status)) => {
+                                PlaybackEnd::Failed(format!("ffmpeg exited with {status}"))
+                            }
+                            (None, Err(e)) => PlaybackEnd::Failed(e.to_string()),
+                        };
+                    }
+                    line = lines.next_line(), if lines_open => match line {
+                        Ok(Some(line)) => {
+                            if progress.update(&line) {
+                                self.metrics.set_encoder(&self.channel, Some(&progress));
+                                let position = offset + progress.out_time;
+                                self.state.send_modify(|s| {
+                                    s.position = position;
+                                    s.progress_at = Some(OffsetDateTime::now_utc());
+                                });
+                                if last_saved.elapsed() >= progress_interval {
+                                    if let Err(e) = self
+                                        .database
+                                        .set_entry_progress(entry.id, position as i32)
+                                        .await
+                                    {
+                                        warn!("failed to save the position of entry {}: {e}", entry.id);
+                                    }
+                                    last_saved = Instant::now();
+                                }
+                            } else if !line.contains('=') {
+                                warn!("ffmpeg: {line}");
+                            }
+                        }
+                        Ok(None) | Err(_) => lines_open = false,
+                    },
+                    Some(command) = self.commands.recv(), if ending.is_none() => match command {
+                        PlayerCommand::Skip { reason } => ending = Some(PlaybackEnd::Skipped(reason)),
+                        PlayerCommand::Pause => {
+                            self.state.send_modify(|s| s.paused = true);
+                            ending = Some(PlaybackEnd::Paused);
+                        }
+                        PlayerCommand::Resume => {}
+                    },
+                    _ = self.shutdown.cancelled(), if ending.is_none() => {
+                        ending = Some(PlaybackEnd::Stopped);
+                    }
+                    _ = &mut kill, if interrupted && !killed => {
+                        let _ = video.kill().await;
+                        killed = true;
+                    }
+                }
+                // lets ffmpeg finish what it encoded, so the stream does not end
+                // in the middle of a frame
+                if ending.is_some() && !interrupted {
+                    ffmpeg::interrupt(&video);
+                    kill.as_mut().reset(Instant::now() + STOP_TIMEOUT);
+                    interrupted = true;
+                }
+            }
+        };
+
+        // a no-op if ffmpeg already exited
+        let _ = video.kill().await;
+        match &end {
+            PlaybackEnd::StreamFailed(reason) => {
+                self.close_stream(stream, Some(reason.clone())).await
+            }
+            _ => self.stream = Some(stream),
+        }
+        self.metrics.set_encoder(&self.channel, None);
+        let position = self.state.borrow().position;
+        self.database
+            .set_entry_progress(entry.id, position as i32)
+            .await?;
+        self.events.publish(Event::EntryFinished {
+            channel: self.channel.clone(),
+            entry_id: entry.id,
+            video_id: entry.video_id,
+            position,
+        });
+        self.state.send_modify(|s| {
+            s.entry_id = None;
+            s.video_id = None;
+            s.position = 0.0;
+            s.started_at = None;
+        });
+
+        Ok(end)
+    }
+}
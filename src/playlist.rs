@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use rand::{seq::SliceRandom, Rng};
+use time::OffsetDateTime;
+
+use crate::{
+    config::{CatalogFilter, PlaylistConfig, SeriesConfig, StrategyKind},
+    db::CatalogVideo,
+};
+
+/// Decides which videos play in one run through the playlist, and in which
+/// order.
+pub trait PlaylistStrategy: Send + Sync {
+    /// Orders the videos that may play. Videos that are left out do not play
+    /// in this run.
+    fn order(&self, videos: Vec<CatalogVideo>) -> Vec<i64>;
+}
+
+/// Builds the strategy selected in the config.
+pub fn from_config(config: &PlaylistConfig) -> Box<dyn PlaylistStrategy> {
+    let series = config.series.keep_together.then(|| config.series.clone());
+    let strategy: Box<dyn PlaylistStrategy> = match config.strategy {
+        StrategyKind::Shuffle => Box::new(Shuffle { series }),
+        StrategyKind::Chronological => Box::new(Chronological),
+        StrategyKind::Weighted => Box::new(WeightedShuffle { series }),
+    };
+    if config.filter.is_empty() {
+        return strategy;
+    }
+
+    Box::new(Filtered {
+        filter: config.filter.clone(),
+        inner: strategy,
+    })
+}
+
+/// Random order. With a series config, the parts of a series play in order,
+/// all at once or in chunks.
+pub struct Shuffle {
+    pub series: Option<SeriesConfig>,
+}
+
+impl PlaylistStrategy for Shuffle {
+    fn order(&self, videos: Vec<CatalogVideo>) -> Vec<i64> {
+        let mut units = units(videos, self.series.as_ref());
+        units.shuffle(&mut rand::thread_rng());
+        flatten(units)
+    }
+}
+
+/// Oldest first. Videos without a publication date come last.
+pub struct Chronological;
+
+impl PlaylistStrategy for Chronological {
+    fn order(&self, mut videos: Vec<CatalogVideo>) -> Vec<i64> {
+        videos.sort_by(|a, b| {
+            (a.published_at.is_none(), a.published_at, &a.title).cmp(&(
+                b.published_at.is_none(),
+                b.published_at,
+                &b.title,
+            ))
+        });
+        videos.into_iter().map(|v| v.id).collect()
+    }
+}
+
+/// Shuffles so that a video that played `n` times is `n + 1` times less likely
+/// to come first than one that never played. Series count with the average
+/// plays of their parts.
+pub struct WeightedShuffle {
+    pub series: Option<SeriesConfig>,
+}
+
+impl PlaylistStrategy for WeightedShuffle {
+    fn order(&self, videos: Vec<CatalogVideo>) -> Vec<i64> {
+        let mut rng = rand::thread_rng();
+        // weighted random sampling by Efraimidis and Spirakis
+        let mut keyed: Vec<_> = units(videos, self.series.as_ref())
+            .into_iter()
+            .map(|unit| {
+                let plays = unit.videos.iter().map(|v| v.plays as f64).sum::<f64>()
+                    / unit.videos.len() as f64;
+                (rng.gen::<f64>().powf(plays + 1.0), unit)
+            })
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        flatten(keyed.into_iter().map(|(_, unit)| unit).collect())
+    }
+}
+
+/// Videos that play back to back: a single video, or consecutive parts of a
+/// series.
+#[derive(Default)]
+struct Unit {
+    /// Index of the series and of the chunk within it
+    series: Option<(usize, usize)>,
+    videos: Vec<CatalogVideo>,
+}
+
+/// Groups the parts of every series into units, in order. Every video is its
+/// own unit without a series config.
+fn units(videos: Vec<CatalogVideo>, config: Option<&SeriesConfig>) -> Vec<Unit> {
+    let Some(config) = config else {
+        return videos
+            .into_iter()
+            .map(|video| Unit {
+                series: None,
+                videos: vec![video],
+            })
+            .collect();
+    };
+
+    let mut series: HashMap<String, Vec<(PartKey, CatalogVideo)>> = HashMap::new();
+    let mut units = vec![];
+    for video in videos {
+        match series_of(&video, config) {
+            Some((name, key)) => series.entry(name).or_default().push((key, video)),
+            None => units.push(Unit {
+                series: None,
+                videos: vec![video],
+            }),
+        }
+    }
+
+    for (index, mut parts) in series.into_values().enumerate() {
+        parts.sort_by_key(|(key, _)| *key);
+        let chunk_size = match config.chunk_size {
+            0 => parts.len(),
+            size => size,
+        };
+        let mut parts = parts.into_iter().map(|(_, video)| video).peekable();
+        let mut chunk = 0;
+        while parts.peek().is_some() {
+            units.push(Unit {
+                series: Some((index, chunk)),
+                videos: parts.by_ref().take(chunk_size).collect(),
+            });
+            chunk += 1;
+        }
+    }
+
+    units
+}
+
+/// Puts the chunks of every series back into order, keeping the places the
+/// series got in the playlist.
+fn flatten(mut units: Vec<Unit>) -> Vec<i64> {
+    let mut places: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (place, unit) in units.iter().enumerate() {
+        if let Some((series, _)) = unit.series {
+            places.entry(series).or_default().push(place);
+        }
+    }
+    for places in places.values() {
+        let mut chunks: Vec<_> = places
+            .iter()
+            .map(|place| std::mem::take(&mut units[*place]))
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.series);
+        for (place, chunk) in places.iter().zip(chunks) {
+            units[*place] = chunk;
+        }
+    }
+
+    units
+        .into_iter()
+        .flat_map(|unit| unit.videos)
+        .map(|v| v.id)
+        .collect()
+}
+
+/// Orders the parts of a series: by part number if known, then by publication.
+type PartKey = (Option<i32>, Option<OffsetDateTime>);
+
+/// The series a video is part of, by name in lowercase. Overrides set by hand
+/// come first, then the archive subjects from the config and then the title.
+fn series_of(video: &CatalogVideo, config: &SeriesConfig) -> Option<(String, PartKey)> {
+    let title_part = series_part(&video.title);
+    if let Some(series) = &video.series_override {
+        let part = series
+            .part
+            .or_else(|| title_part.map(|(_, part)| part as i32));
+        return Some((
+            series.series.as_ref()?.to_lowercase(),
+            (part, video.published_at),
+        ));
+    }
+    // parts of different games under one subject may restart their numbering
+    if let Some(subject) = config.subjects.iter().find(|subject| {
+        video
+            .subjects
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(subject))
+    }) {
+        return Some((subject.to_lowercase(), (None, video.published_at)));
+    }
+
+    let (name, part) = title_part?;
+    Some((name.to_lowercase(), (Some(part as i32), video.published_at)))
+}
+
+/// Splits a title like `Endurance Run: Persona 4 - Part 12`, `Bombcast #12` or
+/// `Quick Look: Deadly Premonition (Episode 3 of 5)` into the name of the
+/// series and the part number.
+pub fn series_part(title: &str) -> Option<(&str, u32)> {
+    const MARKERS: [&str; 7] = ["part", "pt.", "pt", "episode", "ep.", "ep", "day"];
+
+    let mut rest = title.trim_end().trim_end_matches(')').trim_end();
+    if let Some((head, total)) = rest.rsplit_once(" of ") {
+        if total.parse::<u32>().is_ok() {
+            rest = head.trim_end();
+        }
+    }
+    let (head, number) = rest.rsplit_once([' ', '#'])?;
+    let number = number.parse().ok()?;
+
+    let name = if rest[head.len()..].starts_with('#') {
+        head
+    } else {
+        let head = head.trim_end();
+        let marker = MARKERS.iter().find(|marker| {
+            let start = head.len().checked_sub(marker.len());
+            start.is_some_and(|start| {
+                head.is_char_boundary(start)
+                    && head[start..].eq_ignore_ascii_case(marker)
+                    && !head[..start].ends_with(char::is_alphanumeric)
+            })
+        })?;
+        &head[..head.len() - marker.len()]
+    };
+    let name = name.trim_end_matches([' ', '-', ':', ',', '(']);
+
+    (!name.is_empty()).then_some((name, number))
+}
+
+/// Leaves out the videos not matching a filter, ordering the rest with another
+/// strategy.
+pub struct Filtered {
+    pub filter: CatalogFilter,
+    pub inner: Box<dyn PlaylistStrategy>,
+}
+
+impl PlaylistStrategy for Filtered {
+    fn order(&self, videos: Vec<CatalogVideo>) -> Vec<i64> {
+        self.inner.order(
+            videos
+                .into_iter()
+                .filter(|v| {
+                    self.filter
+                        .matches(&v.title, &v.subjects, v.creator.as_deref(), &v.collections)
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn video(id: i64, title: &str) -> CatalogVideo {
+        CatalogVideo {
+            id,
+            title: title.into(),
+            published_at: None,
+            creator: None,
+            collections: vec![],
+            subjects: vec![],
+            plays: 0,
+            series_override: None,
+        }
+    }
+
+    #[test]
+    fn series_part_finds_numbered_parts() {
+        assert_eq!(
+            series_part("Endurance Run: Persona 4 - Part 12"),
+            Some(("Endurance Run: Persona 4", 12))
+        );
+        assert_eq!(
+            series_part("Quick Look: Deadly Premonition (Episode 3 of 5)"),
+            Some(("Quick Look: Deadly Premonition", 3))
+        );
+        assert_eq!(series_part("Bombcast #12"), Some(("Bombcast", 12)));
+        assert_eq!(series_part("Quick Look: Halo 3"), None);
+        assert_eq!(series_part("Quick Look: Deep 3"), None);
+        assert_eq!(series_part("Part 2"), None);
+    }
+
+    #[test]
+    fn shuffle_keeps_chunks_in_order() {
+        let videos = (1..=7)
+            .map(|part| video(part, &format!("Persona 4 - Part {part}")))
+            .chain((8..=20).map(|id| video(id, &format!("Quick Look {id}"))))
+            .collect();
+        let order = Shuffle {
+            series: Some(SeriesConfig::default()),
+        }
+        .order(videos);
+
+        let parts: Vec<_> = order.iter().filter(|id| **id <= 7).copied().collect();
+        assert_eq!(parts, (1..=7).collect::<Vec<_>>());
+        let first = order.iter().position(|id| *id == 1).unwrap();
+        assert_eq!(order[first..first + 3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn chronological_puts_undated_videos_last() {
+        let dated = |id, title, published_at| CatalogVideo {
+            published_at: Some(published_at),
+            ..video(id, title)
+        };
+        let videos = vec![
+            video(1, "Undated"),
+            dated(2, "B", datetime!(2012-05-01 0:00 UTC)),
+            dated(3, "A", datetime!(2012-05-01 0:00 UTC)),
+            dated(4, "C", datetime!(2009-01-01 0:00 UTC)),
+        ];
+        assert_eq!(Chronological.order(videos), [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn filtered_leaves_out_other_videos() {
+        let videos = vec![
+            CatalogVideo {
+                subjects: vec!["Quick Look".into()],
+                ..video(1, "Quick Look: Halo 3")
+            },
+            CatalogVideo {
+                subjects: vec!["Endurance Run".into()],
+                ..video(2, "Persona 4 - Part 1")
+            },
+            video(3, "Quick Look: Deadly Premonition"),
+        ];
+        let filtered = Filtered {
+            filter: CatalogFilter {
+                subject: Some("quick look".into()),
+                ..Default::default()
+            },
+            inner: Box::new(Chronological),
+        };
+        assert_eq!(filtered.order(videos), [1]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use time::OffsetDateTime;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+const CAPACITY: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct LoggedError {
+    pub timestamp: OffsetDateTime,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// A tracing layer that keeps the most recent warnings and errors in memory, so
+/// they can be shown without access to the logs.
+#[derive(Clone, Default)]
+pub struct RecentErrors {
+    entries: Arc<Mutex<VecDeque<LoggedError>>>,
+}
+
+impl RecentErrors {
+    /// The recorded errors, newest first.
+    pub fn list(&self) -> Vec<LoggedError> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().rev().cloned().collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let error = LoggedError {
+            timestamp: OffsetDateTime::now_utc(),
+            level,
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(error);
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.message, " {}={value:?}", field.name());
+        }
+    }
+}
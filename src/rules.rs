@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use color_eyre::eyre::Context;
+use regex::{Regex, RegexBuilder};
+use time::{macros::format_description, Date};
+use tracing::info;
+
+use crate::{
+    config::{RuleConfig, RulesConfig},
+    db::{Database, GbVideo},
+    Result,
+};
+
+/// Rule name recorded for identifiers on the blocklist of the config.
+const CONFIG_BLOCKLIST: &str = "blocklist";
+/// Rule name recorded for videos that match none of the include rules.
+const NOT_INCLUDED: &str = "not included";
+
+/// A compiled [`RuleConfig`].
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    collection: Option<String>,
+    creator: Option<String>,
+    title: Option<Regex>,
+    published_after: Option<Date>,
+    published_before: Option<Date>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+}
+
+impl Rule {
+    fn from_config(config: &RuleConfig) -> Result<Self> {
+        let date = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| Date::parse(value, format_description!("[year]-[month]-[day]")))
+                .transpose()
+                .wrap_err_with(|| format!("invalid date in rule {}", config.name))
+        };
+        let title = config
+            .title
+            .as_deref()
+            .map(|title| RegexBuilder::new(title).case_insensitive(true).build())
+            .transpose()
+            .wrap_err_with(|| format!("invalid title pattern in rule {}", config.name))?;
+
+        Ok(Self {
+            name: config.name.clone(),
+            collection: config.collection.as_ref().map(|c| c.to_lowercase()),
+            creator: config.creator.as_ref().map(|c| c.to_lowercase()),
+            title,
+            published_after: date(&config.published_after)?,
+            published_before: date(&config.published_before)?,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            min_duration: config.min_duration,
+            max_duration: config.max_duration,
+        })
+    }
+
+    fn matches(&self, video: &GbVideo) -> bool {
+        let contains = |pattern: &Option<String>, texts: &[String]| {
+            pattern
+                .as_ref()
+                .is_none_or(|p| texts.iter().any(|t| t.to_lowercase().contains(p)))
+        };
+
+        contains(
+            &self.collection,
+            video.collections.as_deref().unwrap_or_default(),
+        ) && contains(&self.creator, video.creator.as_slice())
+            && self.title.as_ref().is_none_or(|t| t.is_match(&video.title))
+            && within(
+                video.published_at.map(|at| at.date()),
+                self.published_after,
+                self.published_before,
+            )
+            && within(video.item_size, self.min_size, self.max_size)
+            && within(video.runtime, self.min_duration, self.max_duration)
+    }
+}
+
+/// Whether `value` is between `min` and `max`. An unknown value is not, unless
+/// there are no limits.
+fn within<T: PartialOrd + Copy>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+    value.is_some_and(|value| {
+        min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+    })
+}
+
+/// Decides which archive items may air, see [`RulesConfig`].
+#[derive(Debug, Clone, Default)]
+pub struct ContentRules {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    blocklist: HashSet<String>,
+}
+
+impl ContentRules {
+    pub fn from_config(config: &RulesConfig) -> Result<Self> {
+        let compile = |rules: &[RuleConfig]| -> Result<Vec<_>> {
+            rules.iter().map(Rule::from_config).collect()
+        };
+        Ok(Self {
+            include: compile(&config.include)?,
+            exclude: compile(&config.exclude)?,
+            blocklist: config.blocklist.iter().cloned().collect(),
+        })
+    }
+
+    /// The name of the rule that keeps a video from airing, if any.
+    pub fn exclusion(&self, video: &GbVideo) -> Option<&str> {
+        if self.blocklist.contains(&video.identifier) {
+            return Some(CONFIG_BLOCKLIST);
+        }
+        if let Some(rule) = self.exclude.iter().find(|rule| rule.matches(video)) {
+            return Some(&rule.name);
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|rule| rule.matches(video)) {
+            return Some(NOT_INCLUDED);
+        }
+
+        None
+    }
+
+    /// Puts the videos the rules exclude on the blocklist and takes the ones
+    /// they no longer exclude off it. Returns the number of excluded videos.
+    pub async fn apply(&self, database: &Database) -> Result<u64> {
+        let excluded: Vec<_> = database
+            .available_videos()
+            .await?
+            .iter()
+            .filter_map(|video| Some((video.id, self.exclusion(video)?.to_string())))
+            .collect();
+        let blocklisted = database.set_rule_blocklist(&excluded).await?;
+        info!("content rules exclude {blocklisted} videos");
+
+        Ok(blocklisted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn video(title: &str, runtime: Option<f64>) -> GbVideo {
+        GbVideo {
+            collections: Some(vec!["giantbomb-premium".into()]),
+            published_at: Some(datetime!(2014-06-01 0:00 UTC)),
+            runtime,
+            ..GbVideo::for_tests(1, title)
+        }
+    }
+
+    #[test]
+    fn rules_match_all_conditions() {
+        let config = RulesConfig {
+            exclude: vec![RuleConfig {
+                name: "teasers".into(),
+                collection: Some("Premium".into()),
+                title: Some("^premium teaser".into()),
+                max_duration: Some(300.0),
+                ..Default::default()
+            }],
+            include: vec![RuleConfig {
+                name: "after 2010".into(),
+                published_after: Some("2010-01-01".into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let rules = ContentRules::from_config(&config).unwrap();
+
+        assert_eq!(
+            rules.exclusion(&video("Premium Teaser: Bombcast", Some(120.0))),
+            Some("teasers")
+        );
+        // unknown runtime does not match a duration limit
+        assert_eq!(
+            rules.exclusion(&video("Premium Teaser: Bombcast", None)),
+            None
+        );
+        assert_eq!(
+            rules.exclusion(&video("Quick Look: Halo 3", Some(120.0))),
+            None
+        );
+
+        let mut old = video("Quick Look: Halo 3", None);
+        old.published_at = Some(datetime!(2008-06-01 0:00 UTC));
+        assert_eq!(rules.exclusion(&old), Some(NOT_INCLUDED));
+    }
+}
@@ -0,0 +1,275 @@
+use color_eyre::eyre::{bail, Context};
+use time::{macros::format_description, Duration, OffsetDateTime, Time, Weekday};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{BlockConfig, CatalogFilter, ScheduleConfig},
+    db::Database,
+    player::PlayerHandle,
+    supervisor::Supervisor,
+    Result,
+};
+
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Seconds assumed for upcoming videos whose duration is not known.
+pub const UNKNOWN_DURATION: f64 = 1800.0;
+/// How many matching videos are considered when filling a block.
+const CANDIDATE_COUNT: i64 = 200;
+/// How many upcoming entries are looked at to find where a block starts.
+const LOOKAHEAD: i64 = 50;
+
+/// A themed time slot that repeats on some or all days of the week.
+#[derive(Debug, Clone)]
+pub struct ProgramBlock {
+    pub name: String,
+    /// Runs every day if empty
+    pub days: Vec<Weekday>,
+    /// Start time in UTC
+    pub start: Time,
+    pub duration: Duration,
+    pub filter: CatalogFilter,
+}
+
+impl ProgramBlock {
+    pub fn from_config(config: &BlockConfig) -> Result<Self> {
+        let days = config
+            .days
+            .iter()
+            .map(|day| parse_weekday(day))
+            .collect::<Result<_>>()?;
+        let start = Time::parse(&config.start, format_description!("[hour]:[minute]"))
+            .wrap_err_with(|| format!("invalid start time of block {}", config.name))?;
+        if config.duration == 0 || config.duration > 24 * 60 {
+            bail!(
+                "block {} has to last between 1 and 1440 minutes",
+                config.name
+            );
+        }
+
+        Ok(Self {
+            name: config.name.clone(),
+            days,
+            start,
+            duration: Duration::minutes(config.duration as i64),
+            filter: config.filter.clone(),
+        })
+    }
+
+    /// Start and end of the occurrences that have not ended at `from`, up to
+    /// the one `days` days after the day of `from`, in order.
+    pub fn occurrences(
+        &self,
+        from: OffsetDateTime,
+        days: i64,
+    ) -> impl Iterator<Item = (OffsetDateTime, OffsetDateTime)> + '_ {
+        // an occurrence that started yesterday may still be running
+        (-1..=days)
+            .map(move |offset| from.date() + Duration::days(offset))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            .map(|date| {
+                let starts_at = date.with_time(self.start).assume_utc();
+                (starts_at, starts_at + self.duration)
+            })
+            .filter(move |(_, ends_at)| *ends_at > from)
+    }
+}
+
+fn parse_weekday(day: &str) -> Result<Weekday> {
+    let weekday = match day.to_lowercase().get(..3) {
+        Some("mon") => Weekday::Monday,
+        Some("tue") => Weekday::Tuesday,
+        Some("wed") => Weekday::Wednesday,
+        Some("thu") => Weekday::Thursday,
+        Some("fri") => Weekday::Friday,
+        Some("sat") => Weekday::Saturday,
+        Some("sun") => Weekday::Sunday,
+        _ => bail!("invalid day of the week: {day}"),
+    };
+
+    Ok(weekday)
+}
+
+pub fn parse_blocks(config: &ScheduleConfig) -> Result<Vec<ProgramBlock>> {
+    config
+        .blocks
+        .iter()
+        .map(ProgramBlock::from_config)
+        .collect()
+}
+
+/// Puts matching videos into the playlist ahead of every programming block, so
+/// they play during its time window.
+pub struct Scheduler {
+    blocks: Vec<ProgramBlock>,
+    lead_time: Duration,
+    database: Database,
+    player: PlayerHandle,
+}
+
+impl Scheduler {
+    pub fn new(config: &ScheduleConfig, database: Database, player: PlayerHandle) -> Result<Self> {
+        Ok(Self {
+            blocks: parse_blocks(config)?,
+            lead_time: Duration::seconds(config.lead_time as i64),
+            database,
+            player,
+        })
+    }
+
+    pub fn start(self, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.check().await {
+                    error!("filling the schedule failed: {e}");
+                }
+            }
+        });
+    }
+
+    async fn check(&self) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+        for block in &self.blocks {
+            for (starts_at, ends_at) in block.occurrences(now, 1) {
+                if starts_at - self.lead_time > now
+                    || self
+                        .database
+                        .schedule_slot_filled(&block.name, starts_at)
+                        .await?
+                {
+                    continue;
+                }
+                self.fill(block, starts_at, ends_at, now).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Picks random matching videos until the window is full and puts them at
+    /// the spot of the playlist that is reached closest to the start. A block
+    /// that nothing fits into counts as filled as well.
+    async fn fill(
+        &self,
+        block: &ProgramBlock,
+        starts_at: OffsetDateTime,
+        ends_at: OffsetDateTime,
+        now: OffsetDateTime,
+    ) -> Result<()> {
+        let (slot, block_start) = self.find_slot(starts_at.max(now), now).await?;
+        let mut remaining = (ends_at - block_start).as_seconds_f64();
+        let mut picked = vec![];
+        for video in self
+            .database
+            .filtered_videos(&block.filter, CANDIDATE_COUNT)
+            .await?
+        {
+            if video.duration <= remaining {
+                remaining -= video.duration;
+                picked.push(video.id);
+            }
+        }
+
+        let Some(ids) = self
+            .database
+            .fill_schedule_slot(&block.name, starts_at, ends_at, &picked, slot)
+            .await?
+        else {
+            return Ok(());
+        };
+        if ids.is_empty() {
+            warn!("no videos fit into block {} at {starts_at}", block.name);
+        } else {
+            info!(
+                "filled block {} at {starts_at} with {} videos, starting at slot {slot}",
+                block.name,
+                ids.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// The boundary between two upcoming entries that is reached closest to
+    /// `at`, as the number of upcoming entries before it and the estimated time
+    /// it is reached.
+    async fn find_slot(
+        &self,
+        at: OffsetDateTime,
+        now: OffsetDateTime,
+    ) -> Result<(i64, OffsetDateTime)> {
+        let state = self.player.state();
+        let mut time = now;
+        if let Some(video_id) = state.video_id {
+            let end = self.database.playback_end(video_id).await?;
+            time += Duration::seconds_f64(end.map_or(0.0, |end| (end - state.position).max(0.0)));
+        }
+
+        let upcoming = self.database.upcoming_entries(LOOKAHEAD).await?;
+        let mut previous = (0, time);
+        for (slot, entry) in upcoming.iter().enumerate() {
+            if time >= at {
+                return Ok(if at - previous.1 < time - at {
+                    previous
+                } else {
+                    (slot as i64, time)
+                });
+            }
+            previous = (slot as i64, time);
+            let duration = self.database.playback_end(entry.video_id).await?;
+            time += Duration::seconds_f64(duration.unwrap_or(UNKNOWN_DURATION));
+        }
+
+        Ok((upcoming.len() as i64, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn block(days: Vec<Weekday>, start: Time, minutes: i64) -> ProgramBlock {
+        ProgramBlock {
+            name: "block".into(),
+            days,
+            start,
+            duration: Duration::minutes(minutes),
+            filter: CatalogFilter::default(),
+        }
+    }
+
+    #[test]
+    fn occurrences_include_the_next_day() {
+        let block = block(vec![], Time::from_hms(0, 30, 0).unwrap(), 60);
+        let from = datetime!(2025-06-04 23:50 UTC);
+        let starts: Vec<_> = block.occurrences(from, 1).map(|(start, _)| start).collect();
+        assert_eq!(starts, [datetime!(2025-06-05 00:30 UTC)]);
+    }
+
+    #[test]
+    fn occurrences_include_the_running_one() {
+        // wednesday 22:00 until thursday 02:00
+        let block = block(
+            vec![Weekday::Wednesday, Weekday::Friday],
+            Time::from_hms(22, 0, 0).unwrap(),
+            240,
+        );
+        let from = datetime!(2025-06-05 01:00 UTC);
+        let occurrences: Vec<_> = block.occurrences(from, 2).collect();
+        assert_eq!(
+            occurrences,
+            [
+                (
+                    datetime!(2025-06-04 22:00 UTC),
+                    datetime!(2025-06-05 02:00 UTC)
+                ),
+                (
+                    datetime!(2025-06-06 22:00 UTC),
+                    datetime!(2025-06-07 02:00 UTC)
+                ),
+            ]
+        );
+    }
+}
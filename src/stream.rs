@@ -5,19 +5,72 @@ use tokio::{
     io::AsyncWriteExt,
 };
 
+use crate::db::VideoTrim;
+
+/// A single `file` directive in the concat file.
+#[derive(Debug, Clone)]
+pub struct ConcatEntry {
+    pub path: Utf8PathBuf,
+    pub inpoint: Option<f64>,
+    pub outpoint: Option<f64>,
+}
+
+impl ConcatEntry {
+    pub fn new(path: Utf8PathBuf) -> Self {
+        Self {
+            path,
+            inpoint: None,
+            outpoint: None,
+        }
+    }
+
+    /// Creates an entry that skips the dead air detected at the start and the end
+    /// of the video.
+    pub fn with_trim(path: Utf8PathBuf, trim: Option<&VideoTrim>) -> Self {
+        Self {
+            path,
+            inpoint: trim.and_then(|t| t.inpoint),
+            outpoint: trim.and_then(|t| t.outpoint),
+        }
+    }
+
+    fn directives(&self) -> String {
+        let path = self.path.as_str().replace('\'', r"'\''");
+        let mut lines = vec![format!("file '{path}'")];
+        if let Some(inpoint) = self.inpoint {
+            lines.push(format!("inpoint {inpoint:.3}"));
+        }
+        if let Some(outpoint) = self.outpoint {
+            lines.push(format!("outpoint {outpoint:.3}"));
+        }
+        lines.join("\n")
+    }
+}
+
 pub struct ConcatFile {
     path: Utf8PathBuf,
-    entries: Vec<Utf8PathBuf>,
+    entries: Vec<ConcatEntry>,
 }
 
 impl ConcatFile {
+    pub fn new(path: Utf8PathBuf) -> Self {
+        Self {
+            path,
+            entries: vec![],
+        }
+    }
+
+    pub fn path(&self) -> &Utf8PathBuf {
+        &self.path
+    }
+
     fn file_content(&self) -> String {
-        let entries: Vec<_> = self.entries.iter().map(|e| format!("file '{e}'")).collect();
+        let entries: Vec<_> = self.entries.iter().map(|e| e.directives()).collect();
         entries.join("\n")
     }
 
-    pub async fn append_video(&mut self, path: Utf8PathBuf) -> Result<()> {
-        self.entries.push(path);
+    pub async fn append_video(&mut self, entry: ConcatEntry) -> Result<()> {
+        self.entries.push(entry);
 
         let temp_file_path = self.path.with_file_name(format!(
             "{}_temp.{}",
@@ -0,0 +1,83 @@
+use std::{future::Future, time::Duration};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
+
+use crate::Result;
+
+/// How long tasks get to stop after shutdown started.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Keeps track of the tasks of the process and stops them on shutdown.
+/// Clones share the same tasks.
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Supervisor {
+    /// Cancelled once shutdown starts.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Runs `task` until it finishes or shutdown starts, then drops it. For
+    /// tasks that have nothing to clean up.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let token = self.token.clone();
+        self.tasks.spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task => {}
+            }
+        });
+    }
+
+    /// Runs `task`, which watches [`Self::token`] and returns once it is
+    /// cancelled. Shutdown waits for it, and starts when it fails, so that the
+    /// service manager restarts the whole process.
+    pub fn spawn_graceful(
+        &self,
+        name: impl Into<String>,
+        task: impl Future<Output = Result<()>> + Send + 'static,
+    ) {
+        let name = name.into();
+        let token = self.token.clone();
+        self.tasks.spawn(async move {
+            if let Err(e) = task.await {
+                error!("{name} failed: {e}");
+                token.cancel();
+            }
+        });
+    }
+
+    /// Waits for SIGINT or SIGTERM, or until a task failed.
+    pub async fn wait_for_shutdown(&self) -> Result<()> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = interrupt.recv() => info!("received SIGINT, shutting down"),
+            _ = terminate.recv() => info!("received SIGTERM, shutting down"),
+            _ = self.token.cancelled() => info!("shutting down after a task failed"),
+        }
+        Ok(())
+    }
+
+    /// Cancels all tasks and waits until they stopped.
+    pub async fn shutdown(&self) {
+        self.token.cancel();
+        self.tasks.close();
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} tasks did not stop within {}s",
+                self.tasks.len(),
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use futures::{pin_mut, StreamExt, TryStreamExt};
+use tracing::{error, info, warn};
+
+use crate::{
+    db::Database, ia::InternetArchive, rules::ContentRules, supervisor::Supervisor, Result,
+};
+
+pub const ARCHIVE_QUERY: &str = "collection:giant-bomb-archive";
+
+/// Number of item detail requests that are made at the same time.
+const DETAILS_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Number of items returned by the archive
+    pub seen: usize,
+    /// Number of videos that were added to the catalog
+    pub inserted: usize,
+    /// Number of videos whose metadata changed
+    pub updated: usize,
+    /// Number of videos that disappeared from the archive
+    pub removed: u64,
+    /// Number of videos whose file listing was fetched
+    pub details_fetched: usize,
+    /// Number of videos the content rules keep from airing
+    pub excluded: u64,
+}
+
+/// Keeps the `gb_videos` table in sync with the archive collection.
+#[derive(Clone)]
+pub struct CatalogSync {
+    database: Database,
+    ia: InternetArchive,
+    rules: Option<ContentRules>,
+}
+
+impl CatalogSync {
+    pub fn new(database: Database, ia: InternetArchive) -> Self {
+        Self {
+            database,
+            ia,
+            rules: None,
+        }
+    }
+
+    /// Applies the content rules to new and changed videos on every sync.
+    pub fn with_rules(mut self, rules: ContentRules) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Fetches the whole collection, upserts every item and marks items that are
+    /// no longer returned (deleted or darkened) as removed. New and restored
+    /// videos are slotted into the existing playlist at random positions, unless
+    /// the content rules exclude them.
+    pub async fn sync(&self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        let mut seen = vec![];
+        let mut inserted = vec![];
+
+        let stream = self.ia.search_all(ARCHIVE_QUERY).try_chunks(10_000);
+        pin_mut!(stream);
+        while let Some(chunk) = stream.try_next().await? {
+            info!("got chunk of {} items", chunk.len());
+            seen.extend(chunk.iter().map(|item| item.identifier.clone()));
+            let result = self.database.upsert_items(&chunk).await?;
+            report.inserted += result.inserted.len();
+            inserted.extend(result.inserted);
+            // removing a video dropped it from the upcoming playlist
+            inserted.extend(result.restored);
+            report.updated += result.updated;
+        }
+        report.seen = seen.len();
+
+        // an empty result is much more likely to be an archive hiccup than the
+        // whole collection vanishing
+        if seen.is_empty() {
+            warn!("archive returned no items, not marking anything as removed");
+        } else {
+            report.removed = self.database.mark_missing_removed(&seen).await?;
+        }
+
+        self.apply_rules(&mut report).await?;
+        if !inserted.is_empty() && !self.database.playlist_is_empty().await? {
+            self.database
+                .insert_into_playlist_randomly(&inserted)
+                .await?;
+        }
+
+        report.details_fetched = self.fetch_missing_details().await?;
+        // rules on the size or duration only match once the details are known
+        if report.details_fetched > 0 {
+            self.apply_rules(&mut report).await?;
+        }
+
+        info!("catalog sync finished: {report:?}");
+        Ok(report)
+    }
+
+    async fn apply_rules(&self, report: &mut SyncReport) -> Result<()> {
+        if let Some(rules) = &self.rules {
+            report.excluded = rules.apply(&self.database).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the file listing of every new or changed item. Failures for single
+    /// items are logged and retried on the next sync.
+    async fn fetch_missing_details(&self) -> Result<usize> {
+        let videos = self.database.videos_missing_details().await?;
+        info!("fetching details for {} items", videos.len());
+
+        let fetched = futures::stream::iter(videos)
+            .map(|(video_id, identifier)| async move {
+                let result = async {
+                    let details = self.ia.get_item_details(&identifier).await?;
+                    self.database
+                        .set_video_files(video_id, &details.files)
+                        .await
+                }
+                .await;
+                if let Err(e) = &result {
+                    warn!("failed to fetch details for {identifier}: {e}");
+                }
+                result.is_ok()
+            })
+            .buffer_unordered(DETAILS_CONCURRENCY)
+            .filter(|ok| futures::future::ready(*ok))
+            .count()
+            .await;
+
+        Ok(fetched)
+    }
+
+    /// Spawns a task that syncs the catalog every `interval`.
+    pub fn start_periodic(self, interval: Duration, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // the first tick completes immediately, the catalog was either just
+            // initialized or is synced on the next tick
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.sync().await {
+                    error!("catalog sync failed: {e}");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::{
+    chat::ChatHandle,
+    config::VotingConfig,
+    db::{Database, VoteRound},
+    player::PlayerHandle,
+    supervisor::Supervisor,
+    Result,
+};
+
+/// How often the vote checks the player position.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs a vote among a few downloaded upcoming videos near the end of each
+/// video and moves the winner to the front of the playlist.
+pub struct Voting {
+    config: VotingConfig,
+    database: Database,
+    player: PlayerHandle,
+    chat: Option<ChatHandle>,
+}
+
+impl Voting {
+    pub fn new(
+        config: VotingConfig,
+        database: Database,
+        player: PlayerHandle,
+        chat: Option<ChatHandle>,
+    ) -> Self {
+        Self {
+            config,
+            database,
+            player,
+            chat,
+        }
+    }
+
+    pub fn start(self, supervisor: &Supervisor) {
+        supervisor.spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.check().await {
+                    error!("vote failed: {e}");
+                }
+            }
+        });
+    }
+
+    /// Opens or closes a round depending on how much of the current video is
+    /// left.
+    async fn check(&self) -> Result<()> {
+        let state = self.player.state();
+        let remaining = match state.video_id {
+            Some(video_id) => self
+                .database
+                .playback_end(video_id)
+                .await?
+                .map(|end| end - state.position),
+            None => None,
+        };
+
+        if let Some(round) = self.database.current_vote_round().await? {
+            // the video the vote ran for ended early, e.g. because it was skipped
+            let moved_on = state.entry_id.is_none_or(|id| round.entry_id != Some(id));
+            if moved_on || remaining.is_some_and(|r| r <= self.config.close_before) {
+                self.close(round).await?;
+            }
+            return Ok(());
+        }
+
+        let (Some(entry_id), Some(started_at), Some(remaining)) =
+            (state.entry_id, state.started_at, remaining)
+        else {
+            return Ok(());
+        };
+        if state.paused
+            || remaining > self.config.open_before
+            || remaining <= self.config.close_before
+            || self.database.vote_round_held(entry_id, started_at).await?
+        {
+            return Ok(());
+        }
+        self.open(entry_id).await
+    }
+
+    async fn open(&self, entry_id: i64) -> Result<()> {
+        let candidates = self
+            .database
+            .vote_candidates(self.config.candidates)
+            .await?;
+        if candidates.len() < 2 {
+            warn!("not enough videos for a vote");
+            return Ok(());
+        }
+        let ids: Vec<_> = candidates.iter().map(|v| v.id).collect();
+        let round = self.database.open_vote_round(entry_id, &ids).await?;
+        info!("opened vote round {} with videos {ids:?}", round.id);
+
+        let choices: Vec<_> = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("{}: {}", i + 1, v.title))
+            .collect();
+        self.say(format!(
+            "Vote for what plays next with !vote <n> | {}",
+            choices.join(" | ")
+        ));
+        Ok(())
+    }
+
+    async fn close(&self, round: VoteRound) -> Result<()> {
+        let Some(winner) = self.database.close_vote_round(round.id).await? else {
+            info!("vote round {} closed without votes", round.id);
+            self.say("Nobody voted, the playlist continues as planned");
+            return Ok(());
+        };
+        info!(
+            "vote round {} won by video {} with {} votes",
+            round.id, winner.video_id, winner.votes
+        );
+        self.database.enqueue_next(winner.video_id).await?;
+        self.say(format!(
+            "The vote is over! Up next: {} ({} votes)",
+            winner.title, winner.votes
+        ));
+        Ok(())
+    }
+
+    fn say(&self, message: impl Into<String>) {
+        if let Some(chat) = &self.chat {
+            chat.say(message);
+        }
+    }
+}